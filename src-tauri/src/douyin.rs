@@ -1,15 +1,26 @@
 
-use std::{time::Duration, path::Path, sync::Arc};
+use std::{collections::HashMap, path::{Component, Path}, sync::Arc};
 use futures::future::join_all;
-use serde_json::Value;
-use tauri::{regex::Regex, Window};
-use serde::{Serialize, Deserialize};
-use tokio::time::sleep;
-use crate::downloader::Downloader;
+use tauri::{Window, State};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use reqwest::Client;
+use crate::manager::{DownloadManager, DownloadJob};
+use crate::downloader::CollisionPolicy;
+use crate::http::HttpClient;
+use crate::template::{FilenameTemplate, TemplateValue};
 use thiserror::Error;
 use anyhow::Result;
+use model::{AwemePostResponse, ItemInfoResponse, UserInfoResponse};
+use link::{DouyinId, Link};
 
-const USER_AGNET: &'static str = "Mozilla/5.0 (iPhone; CPU iPhone OS 13_2_3 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/13.0.3 Mobile/15E148 Safari/604.1";
+mod model;
+mod link;
+
+// 单个视频下载在队列中的优先级, 批量下载为0
+const SINGLE_DOWNLOAD_PRIORITY: i32 = 10;
+
+// 默认的文件名模板, 即<标题>.mp4
+const DEFAULT_FILENAME_TEMPLATE: &'static str = "{title}.{ext}";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserInfo {
@@ -26,6 +37,8 @@ pub struct VideoInfoItem {
     pub video_url: String,  // 视频链接
     pub cover_url: String, // 视频封面URL
    // pub music_url: String, // 视频音频URL
+    #[serde(default)]
+    pub create_time: i64, // 发布时间, 秒级时间戳
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -46,18 +59,12 @@ pub struct UserVideoInfo {
 #[derive(Error, Debug)]
 enum DouyinError {
     
-    #[error("系统错误")]
-    SystemError,
-
     #[error("未找到视频")]
     VideoInfoNotFoundError,
     
     #[error("网络错误")]
     NetworkError,
 
-    #[error("获取数据失败")]
-    GetDataError,
-
     #[error("下载视频失败")]
     DownloadVideoError,
 
@@ -66,6 +73,12 @@ enum DouyinError {
 
     #[error("未找到用户")]
     UserInfoNotFoundError,
+
+    #[error("接口返回的数据格式不正确: {0}")]
+    UnexpectedResponseError(String),
+
+    #[error("保存路径不在所选目录中: {0}")]
+    SavePathError(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -81,272 +94,129 @@ pub struct DouyinMuplitDownloadProgress {
     pub is_success: bool,
 }
 
-fn get_id_from_url(url: &mut String) -> String {
-    if !url.contains("?") {
-        url.push('?');
-    }
-    let regex = Regex::new(r"/[video|user]+/(?P<aweme_id>\S+)[/|\?]+").unwrap();
-    let aweme_id = match regex.captures(&url) {
-        Some(cap) => {
-            cap.name("aweme_id").unwrap().as_str().replace('/', "").to_string()
+/// 解析链接或分享文本, 短链接按跳转后的地址解析
+async fn resolve_link(client: &Client, text: &str) -> Result<Option<DouyinId>, DouyinError> {
+    match link::parse(text) {
+        Some(Link::Resolved(id)) => Ok(Some(id)),
+        Some(Link::Short(url)) => {
+            let response = client.get(url)
+                .send()
+                .await
+                .map_err(|_| DouyinError::NetworkError)?;
+            Ok(link::parse_url(response.url()))
         },
-        None => "".to_string()
-    };
-    aweme_id
+        None => Ok(None),
+    }
 }
 
 
-/// 解析视频,音频,封面URL
-#[tauri::command]
-pub async fn douyin_single_search(url: String) -> Result<UserVideoInfo, String> {
-
-    let client = reqwest::Client::builder()
-        .user_agent(USER_AGNET)
-        .build().unwrap();
-    let mut real_url = client.get(&url)
+/// 读取接口响应并解析为对应的结构
+async fn get_json<T: DeserializeOwned>(client: &Client, url: &str) -> Result<T> {
+    let body = client.get(url)
         .send()
-        .await
-        .map_err(|_| DouyinError::NetworkError.to_string())?
-        .url()
-        .to_string();
+        .await?
+        .bytes()
+        .await?;
+    Ok(model::parse(&body)?)
+}
 
-    let aweme_id = get_id_from_url(&mut real_url);
-    if aweme_id.is_empty() {
-        return Err(DouyinError::VideoInfoNotFoundError.to_string());
-    }
-    let api_url = format!("https://www.iesdouyin.com/web/api/v2/aweme/iteminfo/?item_ids={}", aweme_id);
-    
-    let data:Value = client.get(&api_url)
-        .send()
-        .await
-        .map_err(|_| DouyinError::NetworkError.to_string())?
-        .json::<Value>()
-        .await
-        .map_err(|_| DouyinError::GetDataError.to_string())?
-        ["item_list"][0].clone();
-
-    let video_id = data["aweme_id"]
-        .to_string()
-        .replace('"', "");
-
-    let mut video_title = data["share_info"]["share_title"]
-        .to_string()
-        .replace('"', "")
-        .split("@")
-        .collect::<Vec<&str>>()[0]
-        .to_string();
-
-    if video_title.is_empty() {
-        video_title = format!("无标题: {}", video_id);
+/// 保留接口数据相关的错误原因, 其他错误使用fallback
+fn error_message(e: anyhow::Error, fallback: DouyinError) -> String {
+    match e.downcast::<DouyinError>() {
+        Ok(e) => e.to_string(),
+        Err(_) => fallback.to_string(),
     }
+}
 
-    let video_url = data["video"]["play_addr"]["url_list"][0]
-        .to_string()
-        .replace('"', "")
-        .replace("playwm", "play")
-        .replace("ratio=720p", "ratio=1080p").replace('"', "");
-    
-    let video_cover_url:String = data["video"]["origin_cover"]["url_list"][0]
-        .to_string()
-        .replace('"', "");
-
-    // let video_music_url = data["music"]["play_url"]["url_list"][0]
-    //     .to_string()
-    //     .replace('"', "");
-
-    let author_uid = data["author"]["uid"]
-        .to_string()
-        .replace('"', "");
-
-    let author_name = data["author"]["nickname"]
-        .to_string()
-        .replace('"', "");
-
-    let author_avatar = data["author"]["avatar_thumb"]["url_list"][0]
-        .to_string()
-        .replace('"', "");
-
-    let user_info =  UserInfo { 
-        nickname: author_name, 
-        uid: author_uid, 
-        avatar_url: author_avatar,
-        video_count: 1
-    };
+/// 解析视频,音频,封面URL
+#[tauri::command]
+pub async fn douyin_single_search(url: String, http: State<'_, HttpClient>) -> Result<UserVideoInfo, String> {
 
-    let video_list = vec![
-        VideoInfoItem {
-            video_id,
-            video_title,
-            video_url,
-            cover_url: video_cover_url,
-        }
-    ];
-    let video_info = VideoInfo { 
-        max_cursor: 0,
-        has_more: false, 
-        items: video_list
+    let client = http.api_client();
+    // 图文作品与视频使用同一个作品接口
+    let aweme_id = match resolve_link(&client, &url).await.map_err(|e| e.to_string())? {
+        Some(DouyinId::Video(id)) | Some(DouyinId::Note(id)) => id,
+        _ => return Err(DouyinError::VideoInfoNotFoundError.to_string()),
     };
+    let api_url = format!("https://www.iesdouyin.com/web/api/v2/aweme/iteminfo/?item_ids={}", aweme_id);
 
-    let res = UserVideoInfo { user_info, video_info };
-
-    Ok(res)
-
+    get_json::<ItemInfoResponse>(&client, &api_url)
+        .await
+        .map_err(|e| error_message(e, DouyinError::NetworkError))?
+        .into_user_video_info()
+        .map_err(|e| e.to_string())
 }
 
 
 #[tauri::command]
-pub async fn douyin_single_download(save_path: String, video_url: String, window: Window) -> Result<String, String> {
-    let downloader = Downloader::new(video_url, save_path, Some(8))
-        .await
-        .map_err(|_|DouyinError::SystemError.to_string())?;
-    let save_path = downloader.get_save_path();
-    let downloader_clone = downloader.clone();
-    tokio::spawn(async move {
-        let total_size = downloader_clone.total_size();
-        loop {
-            let cur_size  = downloader_clone.downloaded_size().await;
-            if cur_size >= total_size {
-                let _ = window.emit("douyin_single_download", ElProgress{ percentage: 100 });
-                break;
+pub async fn douyin_single_download(video: VideoInfoItem, save_dir: String, collision_policy: Option<CollisionPolicy>, template: Option<String>, user_info: Option<UserInfo>, window: Window, manager: State<'_, DownloadManager>) -> Result<String, String> {
+    // 与批量下载一样由后端生成文件名, 标题中的非法字符与路径分隔符不会进入保存路径
+    let template = parse_template(template.as_deref())?;
+    let save_path = get_save_path(&save_dir, &template, &video, user_info.as_ref())?;
+    create_parent_dir(&save_path)?;
+    // 以视频ID作为下载ID, 前端可据此暂停/继续/取消, 单个下载优先于批量下载
+    let job = DownloadJob::new(video.video_id, video.video_url, save_path)
+        .with_priority(SINGLE_DOWNLOAD_PRIORITY)
+        .with_collision(collision_policy.unwrap_or_default());
+    let mut jobs = [job];
+    manager.preflight(Path::new(&save_dir), &mut jobs).await?;
+    let [job] = jobs;
+    let mut handle = manager.enqueue(job)?;
+    let progress_handler = handle.started().await.map(|downloader| {
+        let mut progress_rx = downloader.subscribe();
+        let window_progress = window.clone();
+        tokio::spawn(async move {
+            while progress_rx.changed().await.is_ok() {
+                // 下载完成并通过校验后才会发送100%
+                let percentage = std::cmp::min(progress_rx.borrow().percentage(), 99);
+                let _ = window_progress.emit("douyin_single_download", ElProgress{ percentage });
             }
-            let percentage = (cur_size as f64 * 100.0 / total_size as f64 ).round() as u8;
-            let _ = window.emit("douyin_single_download", ElProgress{ percentage });
-            sleep(Duration::from_millis(100)).await;
-        }
+        })
     });
-    match downloader.download().await {
-        Ok(_) => {
-            sleep(Duration::from_millis(100)).await
-        },
-        Err(_) => return Err(DouyinError::DownloadVideoError.to_string()),
-    };
+    let result = handle.finished().await;
+    if let Some(progress_handler) = progress_handler {
+        progress_handler.abort();
+    }
+    let save_path = result.map_err(|_| DouyinError::DownloadVideoError.to_string())?;
+    let _ = window.emit("douyin_single_download", ElProgress{ percentage: 100 });
     Ok(save_path)
 }
 
 
 // 获取用户信息
-async fn get_user_info(uid: &String) -> Result<UserInfo> {
+async fn get_user_info(client: &Client, uid: &String) -> Result<UserInfo> {
     
     let api_url = format!("https://www.iesdouyin.com/web/api/v2/user/info/?sec_uid={}", uid);
 
-    let data = reqwest::Client::builder()
-        .user_agent(USER_AGNET)
-        .build()?
-        .get(&api_url)
-        .send()
-        .await?
-        .json::<Value>()
-        .await?;
-
-    
-    let nickname = data["user_info"]["nickname"]
-        .to_string()
-        .replace('"', "");
-        
-    let video_count = data["user_info"]["aweme_count"]
-        .as_u64()
-        .map_or(0, |i|i) as u16;
-
-    let avatar_url = data["user_info"]["avatar_thumb"]["url_list"][0]
-        .to_string()
-        .replace('"', "");
-
-    Ok(UserInfo{
-        nickname,
-        uid: uid.to_string(),
-        avatar_url,
-        video_count,
-    })
+    let response = get_json::<UserInfoResponse>(client, &api_url).await?;
+    Ok(response.into_user_info(uid)?)
 }  
 
-async fn get_user_video_list(uid: String, count: u16, max_cursor: u64) -> Result<VideoInfo> {
+async fn get_user_video_list(client: &Client, uid: String, count: u16, max_cursor: u64) -> Result<VideoInfo> {
     let api_url = format!("https://www.iesdouyin.com/web/api/v2/aweme/post/?sec_uid={uid}&count={count}&max_cursor={max_cursor}");
 
-    let data = reqwest::Client::builder()
-        .user_agent(USER_AGNET)
-        .build()?
-        .get(&api_url)
-        .send()
-        .await?
-        .json::<Value>()
-        .await?;
-
-    let max_cursor = data["max_cursor"].as_u64().unwrap_or(0);
-    let has_more = data["has_more"].as_bool().unwrap_or(false);
-
-    if !data["aweme_list"].is_array() || data["aweme_list"].as_array().unwrap().len() == 0 {
-        return Ok(VideoInfo {
-            max_cursor,
-            has_more,
-            items: Vec::<VideoInfoItem>::new(),
-        });
-    }
-
-    let video_items = data["aweme_list"]
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|item| {
-            let video_id = item["aweme_id"].to_string().replace('"', "");
-            let mut video_title = item["desc"]
-                .to_string()
-                .replace('"', "")
-                .split("#")
-                .collect::<Vec<&str>>()[0]
-                .to_string()
-                .split("@")
-                .collect::<Vec<&str>>()[0]
-                .to_string();
-            if video_title.is_empty() {
-                video_title = format!("无标题{}", video_id);
-            }
-            let video_url = item["video"]["play_addr"]["url_list"][0].to_string().replace('"', "");
-            let cover_url = item["video"]["cover"]["url_list"][0].to_string().replace('"', "");
-            VideoInfoItem {
-                video_id,
-                video_title,
-                video_url,
-                cover_url,
-            }
-        }).collect::<Vec<VideoInfoItem>>();
-    
-    Ok(VideoInfo {
-        max_cursor,
-        has_more,
-        items: video_items,
-    })
+    let response = get_json::<AwemePostResponse>(client, &api_url).await?;
+    Ok(response.into_video_info())
 }
 
 
 #[tauri::command]
-pub async fn douyin_muplit_search(home_url: String) -> Result<UserVideoInfo, String>  {
+pub async fn douyin_muplit_search(home_url: String, http: State<'_, HttpClient>) -> Result<UserVideoInfo, String>  {
 
-    let client = reqwest::Client::builder()
-        .user_agent(USER_AGNET)
-        .build()
-        .map_err(|_|{ DouyinError::SystemError.to_string()})?;
+    let client = http.api_client();
     
-    let mut real_url = client.get(&home_url)
-        .send()
-        .await
-        .map_err(|_| DouyinError::NetworkError.to_string())?
-        .url()
-        .to_string();
-
-    let uid = get_id_from_url(&mut real_url);
-
-    if uid.is_empty() {
-        return Err(DouyinError::UserInfoNotFoundError.to_string()); 
-    }
+    let uid = match resolve_link(&client, &home_url).await.map_err(|e| e.to_string())? {
+        Some(DouyinId::User(sec_uid)) => sec_uid,
+        _ => return Err(DouyinError::UserInfoNotFoundError.to_string()),
+    };
     
-    let user_info = get_user_info(&uid)
+    let user_info = get_user_info(&client, &uid)
         .await
-        .map_err(|_|{ DouyinError::GetUserInfoFailureError
-        .to_string()})?;
+        .map_err(|e| error_message(e, DouyinError::GetUserInfoFailureError))?;
 
-    let video_info = get_user_video_list(uid, user_info.video_count, 0)
+    let video_info = get_user_video_list(&client, uid, user_info.video_count, 0)
         .await
-        .map_err(|_| {DouyinError::VideoInfoNotFoundError.to_string()})?;
+        .map_err(|e| error_message(e, DouyinError::VideoInfoNotFoundError))?;
     
     Ok(UserVideoInfo { user_info, video_info })
 }
@@ -354,13 +224,14 @@ pub async fn douyin_muplit_search(home_url: String) -> Result<UserVideoInfo, Str
 
 // 获取所有的视频信息
 #[tauri::command]
-pub async fn douyin_get_all_video_info(uid: String, video_count: u16, max_cursor: u64, window: tauri::Window) -> Result<(), String> {
+pub async fn douyin_get_all_video_info(uid: String, video_count: u16, max_cursor: u64, window: tauri::Window, http: State<'_, HttpClient>) -> Result<(), String> {
  
+    let client = http.api_client();
     let mut cursor = max_cursor;
     let mut retry_num = 3;
    
     loop {
-        let result = get_user_video_list(uid.clone(), video_count, cursor)
+        let result = get_user_video_list(&client, uid.clone(), video_count, cursor)
         .await
         .map_err(|_| {DouyinError::NetworkError.to_string()});
         if let Ok(v_info) = result {
@@ -383,59 +254,145 @@ pub async fn douyin_get_all_video_info(uid: String, video_count: u16, max_cursor
     Ok(())
 }
 
-pub fn get_save_path(save_dir: &String, video_title: &String) -> String {
+/// 文件名模板可以使用的字段
+fn template_values(item: &VideoInfoItem, user_info: Option<&UserInfo>) -> HashMap<&'static str, TemplateValue> {
+    let items: Vec<&str> = item.video_title.split("#").collect();
+    let create_time = match item.create_time {
+        0 => None,
+        create_time => Some(create_time),
+    };
+    HashMap::from([
+        ("title", TemplateValue::Text(items[0].trim().to_string())),
+        ("video_id", TemplateValue::Text(item.video_id.clone())),
+        ("nickname", TemplateValue::Text(user_info.map(|u| u.nickname.clone()).unwrap_or_default())),
+        ("uid", TemplateValue::Text(user_info.map(|u| u.uid.clone()).unwrap_or_default())),
+        ("create_date", TemplateValue::Date(create_time, "%Y-%m-%d")),
+        ("create_time", TemplateValue::Date(create_time, "%Y-%m-%d_%H-%M-%S")),
+        ("ext", TemplateValue::Text("mp4".to_string())),
+    ])
+}
+
+/// 按模板生成保存路径, 结果必须位于save_dir之下
+pub fn get_save_path(save_dir: &String, template: &FilenameTemplate, item: &VideoInfoItem, user_info: Option<&UserInfo>) -> Result<String, String> {
+
+    let filename = template.render(&template_values(item, user_info))
+        .map_err(|e| e.to_string())?;
+    // 渲染结果只能由普通的目录名与文件名组成, 不能是绝对路径, 也不能包含".."
+    let contained = filename.components().next().is_some()
+        && filename.components().all(|c| matches!(c, Component::Normal(_)));
+    if !contained {
+        return Err(DouyinError::SavePathError(filename.to_string_lossy().to_string()).to_string());
+    }
+    let save_path = Path::new(&save_dir).join(filename).to_string_lossy().to_string();
+
+    Ok(save_path)
+}
 
-    let items: Vec<&str> = video_title.split("#").collect();
-    let save_path = Path::new(&save_dir).join(&items[0].trim()).to_str().unwrap().to_string() + ".mp4"; 
+/// 文件名模板中可能包含子目录, 只为经过get_save_path检查的路径创建
+fn create_parent_dir(save_path: &str) -> Result<(), String> {
+    match Path::new(save_path).parent() {
+        Some(dir) => std::fs::create_dir_all(dir).map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
+}
 
-    save_path
+fn parse_template(template: Option<&str>) -> Result<FilenameTemplate, String> {
+    FilenameTemplate::parse(template.unwrap_or(DEFAULT_FILENAME_TEMPLATE))
+        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-pub async fn douyin_muplit_download(items: Vec<VideoInfoItem>, save_dir: String, window: Window) -> Result<(), String>{
+pub async fn douyin_muplit_download(items: Vec<VideoInfoItem>, save_dir: String, collision_policy: Option<CollisionPolicy>, template: Option<String>, user_info: Option<UserInfo>, window: Window, manager: State<'_, DownloadManager>) -> Result<(), String>{
+
+    let template = parse_template(template.as_deref())?;
+    let save_paths = items.iter()
+        .map(|item| get_save_path(&save_dir, &template, item, user_info.as_ref()))
+        .collect::<Result<Vec<String>, String>>()?;
+    for save_path in &save_paths {
+        create_parent_dir(save_path)?;
+    }
+
+    let mut jobs = items.iter()
+        .zip(save_paths)
+        .map(|(item, save_path)| DownloadJob::new(item.video_id.clone(), item.video_url.clone(), save_path)
+            .with_collision(collision_policy.unwrap_or_default()))
+        .collect::<Vec<DownloadJob>>();
+    // 整批下载所需的空间不足时一个都不开始
+    manager.preflight(Path::new(&save_dir), &mut jobs).await?;
 
     let window = Arc::new(window);
     let mut handler_list = Vec::new();
-    for item in items.iter() {
-        let video_title = Arc::new(item.video_title.clone());
-        let video_title_clone = video_title.clone();
-        let save_path = Arc::new(get_save_path(&save_dir, &video_title.clone())); 
-        let save_path_clone = save_path.clone();
-        let downloader = Downloader::new(item.video_url.clone(), save_path.clone().to_string(), Some(8)).await.unwrap();
-        let downloader_clone = downloader.clone();
-        let video_id = Arc::new(item.video_id.clone());
-        let video_id_clone = video_id.clone();
-        let window_progress = window.clone();
+    for (item, job) in items.iter().zip(jobs) {
+        let video_title = item.video_title.clone();
+        let video_id = item.video_id.clone();
+        let save_path = job.save_path.clone();
+        // 交给全局下载队列调度, 避免同时打开过多连接
+        let handle = manager.enqueue(job);
         let window_download = window.clone();
-        tokio::spawn(async move {
-            if let Err(_) = downloader.download().await {
-                let _ = window_download.emit("douyin_muplit_download", DouyinMuplitDownloadProgress { 
-                    video_id: video_id.to_string(),
-                    is_success: false,
-                    video_title: video_title.clone().to_string(),
-                    save_path: save_path.clone().to_string(),
-                });
-            }
-            
-        });
+        // 下载并校验完成后再通知前端结果
         let handler = tokio::spawn(async move {
-            let filesize = downloader_clone.total_size();
-            loop {
-                let download_size = downloader_clone.downloaded_size().await;
-                if download_size >= filesize {
-                    let _ = window_progress.emit("douyin_muplit_download", DouyinMuplitDownloadProgress { 
-                        video_id: video_id_clone.to_string(), 
-                        is_success: true,
-                        video_title: video_title_clone.clone().to_string(),
-                        save_path: save_path_clone.to_string()
-                    });
-                    break;
-                }
-                sleep(Duration::from_secs(1)).await;
-            }
+            let result = match handle {
+                Ok(handle) => handle.finished().await.map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            let _ = window_download.emit("douyin_muplit_download", DouyinMuplitDownloadProgress { 
+                video_id,
+                is_success: result.is_ok(),
+                video_title,
+                save_path: result.unwrap_or(save_path),
+            });
         });
         handler_list.push(handler);
     }
     join_all(handler_list).await;
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(video_title: &str) -> VideoInfoItem {
+        VideoInfoItem {
+            video_id: "7129374563826175240".to_string(),
+            video_title: video_title.to_string(),
+            video_url: String::new(),
+            cover_url: String::new(),
+            create_time: 0,
+        }
+    }
+
+    #[test]
+    fn builds_save_paths_under_save_dir() {
+        let save_dir = "/downloads".to_string();
+        let user_info = UserInfo {
+            nickname: "海边的/阿杰".to_string(),
+            uid: "96712384756".to_string(),
+            avatar_url: String::new(),
+            video_count: 1,
+        };
+        let cases = [
+            (None, "周末去看海 #旅行", "/downloads/周末去看海.mp4"),
+            (None, "../../etc/passwd", "/downloads/.._.._etc_passwd.mp4"),
+            (None, "a/b\\c:d", "/downloads/a_b_c_d.mp4"),
+            (None, "..", "/downloads/download.mp4"),
+            (Some("{nickname}/{video_id}.{ext}"), "标题", "/downloads/海边的_阿杰/7129374563826175240.mp4"),
+            (Some("../{title}.{ext}"), "标题", "/downloads/download/标题.mp4"),
+            (Some("/{title}.{ext}"), "标题", "/downloads/标题.mp4"),
+            (Some("{create_date}/{title}.{ext}"), "标题", "/downloads/标题.mp4"),
+        ];
+        for (template, title, expected) in cases {
+            let template = parse_template(template).unwrap();
+            let save_path = get_save_path(&save_dir, &template, &item(title), Some(&user_info)).unwrap();
+            assert_eq!(Path::new(&save_path), Path::new(expected), "{}", title);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in ["{title", "{unknown}.{ext}", "{title:abc}.{ext}"] {
+            let result = parse_template(Some(template))
+                .and_then(|t| get_save_path(&"/downloads".to_string(), &t, &item("标题"), None));
+            assert!(result.is_err(), "{}", template);
+        }
+    }
+}
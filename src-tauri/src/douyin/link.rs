@@ -0,0 +1,173 @@
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use tauri::regex::Regex;
+
+// 作品ID的位数范围
+const MIN_ID_LEN: usize = 15;
+const MAX_ID_LEN: usize = 20;
+
+/// 链接指向的内容
+#[derive(Debug, Clone, PartialEq)]
+pub enum DouyinId {
+    Video(String),
+    // 图文作品, 与视频共用作品接口
+    Note(String),
+    // 用户的sec_uid
+    User(String),
+    // 合集
+    Mix(String),
+    Music(String),
+}
+
+/// 分享文本的解析结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum Link {
+    Resolved(DouyinId),
+    // v.douyin.com短链接, 需要请求后按跳转后的地址再解析
+    Short(Url),
+}
+
+fn is_numeric_id(value: &str) -> bool {
+    (MIN_ID_LEN..=MAX_ID_LEN).contains(&value.len()) && value.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn is_sec_uid(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn is_douyin_host(host: &str) -> bool {
+    ["douyin.com", "iesdouyin.com"].iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+}
+
+fn is_short_link(host: &str) -> bool {
+    host == "v.douyin.com" || host == "v.iesdouyin.com"
+}
+
+// 只匹配URL允许的ASCII字符, 链接前后的中文自然被截断, 部分分享文本的链接没有协议头
+// 域名前要求ASCII单词边界, 避免把notdouyin.com之类的域名当作抖音
+static URL_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)(?-u:\b)(?:https?://)?(?:[a-z0-9-]+\.)*(?:iesdouyin|douyin)\.com(?:[/?#][A-Za-z0-9\-._~:/?#\[\]@!$&'*+,;=%]*)?").unwrap()
+});
+
+/// 从分享文本中找出抖音链接, 文本中的其他链接与邮箱之类的内容会被忽略
+fn find_url(text: &str) -> Option<Url> {
+    let url = URL_REGEX.find_iter(text)
+        .filter_map(|m| {
+            let candidate = m.as_str().trim_end_matches(['.', ',', ';', '!', '\'']);
+            let candidate = match candidate.to_ascii_lowercase().starts_with("http") {
+                true => candidate.to_string(),
+                false => format!("https://{}", candidate),
+            };
+            Url::parse(&candidate).ok()
+        })
+        .find(|url| matches!(url.host_str(), Some(host) if is_douyin_host(host)));
+    url
+}
+
+/// 按完整的抖音地址解析, 不识别的地址返回None
+pub fn parse_url(url: &Url) -> Option<DouyinId> {
+    if !matches!(url.host_str(), Some(host) if is_douyin_host(host)) {
+        return None;
+    }
+    // 网页版在用户主页, 搜索页等位置打开视频时, 视频ID放在modal_id中
+    if let Some((_, id)) = url.query_pairs().find(|(key, _)| key == "modal_id") {
+        if is_numeric_id(&id) {
+            return Some(DouyinId::Video(id.to_string()));
+        }
+    }
+
+    let segments: Vec<&str> = url.path_segments()?.filter(|s| !s.is_empty()).collect();
+    // iesdouyin.com的分享页为/share/<类型>/<ID>, 合集为/share/mix/detail/<ID>
+    let (kind, id) = match segments.as_slice() {
+        ["share", "mix", "detail", id, ..] => ("mix", *id),
+        ["share", kind, id, ..] | [kind, id, ..] => (*kind, *id),
+        _ => return None,
+    };
+    match kind {
+        "user" if is_sec_uid(id) => Some(DouyinId::User(id.to_string())),
+        "user" => None,
+        _ if !is_numeric_id(id) => None,
+        "video" => Some(DouyinId::Video(id.to_string())),
+        "note" | "slides" => Some(DouyinId::Note(id.to_string())),
+        "collection" | "mix" => Some(DouyinId::Mix(id.to_string())),
+        "music" => Some(DouyinId::Music(id.to_string())),
+        _ => None,
+    }
+}
+
+/// 解析用户输入的链接或分享文本, 也接受单独的作品ID
+pub fn parse(text: &str) -> Option<Link> {
+    let text = text.trim();
+    if is_numeric_id(text) {
+        return Some(Link::Resolved(DouyinId::Video(text.to_string())));
+    }
+    let url = find_url(text)?;
+    match matches!(url.host_str(), Some(host) if is_short_link(host)) {
+        true => Some(Link::Short(url)),
+        false => parse_url(&url).map(Link::Resolved),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(id: &str) -> Option<Link> {
+        Some(Link::Resolved(DouyinId::Video(id.to_string())))
+    }
+
+    fn short(url: &str) -> Option<Link> {
+        Some(Link::Short(Url::parse(url).unwrap()))
+    }
+
+    #[test]
+    fn parses_links() {
+        let cases = [
+            ("https://www.douyin.com/video/7129374563826175240", video("7129374563826175240")),
+            ("https://www.douyin.com/video/7129374563826175240?previous_page=app_code_link", video("7129374563826175240")),
+            ("www.douyin.com/video/7129374563826175240/", video("7129374563826175240")),
+            ("https://www.iesdouyin.com/share/video/7129374563826175240/?region=CN&mid=7129374600000000000&u_code=0", video("7129374563826175240")),
+            ("https://www.douyin.com/user/MS4wLjABAAAA-Kx_9tYrJ3?modal_id=7129374563826175240", video("7129374563826175240")),
+            ("https://www.douyin.com/discover?modal_id=7129374563826175240", video("7129374563826175240")),
+            ("7129374563826175240", video("7129374563826175240")),
+            ("https://www.douyin.com/note/7118000000000000001", Some(Link::Resolved(DouyinId::Note("7118000000000000001".to_string())))),
+            ("https://www.iesdouyin.com/share/slides/7118000000000000001/", Some(Link::Resolved(DouyinId::Note("7118000000000000001".to_string())))),
+            ("https://www.douyin.com/user/MS4wLjABAAAA-Kx_9tYrJ3", Some(Link::Resolved(DouyinId::User("MS4wLjABAAAA-Kx_9tYrJ3".to_string())))),
+            ("https://www.iesdouyin.com/share/user/MS4wLjABAAAA-Kx_9tYrJ3?sec_uid=MS4wLjABAAAA-Kx_9tYrJ3", Some(Link::Resolved(DouyinId::User("MS4wLjABAAAA-Kx_9tYrJ3".to_string())))),
+            ("https://www.douyin.com/collection/7100000000000000003", Some(Link::Resolved(DouyinId::Mix("7100000000000000003".to_string())))),
+            ("https://www.iesdouyin.com/share/mix/detail/7100000000000000003/", Some(Link::Resolved(DouyinId::Mix("7100000000000000003".to_string())))),
+            ("https://www.douyin.com/music/7129374600000000000", Some(Link::Resolved(DouyinId::Music("7129374600000000000".to_string())))),
+            ("https://www.iesdouyin.com/share/music/7129374600000000000/", Some(Link::Resolved(DouyinId::Music("7129374600000000000".to_string())))),
+            ("https://v.douyin.com/iFRvuXY/", short("https://v.douyin.com/iFRvuXY/")),
+            ("7.43 复制打开抖音，看看【海边的阿杰的作品】周末去看海 # 旅行 https://v.douyin.com/iFRvuXY/ 12@qq.com :9pm", short("https://v.douyin.com/iFRvuXY/")),
+            ("长按复制此条消息，打开抖音搜索，查看TA的更多作品。https://v.douyin.com/iFRvuXY/，", short("https://v.douyin.com/iFRvuXY/")),
+            ("看看这个v.douyin.com/iFRvuXY/好好玩", short("https://v.douyin.com/iFRvuXY/")),
+            // 字符类写法会把/ideo/之类的路径也当作视频
+            ("https://www.douyin.com/ideo/7129374563826175240", None),
+            ("https://www.douyin.com/video/not-a-number", None),
+            ("https://www.douyin.com/user/", None),
+            ("https://www.douyin.com/", None),
+            ("https://www.example.com/video/7129374563826175240", None),
+            ("https://notdouyin.com/video/7129374563826175240", None),
+            ("12345", None),
+            ("", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn parses_redirected_urls() {
+        let cases = [
+            ("https://www.iesdouyin.com/share/video/7129374563826175240/?region=CN", Some(DouyinId::Video("7129374563826175240".to_string()))),
+            ("https://www.iesdouyin.com/share/user/MS4wLjABAAAA-Kx_9tYrJ3?did=1", Some(DouyinId::User("MS4wLjABAAAA-Kx_9tYrJ3".to_string()))),
+            ("https://www.iesdouyin.com/share/note/7118000000000000001/", Some(DouyinId::Note("7118000000000000001".to_string()))),
+            ("https://www.douyin.com/", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_url(&Url::parse(input).unwrap()), expected, "{}", input);
+        }
+    }
+}
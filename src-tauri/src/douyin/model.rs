@@ -0,0 +1,289 @@
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
+use super::{DouyinError, UserInfo, UserVideoInfo, VideoInfo, VideoInfoItem};
+
+/// 接口常把空字段返回为null, 按默认值处理
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+/// 响应不是预期的结构时返回UnexpectedResponseError, 而不是得到一堆"null"
+pub fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, DouyinError> {
+    serde_json::from_slice(body).map_err(|e| DouyinError::UnexpectedResponseError(e.to_string()))
+}
+
+/// 图片与视频地址, 第一个为主地址
+#[derive(Debug, Default, Deserialize)]
+pub struct UrlList {
+    #[serde(default, deserialize_with = "nullable")]
+    pub url_list: Vec<String>,
+}
+
+impl UrlList {
+
+    pub fn first(&self) -> Option<&str> {
+        self.url_list.first().map(|url| url.as_str()).filter(|url| !url.is_empty())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Video {
+    pub play_addr: UrlList,
+    pub cover: UrlList,
+    // 未裁剪的封面, 只有iteminfo返回
+    pub origin_cover: UrlList,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ShareInfo {
+    #[serde(deserialize_with = "nullable")]
+    pub share_title: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Author {
+    #[serde(deserialize_with = "nullable")]
+    pub uid: String,
+    #[serde(deserialize_with = "nullable")]
+    pub nickname: String,
+    pub avatar_thumb: UrlList,
+}
+
+/// 一个作品, 图文作品没有视频
+#[derive(Debug, Deserialize)]
+pub struct Aweme {
+    pub aweme_id: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub desc: String,
+    #[serde(default)]
+    pub create_time: i64,
+    #[serde(default)]
+    pub video: Option<Video>,
+    #[serde(default)]
+    pub share_info: ShareInfo,
+    #[serde(default)]
+    pub author: Author,
+}
+
+impl Aweme {
+
+    fn play_url(&self) -> Option<&str> {
+        self.video.as_ref().and_then(|video| video.play_addr.first())
+    }
+}
+
+/// 标题只取话题与@之前的部分, 为空时用作品ID代替
+/// 单个作品与作品列表使用同一个默认标题, 同一作品的文件名不随下载页面变化
+fn clean_title(text: &str, separators: &[char], aweme_id: &str) -> String {
+    let title = text.split(separators).next().unwrap_or_default().trim();
+    match title.is_empty() {
+        true => format!("无标题{}", aweme_id),
+        false => title.to_string(),
+    }
+}
+
+/// /web/api/v2/aweme/iteminfo/
+#[derive(Debug, Deserialize)]
+pub struct ItemInfoResponse {
+    #[serde(default, deserialize_with = "nullable")]
+    pub item_list: Vec<Aweme>,
+}
+
+impl ItemInfoResponse {
+
+    /// 单个视频的标题来自分享标题, 同时带回作者信息
+    pub fn into_user_video_info(self) -> Result<UserVideoInfo, DouyinError> {
+        let aweme = self.item_list.into_iter().next().ok_or(DouyinError::VideoInfoNotFoundError)?;
+        let video = aweme.video.as_ref().ok_or(DouyinError::VideoInfoNotFoundError)?;
+        let video_url = aweme.play_url()
+            .ok_or_else(|| DouyinError::UnexpectedResponseError("missing video.play_addr".to_string()))?
+            .replace("playwm", "play")
+            .replace("ratio=720p", "ratio=1080p");
+
+        let user_info = UserInfo {
+            nickname: aweme.author.nickname.clone(),
+            uid: aweme.author.uid.clone(),
+            avatar_url: aweme.author.avatar_thumb.first().unwrap_or_default().to_string(),
+            video_count: 1,
+        };
+        let item = VideoInfoItem {
+            video_title: clean_title(&aweme.share_info.share_title, &['@'], &aweme.aweme_id),
+            video_url,
+            cover_url: video.origin_cover.first().unwrap_or_default().to_string(),
+            create_time: aweme.create_time,
+            video_id: aweme.aweme_id,
+        };
+        let video_info = VideoInfo {
+            max_cursor: 0,
+            has_more: false,
+            items: vec![item],
+        };
+        Ok(UserVideoInfo { user_info, video_info })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct UserProfile {
+    #[serde(deserialize_with = "nullable")]
+    pub nickname: String,
+    pub aweme_count: u64,
+    pub avatar_thumb: UrlList,
+}
+
+/// /web/api/v2/user/info/
+#[derive(Debug, Deserialize)]
+pub struct UserInfoResponse {
+    #[serde(default)]
+    pub user_info: Option<UserProfile>,
+}
+
+impl UserInfoResponse {
+
+    pub fn into_user_info(self, uid: &str) -> Result<UserInfo, DouyinError> {
+        let profile = self.user_info.ok_or(DouyinError::UserInfoNotFoundError)?;
+        Ok(UserInfo {
+            nickname: profile.nickname,
+            uid: uid.to_string(),
+            avatar_url: profile.avatar_thumb.first().unwrap_or_default().to_string(),
+            video_count: std::cmp::min(profile.aweme_count, u16::MAX as u64) as u16,
+        })
+    }
+}
+
+/// /web/api/v2/aweme/post/
+#[derive(Debug, Deserialize)]
+pub struct AwemePostResponse {
+    #[serde(default, deserialize_with = "nullable")]
+    pub aweme_list: Vec<Aweme>,
+    #[serde(default)]
+    pub max_cursor: u64,
+    #[serde(default)]
+    pub has_more: bool,
+}
+
+impl AwemePostResponse {
+
+    /// 没有视频地址的作品(如图文)无法下载, 直接跳过
+    pub fn into_video_info(self) -> VideoInfo {
+        let items = self.aweme_list.into_iter()
+            .filter_map(|aweme| {
+                let video_url = aweme.play_url()?.to_string();
+                let cover_url = aweme.video.as_ref()
+                    .and_then(|video| video.cover.first())
+                    .unwrap_or_default()
+                    .to_string();
+                Some(VideoInfoItem {
+                    video_title: clean_title(&aweme.desc, &['#', '@'], &aweme.aweme_id),
+                    video_url,
+                    cover_url,
+                    create_time: aweme.create_time,
+                    video_id: aweme.aweme_id,
+                })
+            })
+            .collect();
+        VideoInfo {
+            max_cursor: self.max_cursor,
+            has_more: self.has_more,
+            items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_iteminfo() {
+        let response: ItemInfoResponse = parse(include_bytes!("fixtures/iteminfo.json")).unwrap();
+        let info = response.into_user_video_info().unwrap();
+
+        assert_eq!(info.user_info.nickname, "海边的\"阿杰\"");
+        assert_eq!(info.user_info.uid, "96712384756");
+        assert!(info.user_info.avatar_url.starts_with("https://p3-pc.douyinpic.com/img/aweme-avatar/"));
+
+        let item = &info.video_info.items[0];
+        assert_eq!(item.video_id, "7129374563826175240");
+        assert_eq!(item.video_title, "周末去看海 \"蓝色\"的一天 #旅行");
+        assert_eq!(item.video_url, "https://aweme.snssdk.com/aweme/v1/play/?video_id=v0200fg10000cbo1s4bc77u4t8vu7aqg&ratio=1080p&line=0");
+        assert_eq!(item.cover_url, "https://p3-sign.douyinpic.com/large/tos-cn-p-0015/3f5a1c.jpeg");
+        assert_eq!(item.create_time, 1659960000);
+    }
+
+    #[test]
+    fn uses_same_untitled_title() {
+        // 单个作品与作品列表中同一作品的标题都为空
+        let mut iteminfo: serde_json::Value = serde_json::from_slice(include_bytes!("fixtures/iteminfo.json")).unwrap();
+        iteminfo["item_list"][0]["share_info"]["share_title"] = "@海边的阿杰".into();
+        let response: ItemInfoResponse = parse(&serde_json::to_vec(&iteminfo).unwrap()).unwrap();
+        let single = response.into_user_video_info().unwrap().video_info.items.remove(0);
+
+        let mut aweme_post: serde_json::Value = serde_json::from_slice(include_bytes!("fixtures/aweme_post.json")).unwrap();
+        aweme_post["aweme_list"][0]["desc"] = "#旅行".into();
+        let response: AwemePostResponse = parse(&serde_json::to_vec(&aweme_post).unwrap()).unwrap();
+        let listed = response.into_video_info().items.remove(0);
+
+        assert_eq!(single.video_id, listed.video_id);
+        assert_eq!(single.video_title, format!("无标题{}", single.video_id));
+        assert_eq!(listed.video_title, single.video_title);
+    }
+
+    #[test]
+    fn reports_missing_video() {
+        let response: ItemInfoResponse = parse(include_bytes!("fixtures/iteminfo_empty.json")).unwrap();
+        assert!(matches!(response.into_user_video_info(), Err(DouyinError::VideoInfoNotFoundError)));
+    }
+
+    #[test]
+    fn parses_user_info() {
+        let response: UserInfoResponse = parse(include_bytes!("fixtures/user_info.json")).unwrap();
+        let user_info = response.into_user_info("MS4wLjABAAAA").unwrap();
+
+        assert_eq!(user_info.nickname, "海边的\"阿杰\"");
+        assert_eq!(user_info.uid, "MS4wLjABAAAA");
+        assert_eq!(user_info.video_count, 37);
+
+        let response: UserInfoResponse = parse(br#"{"status_code": 0}"#).unwrap();
+        assert!(matches!(response.into_user_info("MS4wLjABAAAA"), Err(DouyinError::UserInfoNotFoundError)));
+    }
+
+    #[test]
+    fn parses_aweme_post() {
+        let response: AwemePostResponse = parse(include_bytes!("fixtures/aweme_post.json")).unwrap();
+        let info = response.into_video_info();
+
+        assert_eq!(info.max_cursor, 1655000000000);
+        assert!(info.has_more);
+        // 图文作品没有视频地址, 被跳过
+        assert_eq!(info.items.len(), 2);
+
+        assert_eq!(info.items[0].video_title, "周末去看海 \"蓝色\"的一天");
+        assert_eq!(info.items[0].cover_url, "https://p3-sign.douyinpic.com/tos-cn-p-0015/3f5a1c~c5_300x400.jpeg");
+
+        // 缺少的字段使用默认值, 不会变成"null"
+        assert_eq!(info.items[1].video_title, "无标题7118000000000000001");
+        assert_eq!(info.items[1].cover_url, "");
+        assert_eq!(info.items[1].create_time, 0);
+    }
+
+    #[test]
+    fn rejects_unexpected_shape() {
+        let cases: [&[u8]; 4] = [
+            br#"{"item_list": "not a list"}"#,
+            br#"{"item_list": [{"desc": "missing aweme_id"}]}"#,
+            br#"{"item_list": [{"aweme_id": 7129374563826175240}]}"#,
+            b"<html>blocked</html>",
+        ];
+        for body in cases {
+            let result = parse::<ItemInfoResponse>(body);
+            assert!(matches!(result, Err(DouyinError::UnexpectedResponseError(_))), "{}", String::from_utf8_lossy(body));
+        }
+    }
+}
@@ -1,232 +1,1688 @@
-use std::{sync::Arc, fs};
-use futures::future::join_all;
-use reqwest::Client;
-use tokio::sync::RwLock;
+use std::{sync::{Arc, atomic::{AtomicBool, AtomicUsize, AtomicU64, AtomicU8, Ordering}}, collections::VecDeque, time::Duration, future::Future};
+use futures::{future::join_all, stream, StreamExt};
+use reqwest::{Client, Response, StatusCode, Url, header::HeaderMap};
+use tokio::{sync::{Mutex, Notify, watch}, task::JoinHandle, time::{sleep, timeout}};
+use tokio_util::sync::CancellationToken;
 use anyhow::Result;
 use log::error;
 use std::path::Path;
+use thiserror::Error;
+use crate::http::HttpClient;
 
-const USER_AGNET: &'static str = "Mozilla/5.0 (iPhone; CPU iPhone OS 13_2_3 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/13.0.3 Mobile/15E148 Safari/604.1";
+mod state;
+mod retry;
+mod verify;
+mod writer;
+mod progress;
+mod control;
+mod ratelimit;
+mod probe;
+mod filename;
+mod storage;
+mod space;
+mod hls;
+mod segment;
+mod dash;
+mod postprocess;
+#[cfg(test)]
+pub(crate) mod test_server;
+use state::DownloadState;
+use control::DownloadControl;
+use writer::{FileWriter, BufferedWriter};
+use segment::SegmentStream;
+use dash::{Representation, TrackSource};
+use progress::{RangeProgress, SpeedMeter};
+pub use progress::{Progress, ChunkProgress, SegmentProgress, StepProgress, ChunkStatus, DownloadStatus};
+pub use ratelimit::{RateLimiter, BandwidthSchedule, ScheduleRule};
+pub use retry::RetryPolicy;
+pub use probe::Probe;
+pub use verify::Checksum;
+use verify::StreamHasher;
+pub use filename::{CollisionPolicy, sanitize_filename};
+pub use storage::StorageBackend;
+pub use space::available_space;
+pub use segment::StreamOptions;
+pub use postprocess::{PostProcessConfig, PostProcessor, PostStep};
 
+// 每个分片累计写入这么多字节后保存一次断点续传状态
+const STATE_FLUSH_BYTES: u64 = 1024 * 1024;
+
+// 分片剩余部分小于两倍此大小时不再拆分
+const MIN_SPLIT_SIZE: u64 = 256 * 1024;
+
+// 初始切分的目标分段大小, 分段越小, 慢连接拖住的数据越少
+const SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+// 初始分段数的上限, 避免大文件的状态文件过大
+const MAX_SEGMENTS: u64 = 64;
+
+// 开始时使用的连接数, 之后根据吞吐量逐个增加, 最多chunk_count个
+const INITIAL_CONNECTIONS: u64 = 2;
+
+// 自动调整连接数的采样间隔
+const TUNE_INTERVAL: Duration = Duration::from_secs(2);
+
+// 新增连接后吞吐量至少提升这个比例才继续增加连接
+const TUNE_MIN_GAIN: f64 = 0.1;
+
+// 下载过程中写入的临时文件后缀, 校验通过后才重命名为目标文件
+const PART_FILE_SUFFIX: &'static str = ".part";
+
+// 推送进度快照的间隔
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Error, Debug)]
+pub enum DownloadError {
+
+    #[error("远程文件已变更")]
+    RemoteChanged,
+
+    #[error("服务器不支持分段下载")]
+    RangeNotSupported,
+
+    #[error("分片数据不完整")]
+    IncompleteRange,
+
+    #[error("以下分片下载失败: {}", format_ranges(.0))]
+    RangesFailed(Vec<(u64, u64)>),
+
+    #[error("Content-Range与请求的分片不一致: {0}")]
+    ContentRangeMismatch(String),
+
+    #[error("文件大小不一致, 期望{expected}字节, 实际{actual}字节")]
+    SizeMismatch { expected: u64, actual: u64 },
+
+    #[error("文件校验失败")]
+    ChecksumMismatch,
+
+    #[error("读取数据超时")]
+    ReadTimeout,
+
+    #[error("磁盘空间不足, 需要{required}字节, 可用{available}字节")]
+    InsufficientSpace { required: u64, available: u64 },
+
+    #[error("磁盘已满")]
+    DiskFull,
+
+    #[error("下载被中断")]
+    Interrupted,
+
+    #[error("下载已取消")]
+    Cancelled,
+}
+
+fn format_ranges(ranges: &[(u64, u64)]) -> String {
+    ranges.iter()
+        .map(|(start, end)| format!("{}-{}", start, end))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn is_remote_changed(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<DownloadError>(), Some(DownloadError::RemoteChanged))
+}
+
+fn is_range_not_supported(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<DownloadError>(), Some(DownloadError::RangeNotSupported))
+}
+
+fn is_disk_full(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<DownloadError>(), Some(DownloadError::DiskFull))
+}
+
+fn is_interrupted(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<DownloadError>(), Some(DownloadError::Interrupted))
+}
+
+/// 等待future的同时响应暂停与取消
+async fn interruptible<T>(token: &CancellationToken, future: impl Future<Output = T>) -> Result<T> {
+    tokio::select! {
+        _ = token.cancelled() => Err(DownloadError::Interrupted.into()),
+        value = future => Ok(value),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub chunk_count: Option<u8>,
+    pub retry: RetryPolicy,
+    // 调用方已知的文件摘要, 优先于服务器提供的Content-MD5
+    pub checksum: Option<Checksum>,
+    // 将形如MD5的ETag当作文件摘要校验
+    pub verify_etag: bool,
+    // 单个下载的限速, 每秒字节数, 0表示不限速
+    pub rate_limit: u64,
+    // 所有下载共用的全局限速器
+    pub global_limiter: Option<RateLimiter>,
+    // 共用的网络客户端, 为空时使用默认配置
+    pub http: Option<HttpClient>,
+    // 目标文件已存在时的处理方式
+    pub collision: CollisionPolicy,
+    // CollisionPolicy::AppendId使用的ID
+    pub download_id: Option<String>,
+    // 临时数据的存储后端
+    pub storage: StorageBackend,
+    // HLS与DASH清晰度的选择条件
+    pub stream: StreamOptions,
+    // 下载完成的MPEG-TS文件转封装为MP4
+    pub remux_ts: bool,
+    // 下载完成后执行的ffmpeg后处理
+    pub post_process: Option<PostProcessor>,
+    // 开始下载前已经完成的探测, 不再重复请求
+    pub probe: Option<Arc<Probe>>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Downloader {
     url: Arc<String>,
-    filesize: Arc<u64>,
+    // chunked传输等没有Content-Length的响应大小未知
+    filesize: Arc<Option<u64>>,
     savepath: Arc<String>,
-    support_range: Arc<bool>,
+    partpath: Arc<String>,
+    support_range: Arc<AtomicBool>,
     chunk_count: Arc<u8>,
-    rw_lock: Arc<RwLock<u64>>,
+    downloaded: Arc<AtomicU64>,
+    range_progress: Arc<std::sync::RwLock<Vec<Arc<RangeProgress>>>>,
+    connections: Arc<AtomicUsize>,
+    status: Arc<AtomicU8>,
+    speed: Arc<std::sync::Mutex<SpeedMeter>>,
+    progress_tx: Arc<watch::Sender<Progress>>,
+    control: Arc<DownloadControl>,
+    state: Arc<Mutex<DownloadState>>,
+    retry: Arc<RetryPolicy>,
+    checksum: Arc<Option<Checksum>>,
+    limiter: RateLimiter,
+    global_limiter: Option<RateLimiter>,
+    client: Client,
+    // 播放列表的密钥等小请求使用, 整个请求有超时
+    api_client: Client,
+    read_timeout: Option<Duration>,
+    // 探测时服务器已返回的完整文件响应, 第一次下载时直接读取
+    probe_body: Arc<std::sync::Mutex<Option<Response>>>,
+    // 扩展名与文件实际类型不符时重命名后的路径
+    renamed: Arc<std::sync::Mutex<Option<String>>>,
+    // 按CollisionPolicy::Skip跳过已存在的文件
+    skipped: bool,
+    storage: StorageBackend,
+    // 暂停后继续下载时沿用同一个存储, 内存存储中的数据不会丢失
+    writer: Arc<std::sync::Mutex<Option<FileWriter>>>,
+    // 地址是m3u8播放列表或DASH分段时按分段下载
+    segments: Option<Arc<SegmentStream>>,
+    // DASH的音视频轨道, 每个轨道是一个单独的下载
+    tracks: Arc<Vec<Arc<Downloader>>>,
+    remux_ts: bool,
+    // 转封装得到的MP4同样按冲突策略选择文件名
+    collision: CollisionPolicy,
+    download_id: Arc<Option<String>>,
+    post_processor: Arc<Option<PostProcessor>>,
+    // 正在执行的后处理步骤, 失败时保留失败的步骤
+    step: Arc<std::sync::Mutex<Option<StepProgress>>>,
+    post_process_error: Arc<std::sync::Mutex<Option<String>>>,
 }
 
-#[cfg(any(windows))]
-async fn write_bytes_to_file(filepath: &str, bytes: &[u8], offset: u64) -> Result<usize, std::io::Error> {
-    use std::os::windows::fs::FileExt;
-    let file = fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(filepath)?;
-    file.seek_write(&bytes, offset)
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
 }
 
-#[cfg(any(unix))]
-async fn write_bytes_to_file(filepath: &str, bytes: &[u8], offset: u64) -> Result<usize, std::io::Error> {
-    use std::os::unix::fs::FileExt;
-    let file = fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(filepath)?;
-    file.write_at(&bytes, offset)
+/// 清单的扩展名换成合并后文件的扩展名, 其他扩展名保持不变
+fn replace_extension(savepath: String, from: &str, to: &str) -> String {
+    match savepath.to_ascii_lowercase().ends_with(from) {
+        true => Path::new(&savepath).with_extension(to).to_string_lossy().to_string(),
+        false => savepath,
+    }
 }
 
-#[cfg(any(linux))]
-async fn write_bytes_to_file(filepath: &str, bytes: &[u8], offset: u64) -> Result<usize, std::io::Error> {
-    tokio_uring::start(async {
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open("filepath")
-            .await?;
-        let (res, _) = file.write_at(bytes. offset).await;
-        let n = res?;
-        file.close().await?;
-    })
+/// 初始分段数, 按SEGMENT_SIZE切分, 至少与最大连接数相同
+fn segment_count(filesize: u64, chunk_count: u8) -> u64 {
+    let count = std::cmp::min((filesize + SEGMENT_SIZE - 1) / SEGMENT_SIZE, MAX_SEGMENTS);
+    std::cmp::max(count, chunk_count as u64)
 }
 
+/// 将文件按分段数切分为闭区间, 最后一个分段不会超过文件末尾
+fn split_ranges(filesize: u64, count: u64) -> Vec<(u64, u64)> {
+    let count = std::cmp::max(count, 1);
+    let chunk_size = std::cmp::max((filesize + count - 1) / count, 1);
+    let mut range_list = vec![];
+    let mut start = 0;
+
+    while start < filesize {
+        let end = std::cmp::min(start + chunk_size, filesize) - 1;
+        range_list.push((start, end));
+        start = end + 1;
+    }
+    range_list
+}
+
+/// 开始下载前探测远程文件, 用于检查磁盘空间, 结果通过DownloadOptions::probe交给Downloader
+/// 不保留探测时的响应体, 排队等待期间不占用连接
+pub async fn probe(client: &Client, url: &str) -> Result<Arc<Probe>> {
+    let mut probe = Probe::run(client, url).await?;
+    probe.body = None;
+    Ok(Arc::new(probe))
+}
 
 impl Downloader {
 
     pub async fn new(url: String, savepath: String, chunk_count: Option<u8>) -> Result<Arc<Self>> {
+        Self::with_options(url, savepath, DownloadOptions { chunk_count, ..Default::default() }).await
+    }
+
+    pub async fn with_options(url: String, savepath: String, mut options: DownloadOptions) -> Result<Arc<Self>> {
         
-        let response = Client::builder()
-        .user_agent(USER_AGNET)
-        .build()?
-        .get(&url)
-        .send()
-        .await?;
-        
-        let url = Arc::new(url);
+        let http = options.http.clone().unwrap_or_default();
+        let client = http.client();
+
+        let mut probe = match options.probe.take() {
+            Some(probe) => probe.detached(),
+            None => Probe::run(&client, &url).await?,
+        };
+        let mut savepath = filename::resolve_save_path(&savepath, &probe.headers, &probe.url);
 
-        let file_extension = match response.headers().get("content-type") {
-            Some(content_type) => match content_type.to_str().unwrap_or("video/mp4") {
-                "video/x-flv" => ".flv",
-                "video/mp4" => ".mp4",
-                "application/x-mpegURL" => ".m3u8",
-                "video/MP2T" => ".ts",
-                "video/3gpp" => ".3gpp",
-                "video/quicktime" => ".mov",
-                "video/x-msvideo" => ".avi",
-                "video/x-ms-wmv" => ".wmv",
-                "audio/x-wav" => ".wav",
-                "audio/x-mp3" => ".mp3",
-                "audio/mp4"   => ".mp4",
-                "application/ogg" => ".ogg",
-                "image/jpeg" => ".jpeg",
-                "image/png"  => ".png",
-                "image/tiff" => ".tiff",
-                "image/gif"  => ".gif",
-                "image/svg+xml" => ".svg",
-                _ => ".mp4"
+        // DASH的音视频轨道分别下载, MPD本身与最终文件无关
+        if dash::is_manifest(&probe.headers, &probe.url) {
+            let representations = dash::load(&http.api_client(), &probe.url, probe.body.take(), &options.stream).await?;
+            let mut downloader = Self::build(url, replace_extension(savepath, ".mpd", "mp4"), Probe::unknown(probe.url), None, options.clone(), &http).await?;
+            if !downloader.skipped {
+                downloader.add_tracks(representations, options, &http).await?;
+            }
+            return Ok(Arc::new(downloader));
+        }
+
+        // m3u8播放列表按HLS下载分段, 合并后的大小在下载完成前未知
+        let segments = match hls::is_playlist(&probe.headers, &probe.url) {
+            true => {
+                let stream = hls::load(&http.api_client(), &probe.url, probe.body.take(), &options.stream).await?;
+                // 分段合并后是MPEG-TS文件, 不能沿用播放列表的扩展名
+                savepath = replace_extension(savepath, ".m3u8", "ts");
+                probe = Probe::unknown(probe.url);
+                Some(stream)
             },
-            None => ".mp4",
-        }.to_string();
-
-        let path = Path::new(&savepath);
-        let dir = path.parent().unwrap();
-        let file_stem = path.file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap();
-        let filename = format!("{}{}", file_stem, file_extension);
-        let savepath = Arc::new(dir.join(filename)
-            .to_str()
-            .unwrap()
-            .to_string()
-        );
-
-        let filesize = Arc::new(response.content_length().unwrap());
-        let chunk_count = Arc::new(match chunk_count {
+            false => None,
+        };
+
+        Ok(Arc::new(Self::build(url, savepath, probe, segments, options, &http).await?))
+    }
+
+    /// 按探测结果创建下载, 检查断点续传状态并按冲突策略占用文件名
+    async fn build(url: String, savepath: String, probe: Probe, segments: Option<SegmentStream>, options: DownloadOptions, http: &HttpClient) -> Result<Self> {
+        let client = http.client();
+        let read_timeout = http.read_timeout();
+        let segments = segments.map(Arc::new);
+
+        let url = Arc::new(url);
+
+        let filesize = Arc::new(probe.filesize);
+        let chunk_count = Arc::new(match options.chunk_count {
             Some(c) => c,
             None => 4,
         });
 
-        let support_range = Arc::new(match response.headers().get("Accept-Ranges") {
-            Some(_) => true,
-            None => false,
+        let etag = header_value(&probe.headers, "ETag");
+        let last_modified = header_value(&probe.headers, "Last-Modified");
+
+        let checksum = options.checksum.or_else(|| match probe.full_response {
+            true => header_value(&probe.headers, "Content-MD5")
+                .and_then(|v| Checksum::from_content_md5(&v)),
+            false => None,
+        }).or_else(|| match (&etag, options.verify_etag) {
+            (Some(etag), true) => Checksum::from_etag(etag),
+            _ => None,
         });
+        let checksum = Arc::new(checksum);
+        let support_range = Arc::new(AtomicBool::new(probe.support_range));
+        let probe_body = Arc::new(std::sync::Mutex::new(probe.body));
+
+        let limiter = RateLimiter::new(options.rate_limit);
+        let global_limiter = options.global_limiter;
+
+        // 同一目标文件存在未完成的状态文件与临时文件, 且远程文件未变更时从断点继续下载
+        // 内存存储没有可续传的临时文件
+        let size = filesize.unwrap_or(0);
+        let storage = options.storage;
+        let resumed = match DownloadState::load(&savepath).await {
+            Some(state) if storage.is_persistent()
+                && support_range.load(Ordering::Acquire)
+                && Path::new(&format!("{}{}", savepath, PART_FILE_SUFFIX)).exists()
+                && state.matches(&url, size, &etag, &last_modified) => Some(state),
+            _ => None,
+        };
+        // 不是续传时按冲突策略选择文件名, 临时文件与状态文件都以最终的路径为准, 下载完成后可能按文件头再修正扩展名
+        let (savepath, skipped) = match resumed {
+            Some(_) => (savepath, false),
+            None => filename::claim_save_path(&savepath, options.collision, options.download_id.as_deref())?,
+        };
+        // 全新下载前检查磁盘空间, 续传时临时文件已经预分配过空间
+        if resumed.is_none() && !skipped {
+            if let Err(e) = space::check_space(Path::new(&savepath), size) {
+                let _ = tokio::fs::remove_file(format!("{}{}", savepath, PART_FILE_SUFFIX)).await;
+                return Err(e);
+            }
+        }
+        let savepath = Arc::new(savepath);
+        let partpath = Arc::new(format!("{}{}", savepath, PART_FILE_SUFFIX));
+        let state = match resumed {
+            Some(state) => state,
+            None => DownloadState::new(&url, etag, last_modified, size, split_ranges(size, segment_count(size, *chunk_count))),
+        };
 
-        let rw_lock = Arc::new(RwLock::<u64>::new(0));
+        let downloaded = Arc::new(AtomicU64::new(state.downloaded()));
+        let range_progress = Arc::new(std::sync::RwLock::new(
+            state.ranges.iter()
+                .map(|r| Arc::new(RangeProgress::new(r.offset, r.end)))
+                .collect::<Vec<Arc<RangeProgress>>>()
+        ));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let status = Arc::new(AtomicU8::new(DownloadStatus::Pending as u8));
+        let speed = Arc::new(std::sync::Mutex::new(SpeedMeter::new(state.downloaded())));
+        let (progress_tx, _) = watch::channel(Progress::new(*filesize));
+        let progress_tx = Arc::new(progress_tx);
+        let control = Arc::new(DownloadControl::default());
+        let state = Arc::new(Mutex::new(state));
+        let retry = Arc::new(options.retry);
         
-        Ok(Arc::new(Self{
+        Ok(Self{
             url,
             savepath,
+            partpath,
             filesize,
             support_range,
             chunk_count,
-            rw_lock,
-        }))
+            downloaded,
+            range_progress,
+            connections,
+            status,
+            speed,
+            progress_tx,
+            control,
+            state,
+            retry,
+            checksum,
+            limiter,
+            global_limiter,
+            client,
+            api_client: http.api_client(),
+            read_timeout,
+            probe_body,
+            renamed: Arc::new(std::sync::Mutex::new(None)),
+            skipped,
+            storage,
+            writer: Arc::new(std::sync::Mutex::new(None)),
+            segments,
+            tracks: Arc::new(vec![]),
+            remux_ts: options.remux_ts,
+            collision: options.collision,
+            download_id: Arc::new(options.download_id),
+            post_processor: Arc::new(options.post_process),
+            step: Arc::new(std::sync::Mutex::new(None)),
+            post_process_error: Arc::new(std::sync::Mutex::new(None)),
+        })
     }
 
-    pub fn total_size(&self) -> u64 {
+    /// DASH的每个轨道单独下载到"<文件名>.video.mp4"等文件, 本下载只汇总进度, 完成后再合并
+    async fn add_tracks(&mut self, representations: Vec<Representation>, options: DownloadOptions, http: &HttpClient) -> Result<()> {
+        let client = http.client();
+        let mut tracks = vec![];
+        let mut total_size = Some(0);
+        for representation in representations {
+            let path = Path::new(self.savepath.as_str())
+                .with_extension(format!("{}.{}", representation.kind.name(), representation.extension()))
+                .to_string_lossy()
+                .to_string();
+            // 轨道的文件名由本下载的文件名决定, 不会与其他下载冲突
+            let options = DownloadOptions {
+                checksum: None,
+                collision: CollisionPolicy::Overwrite,
+                download_id: None,
+                post_process: None,
+                ..options.clone()
+            };
+            let track = match representation.source {
+                TrackSource::File(url) => {
+                    let probe = Probe::run(&client, url.as_str()).await?;
+                    Self::build(url.to_string(), path, probe, None, options, http).await?
+                },
+                TrackSource::Segments(segments) => {
+                    let url = segments.first().map_or_else(|| self.url.to_string(), |s| s.url.to_string());
+                    let probe = Probe::unknown(Url::parse(&url)?);
+                    Self::build(url, path, probe, Some(SegmentStream::new(segments)), options, http).await?
+                },
+            };
+            total_size = match (total_size, track.total_size()) {
+                (Some(total), Some(size)) => Some(total + size),
+                _ => None,
+            };
+            tracks.push(Arc::new(track));
+        }
+        self.filesize = Arc::new(total_size);
+        self.tracks = Arc::new(tracks);
+        self.progress_tx.send_replace(Progress::new(total_size));
+        Ok(())
+    }
+
+    /// 服务器没有返回文件大小时为None
+    pub fn total_size(&self) -> Option<u64> {
         *self.filesize
     }
 
+    /// 最多使用的连接数, 实际连接数根据吞吐量自动调整
     pub fn chunk_count(&self) -> u64 {
         *self.chunk_count as u64
     }
 
-    pub async fn downloaded_size(&self) -> u64 {
-        *self.rw_lock.read().await
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Acquire)
+            + self.tracks.iter().map(|track| track.connections()).sum::<usize>()
+    }
+
+    pub fn downloaded_size(&self) -> u64 {
+        if !self.tracks.is_empty() {
+            return self.tracks.iter().map(|track| track.downloaded_size()).sum();
+        }
+        self.downloaded.load(Ordering::Acquire)
+    }
+
+    /// 订阅下载进度, 下载过程中每隔PROGRESS_INTERVAL推送一次, 结束时推送最终状态
+    pub fn subscribe(&self) -> watch::Receiver<Progress> {
+        self.progress_tx.subscribe()
     }
     
     pub fn get_save_path(&self) -> String {
-        self.savepath.to_string()
+        match self.renamed.lock().unwrap().as_ref() {
+            Some(renamed) => renamed.clone(),
+            None => self.savepath.to_string(),
+        }
     }
 
     fn is_support_range(&self) -> bool {
-        *self.support_range
+        self.support_range.load(Ordering::Acquire)
     }
 
+    /// 暂停下载, 已下载的数据和断点续传状态会保留, download()会一直等待到resume或cancel
+    pub fn pause(&self) {
+        self.control.pause();
+        for track in self.tracks.iter() {
+            track.pause();
+        }
+    }
+
+    pub fn resume(&self) {
+        for track in self.tracks.iter() {
+            track.resume();
+        }
+        self.control.resume();
+    }
+
+    /// 取消下载, 同时删除临时文件与状态文件
+    pub fn cancel(&self) {
+        self.control.cancel();
+        for track in self.tracks.iter() {
+            track.cancel();
+        }
+    }
+
+    pub fn status(&self) -> DownloadStatus {
+        DownloadStatus::from_u8(self.status.load(Ordering::Acquire))
+    }
+
+    /// 修改单个下载的限速, 正在下载的worker会立即按新的速率取令牌
+    pub fn set_rate_limit(&self, rate: u64) {
+        self.limiter.set_rate(rate);
+        for track in self.tracks.iter() {
+            track.set_rate_limit(rate);
+        }
+    }
+
+    /// 收到的数据需要同时从全局与单个下载的令牌桶中取得令牌
+    async fn throttle(&self, len: u64) {
+        if let Some(global_limiter) = self.global_limiter.as_ref() {
+            global_limiter.acquire(len).await;
+        }
+        self.limiter.acquire(len).await;
+    }
+
+    /// 等待请求返回或下一块数据, 超过read_timeout没有响应时按网络错误处理并重试
+    async fn receive<T>(&self, token: &CancellationToken, future: impl Future<Output = reqwest::Result<T>>) -> Result<T> {
+        let result = match self.read_timeout {
+            Some(read_timeout) => interruptible(token, timeout(read_timeout, future))
+                .await?
+                .map_err(|_| DownloadError::ReadTimeout)?,
+            None => interruptible(token, future).await?,
+        };
+        Ok(result?)
+    }
+
+    fn set_status(&self, status: DownloadStatus) {
+        self.status.store(status as u8, Ordering::Release);
+    }
+
+    fn range(&self, index: usize) -> Arc<RangeProgress> {
+        self.range_progress.read().unwrap()[index].clone()
+    }
+
+    /// 分片的实时进度只保存在原子计数器中, 读写状态文件前先同步过去
+    fn sync_offsets(&self, state: &mut DownloadState) {
+        let range_progress = self.range_progress.read().unwrap();
+        for (range, progress) in state.ranges.iter_mut().zip(range_progress.iter()) {
+            range.offset = progress.offset();
+        }
+    }
+
+    /// 记录已写入文件的字节, 累计超过STATE_FLUSH_BYTES时保存状态文件
+    /// 分片的后半部分可能刚被拆走, 超出新结尾的数据由拆走的分片计数, 这里加锁避免与拆分交错
+    async fn commit(&self, progress: &RangeProgress, len: u64, unsaved: &mut u64) {
+        if len == 0 {
+            return;
+        }
+        let len = {
+            let _state = self.state.lock().await;
+            let len = std::cmp::min(len, progress.remaining());
+            progress.add(len);
+            len
+        };
+        self.downloaded.fetch_add(len, Ordering::AcqRel);
+        *unsaved += len;
+        if *unsaved >= STATE_FLUSH_BYTES {
+            self.save_state().await;
+            *unsaved = 0;
+        }
+    }
+
+    /// 把index分片剩余部分的后一半拆成新分片, 返回新分片的序号
+    async fn split_range(&self, index: usize) -> Option<usize> {
+        let mut state = self.state.lock().await;
+        self.sync_offsets(&mut state);
+        let new_index = state.split(index, MIN_SPLIT_SIZE)?;
+        self.range(index).set_end(state.ranges[index].end);
+        let range = &state.ranges[new_index];
+        self.range_progress.write().unwrap().push(Arc::new(RangeProgress::new(range.start, range.end)));
+        Some(new_index)
+    }
+
+    /// 队列为空时从剩余数据最多的下载中分片拆走后一半, 让空闲的worker分担慢连接的数据
+    async fn steal(&self) -> Option<usize> {
+        let index = {
+            let range_progress = self.range_progress.read().unwrap();
+            range_progress.iter()
+                .enumerate()
+                .filter(|(_, p)| p.status() == ChunkStatus::Downloading)
+                .max_by_key(|(_, p)| p.remaining())
+                .map(|(i, _)| i)
+        }?;
+        let new_index = self.split_range(index).await?;
+        self.save_state().await;
+        Some(new_index)
+    }
+
+    async fn save_state(&self) {
+        if !self.storage.is_persistent() {
+            return;
+        }
+        let mut state = self.state.lock().await;
+        self.sync_offsets(&mut state);
+        if let Err(e) = state.save(self.savepath.as_str()).await {
+            error!("Failed to save download state, error: {:?}", e);
+        }
+    }
 
-    async fn plain_download(&self) -> Result<bool> {
-        let client = Client::builder()
-            .user_agent(USER_AGNET)
-            .build()?;
 
-        let mut source = client.get(self.url.as_str()).send().await?;
+    /// 不支持range时只能从头重新下载, 失败后按重试策略整体重试
+    /// 数据按顺序写入, 需要校验时同时计算摘要
+    async fn plain_download(&self, writer: &FileWriter) -> Result<Option<StreamHasher>> {
+        let token = self.control.token();
+        let mut attempt = 0;
+        loop {
+            self.downloaded.store(0, Ordering::Release);
+            match self.plain_download_once(writer, &token).await {
+                Ok(res) => return Ok(res),
+                Err(e) if token.is_cancelled() || is_disk_full(&e) || attempt >= self.retry.max_retries => return Err(e),
+                Err(e) => {
+                    error!("Failed to download file, error: {:?}", e);
+                    interruptible(&token, sleep(self.retry.backoff(attempt))).await?;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn plain_download_once(&self, writer: &FileWriter, token: &CancellationToken) -> Result<Option<StreamHasher>> {
+        writer.set_len(0).await.map_err(space::write_error)?;
+        let probe_body = self.probe_body.lock().unwrap().take();
+        let mut source = match probe_body {
+            Some(response) => response,
+            None => self.receive(token, self.client.get(self.url.as_str()).send())
+                .await?
+                .error_for_status()?,
+        };
      
-        let mut offset = 0;
-        
-        while let Some(bytes) = source.chunk().await? {
-            let mut size = self.rw_lock.write().await;
-            let len = bytes.len() as u64;
-            match write_bytes_to_file(self.savepath.as_str(), &bytes, offset).await {
-                Ok(_) => {},
-                Err(e) => { error!("Failed to write to file,  error: {:?}", e)},
+        let mut buffer = BufferedWriter::new(writer.clone(), 0);
+        let mut hasher = self.checksum.as_ref().as_ref().map(StreamHasher::new);
+
+        while let Some(bytes) = self.receive(token, source.chunk()).await? {
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&bytes);
+            }
+            let len = buffer.push(&bytes).await.map_err(space::write_error)?;
+            self.downloaded.fetch_add(len, Ordering::AcqRel);
+            interruptible(token, self.throttle(bytes.len() as u64)).await?;
+        }
+        let len = buffer.flush().await.map_err(space::write_error)?;
+        self.downloaded.fetch_add(len, Ordering::AcqRel);
+        Ok(hasher)
+    }
+
+    async fn chunk_download(self: Arc<Self>, index: usize, writer: FileWriter) -> Result<bool> {
+        let token = self.control.token();
+        let progress = self.range(index);
+        let (range, validator) = {
+            let mut state = self.state.lock().await;
+            self.sync_offsets(&mut state);
+            (state.ranges[index].clone(), state.validator())
         };
-            offset += len;
-            *size += len;
+        if range.is_complete() {
+            return Ok(true);
+        }
+        progress.set_status(ChunkStatus::Downloading);
+
+        let mut request = self.client.get(self.url.as_str())
+                                        .header("Range", format!("bytes={}-{}", range.offset, range.end));
+        if let Some(validator) = validator.as_ref() {
+            request = request.header("If-Range", validator);
+        }
+        let mut response = self.receive(&token, request.send()).await?.error_for_status()?;
+
+        // 服务器忽略Range时返回完整文件, 带If-Range时也可能是远程文件已变更, 通过响应中的校验值区分
+        if response.status() == StatusCode::OK {
+            let unchanged = validator.as_ref().map_or(true, |v| {
+                header_value(response.headers(), "ETag").as_ref() == Some(v)
+                    || header_value(response.headers(), "Last-Modified").as_ref() == Some(v)
+            });
+            return Err(if unchanged { DownloadError::RangeNotSupported } else { DownloadError::RemoteChanged }.into());
+        }
+
+        let content_range = header_value(response.headers(), "Content-Range").unwrap_or_default();
+        match verify::parse_content_range(&content_range) {
+            Some((start, end, total))
+                if start == range.offset
+                && end == range.end
+                && total.map_or(true, |t| Some(t) == self.total_size()) => {},
+            _ => return Err(DownloadError::ContentRangeMismatch(content_range).into()),
+        }
+
+        let mut buffer = BufferedWriter::new(writer, range.offset);
+        let mut unsaved = 0;
+        let mut received = range.offset;
+
+        let mut result: Result<()> = Ok(());
+        loop {
+            match self.receive(&token, response.chunk()).await {
+                Ok(Some(bytes)) => {
+                    // 后半部分被拆走后只保留新结尾之前的数据
+                    let len = std::cmp::min(bytes.len() as u64, (progress.end() + 1).saturating_sub(received));
+                    received += len;
+                    match buffer.push(&bytes[..len as usize]).await {
+                        Ok(len) => self.commit(&progress, len, &mut unsaved).await,
+                        Err(e) => {
+                            result = Err(space::write_error(e));
+                            break;
+                        }
+                    }
+                    if let Err(e) = interruptible(&token, self.throttle(bytes.len() as u64)).await {
+                        result = Err(e);
+                        break;
+                    }
+                    if received > progress.end() {
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            }
+        }
+        // 网络中断时也要把已收到的数据写入文件, 重试时从这里继续
+        let flushed = buffer.flush().await;
+        if let Ok(len) = flushed {
+            self.commit(&progress, len, &mut unsaved).await;
+        }
+        self.save_state().await;
+        result?;
+        flushed.map_err(space::write_error)?;
+
+        // 连接被正常关闭但数据没有传完
+        if progress.offset() <= progress.end() {
+            return Err(DownloadError::IncompleteRange.into());
         }
         Ok(true)
     }
 
-    async fn chunk_download(self: Arc<Self>, range: (u64, u64)) -> Result<bool> {
-        let client = Client::builder()
-            .user_agent(USER_AGNET)
-            .build()?;
-        let mut response = client.get(self.url.as_str())
-                                        .header("Range", format!("bytes={}-{}", range.0, range.1))
-                                        .send()
-                                        .await?;
-        let mut offset = range.0;
-
-        while let Some(bytes) = response.chunk().await? {
-            let mut size = self.rw_lock.write().await;
-            let len = bytes.len() as u64;
-           
-            match write_bytes_to_file(self.savepath.as_str(), &bytes, offset).await {
-                    Ok(_) => {},
-                    Err(e) => { error!("Failed to write to file, error:{:?}", e)},
+    /// 下载单个分片, 失败后从已写入的位置重试, 每次重试前把剩余部分拆出一半交给其他worker
+    /// 返回false表示重试次数用尽
+    async fn range_download(self: Arc<Self>, index: usize, queue: Arc<Mutex<VecDeque<usize>>>, writer: FileWriter) -> Result<bool> {
+        let token = self.control.token();
+        let progress = self.range(index);
+        let mut attempt = 0;
+        loop {
+            let before = progress.offset();
+            let e = match self.clone().chunk_download(index, writer.clone()).await {
+                Ok(_) => {
+                    progress.set_status(ChunkStatus::Completed);
+                    return Ok(true);
+                },
+                Err(e) if is_remote_changed(&e) || is_range_not_supported(&e) => return Err(e),
+                // 磁盘已满时重试没有意义, 同时中止其他worker, 已写入的数据与状态文件保留用于续传
+                Err(e) if is_disk_full(&e) => {
+                    progress.set_status(ChunkStatus::Failed);
+                    self.control.abort();
+                    return Err(e);
+                },
+                Err(_) if token.is_cancelled() => {
+                    progress.set_status(ChunkStatus::Pending);
+                    return Err(DownloadError::Interrupted.into());
+                },
+                Err(e) => e,
             };
-            offset += len;
-            *size += len;
+            error!("Failed to download range {}, error: {:?}", index, e);
+
+            // 本次有数据写入说明连接是可用的, 重新计算重试次数
+            if progress.offset() > before {
+                attempt = 0;
+            }
+            if attempt >= self.retry.max_retries {
+                progress.set_status(ChunkStatus::Failed);
+                return Ok(false);
+            }
+            progress.set_status(ChunkStatus::Retrying);
+
+            if let Some(new_index) = self.split_range(index).await {
+                queue.lock().await.push_back(new_index);
+                self.save_state().await;
+            }
+            interruptible(&token, sleep(self.retry.backoff(attempt))).await?;
+            attempt += 1;
         }
-        Ok(true)
+    }
+
+    /// 从队列中领取分片下载, 队列为空时从其他worker的分片中拆走一半
+    /// 直到没有可领取的分片且没有其他worker可能再拆分出新分片
+    async fn range_worker(self: Arc<Self>, queue: Arc<Mutex<VecDeque<usize>>>, active: Arc<AtomicUsize>, writer: FileWriter) -> Result<Vec<usize>> {
+        let token = self.control.token();
+        let mut failed = vec![];
+        loop {
+            if token.is_cancelled() {
+                return Err(DownloadError::Interrupted.into());
+            }
+            let index = {
+                let mut queue = queue.lock().await;
+                queue.pop_front().map(|index| {
+                    active.fetch_add(1, Ordering::SeqCst);
+                    index
+                })
+            };
+            let index = match index {
+                Some(index) => index,
+                None => match self.steal().await {
+                    Some(index) => {
+                        active.fetch_add(1, Ordering::SeqCst);
+                        index
+                    },
+                    None if active.load(Ordering::SeqCst) == 0 => break,
+                    None => {
+                        sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                }
+            };
+            let result = self.clone().range_download(index, queue.clone(), writer.clone()).await;
+            active.fetch_sub(1, Ordering::SeqCst);
+            if !result? {
+                failed.push(index);
+            }
+        }
+        Ok(failed)
+    }
+
+    /// 启动一个worker, 退出时通过exited通知download_ranges
+    fn spawn_worker(self: &Arc<Self>, queue: &Arc<Mutex<VecDeque<usize>>>, active: &Arc<AtomicUsize>, writer: &FileWriter, exited: &Arc<Notify>) -> JoinHandle<Result<Vec<usize>>> {
+        let s = self.clone();
+        let queue = queue.clone();
+        let active = active.clone();
+        let writer = writer.clone();
+        let exited = exited.clone();
+        self.connections.fetch_add(1, Ordering::AcqRel);
+        tokio::spawn(async move {
+            let result = s.clone().range_worker(queue, active, writer).await;
+            s.connections.fetch_sub(1, Ordering::AcqRel);
+            exited.notify_one();
+            result
+        })
+    }
+
+    /// 等待一个采样间隔, 暂停或所有worker都已退出时提前返回false
+    async fn wait_tune(&self, token: &CancellationToken, exited: &Notify) -> bool {
+        let tick = sleep(TUNE_INTERVAL);
+        tokio::pin!(tick);
+        loop {
+            tokio::select! {
+                _ = &mut tick => return true,
+                _ = token.cancelled() => return false,
+                _ = exited.notified() => if self.connections() == 0 {
+                    return false;
+                },
+            }
+        }
+    }
+
+    /// 先用INITIAL_CONNECTIONS个worker下载, 每隔TUNE_INTERVAL比较一次吞吐量,
+    /// 新增的连接能明显提升速度时继续增加, 直到chunk_count个, 返回重试后仍失败的区间
+    async fn download_ranges(self: Arc<Self>, writer: &FileWriter) -> Result<Vec<(u64, u64)>> {
+        let token = self.control.token();
+        let queue = {
+            let mut state = self.state.lock().await;
+            self.sync_offsets(&mut state);
+            Arc::new(Mutex::new(
+                (0..state.ranges.len())
+                    .filter(|i| !state.ranges[*i].is_complete())
+                    .collect::<VecDeque<usize>>()
+            ))
+        };
+        let active = Arc::new(AtomicUsize::new(0));
+        let exited = Arc::new(Notify::new());
+        let max_connections = std::cmp::max(self.chunk_count(), 1);
+        let initial = std::cmp::min(INITIAL_CONNECTIONS, max_connections);
+        let mut handler_list = (0..initial)
+            .map(|_| self.spawn_worker(&queue, &active, writer, &exited))
+            .collect::<Vec<JoinHandle<Result<Vec<usize>>>>>();
+
+        let mut last_downloaded = self.downloaded_size();
+        let mut last_speed = 0.0;
+        while (handler_list.len() as u64) < max_connections {
+            if !self.wait_tune(&token, &exited).await {
+                break;
+            }
+            let downloaded = self.downloaded_size();
+            let speed = downloaded.saturating_sub(last_downloaded) as f64 / TUNE_INTERVAL.as_secs_f64();
+            last_downloaded = downloaded;
+            // 新增连接后速度没有明显提升, 说明已达到带宽或服务器的上限
+            if handler_list.len() as u64 > initial && speed < last_speed * (1.0 + TUNE_MIN_GAIN) {
+                break;
+            }
+            last_speed = speed;
+            handler_list.push(self.spawn_worker(&queue, &active, writer, &exited));
+        }
+
+        let mut failed = vec![];
+        let mut error: Option<anyhow::Error> = None;
+        for result in join_all(handler_list).await {
+            match result? {
+                Ok(indexes) => failed.extend(indexes),
+                // 一个worker中止下载后其他worker返回Interrupted, 优先返回真正的原因
+                Err(e) => if error.as_ref().map_or(true, is_interrupted) {
+                    error = Some(e);
+                },
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        let mut state = self.state.lock().await;
+        self.sync_offsets(&mut state);
+        Ok(failed.into_iter()
+            .map(|i| (state.ranges[i].offset, state.ranges[i].end))
+            .collect())
+    }
+
+    /// 下载完成后校验文件大小与摘要, 校验失败时丢弃断点续传状态
+    /// 临时文件是预分配的, 所以同时要求写入的字节数与文件大小一致
+    async fn verify(&self, writer: &FileWriter, hasher: Option<StreamHasher>) -> Result<()> {
+        let downloaded = self.downloaded_size();
+        let mut result = match self.total_size() {
+            Some(total) if downloaded != total => Err(DownloadError::SizeMismatch { expected: total, actual: downloaded }.into()),
+            Some(total) => verify::verify_size(writer, total).await,
+            // 大小未知时以实际收到的字节数为准
+            None => verify::verify_size(writer, downloaded).await,
+        };
+        if result.is_ok() {
+            if let Some(checksum) = self.checksum.as_ref() {
+                result = verify::verify_checksum(writer, checksum, hasher).await;
+            }
+        }
+        if result.is_err() {
+            DownloadState::remove(self.savepath.as_str()).await;
+        }
+        result
+    }
+
+    /// 校验通过后将临时文件保存为目标文件, CDN常返回application/octet-stream, 这里再按文件头修正扩展名
+    /// hasher为顺序写入时算好的摘要, 没有时从文件中读取计算
+    async fn finish(&self, writer: FileWriter, hasher: Option<StreamHasher>) -> Result<()> {
+        self.set_status(DownloadStatus::Verifying);
+        // 大文件的校验需要一段时间, 先通知订阅者
+        self.report().await;
+        writer.sync().await.map_err(space::write_error)?;
+        self.verify(&writer, hasher).await?;
+        let head = writer.read_at(filename::SNIFF_SIZE, 0).await?;
+        // 修正后的文件名与下载开始时一样按冲突策略占用, 按策略跳过或无法占用时保留原来的文件名
+        let renamed = filename::correct_extension(&head, self.savepath.as_str())
+            .and_then(|renamed| match filename::claim_save_path(&renamed, self.collision, self.download_id.as_deref()) {
+                Ok((renamed, false)) => Some(renamed),
+                Ok((_, true)) => None,
+                Err(e) => {
+                    error!("Failed to claim {}, error: {:?}", renamed, e);
+                    None
+                },
+            });
+        let target = renamed.clone().unwrap_or_else(|| self.savepath.to_string());
+        self.writer.lock().unwrap().take();
+        let result = writer.persist(self.partpath.as_str(), &target).await;
+        if renamed.is_some() {
+            let _ = tokio::fs::remove_file(format!("{}{}", target, PART_FILE_SUFFIX)).await;
+        }
+        result.map_err(space::write_error)?;
+        *self.renamed.lock().unwrap() = renamed;
+        DownloadState::remove(self.savepath.as_str()).await;
+        if self.remux_ts && crate::mux::is_mpeg_ts(&head) {
+            self.remux(target).await;
+        }
+        Ok(())
+    }
+
+    /// 转封装为同名的MP4, 失败或按冲突策略跳过时保留原来的TS文件
+    async fn remux(&self, path: String) {
+        let output = Path::new(&path).with_extension("mp4").to_string_lossy().to_string();
+        // 与下载一样先占用文件名, 不会覆盖已有文件, 也不会与同时进行的下载选中同一个文件
+        let output = match filename::claim_save_path(&output, self.collision, self.download_id.as_deref()) {
+            Ok((_, true)) => return,
+            Ok((output, false)) => output,
+            Err(e) => {
+                error!("Failed to claim {}, error: {:?}", output, e);
+                return;
+            },
+        };
+        self.set_status(DownloadStatus::Remuxing);
+        self.report().await;
+        match crate::mux::remux_ts_file(Path::new(&path), Path::new(&output)).await {
+            Ok(_) => {
+                let _ = tokio::fs::remove_file(&path).await;
+                *self.renamed.lock().unwrap() = Some(output.clone());
+            },
+            Err(e) => error!("Failed to remux {}, error: {:?}", path, e),
+        }
+        let _ = tokio::fs::remove_file(format!("{}{}", output, PART_FILE_SUFFIX)).await;
+    }
+
+    /// 生成当前进度快照
+    async fn snapshot(&self) -> Progress {
+        let downloaded = self.downloaded_size();
+        let (speed, average_speed) = self.speed.lock().unwrap().sample(downloaded);
+        let rate = if speed > 0 { speed } else { average_speed };
+        let eta = match (self.total_size(), rate) {
+            (_, 0) | (None, _) => None,
+            (Some(total), rate) => Some(total.saturating_sub(downloaded) / rate),
+        };
+        let segments = match self.segments.as_ref() {
+            Some(segments) => segments.states.iter()
+                .enumerate()
+                .map(|(index, state)| SegmentProgress {
+                    index,
+                    downloaded: state.downloaded(),
+                    status: state.status(),
+                })
+                .collect(),
+            None => vec![],
+        };
+        let chunks = if self.is_support_range() {
+            let state = self.state.lock().await;
+            let range_progress = self.range_progress.read().unwrap();
+            state.ranges.iter()
+                .zip(range_progress.iter())
+                .map(|(range, progress)| ChunkProgress {
+                    start: range.start,
+                    end: range.end,
+                    downloaded: progress.offset().saturating_sub(range.start),
+                    status: progress.status(),
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        Progress {
+            status: self.status(),
+            downloaded,
+            total_size: self.total_size(),
+            chunks,
+            segments,
+            tracks: vec![],
+            step: self.step.lock().unwrap().clone(),
+            post_process_error: self.post_process_error.lock().unwrap().clone(),
+            connections: self.connections(),
+            speed,
+            average_speed,
+            eta,
+        }
+    }
+
+    /// 推送当前进度给所有订阅者, DASH下载同时附上每个轨道的进度
+    async fn report(&self) {
+        let mut progress = self.snapshot().await;
+        for track in self.tracks.iter() {
+            progress.tracks.push(track.snapshot().await);
+        }
+        self.progress_tx.send_replace(progress);
+    }
+
+    fn spawn_reporter(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                self.report().await;
+                sleep(PROGRESS_INTERVAL).await;
+            }
+        })
     }
 
     pub async fn download(self: Arc<Self>) -> Result<bool> {
+        if self.skipped {
+            self.set_status(DownloadStatus::Completed);
+            self.report().await;
+            return Ok(true);
+        }
+        let mut result = loop {
+            self.set_status(DownloadStatus::Downloading);
+            *self.speed.lock().unwrap() = SpeedMeter::new(self.downloaded_size());
+            let pauses = self.control.pauses();
+            let reporter = self.clone().spawn_reporter();
+            let result = self.clone().run().await;
+            reporter.abort();
 
-        if self.total_size() < 1 {
-            return Ok(false);
+            // 校验, 转封装时收到的暂停或取消不影响已经保存的文件
+            if result.is_ok() {
+                break result;
+            }
+            if self.control.is_cancelled() {
+                break Err(DownloadError::Cancelled.into());
+            }
+            // 本次运行中没有暂停过, 是真正的错误
+            if self.control.pauses() == pauses {
+                break result;
+            }
+
+            // 暂停时保留临时文件与状态文件, 等待继续下载
+            self.save_state().await;
+            for track in self.tracks.iter() {
+                track.save_state().await;
+            }
+            // 运行结束前已经继续时直接重新开始
+            if !self.control.is_paused() {
+                continue;
+            }
+            self.set_status(DownloadStatus::Paused);
+            self.report().await;
+            self.control.wait_resume().await;
+            if self.control.is_cancelled() {
+                break Err(DownloadError::Cancelled.into());
+            }
+        };
+
+        if result.is_err() && self.control.is_cancelled() {
+            self.discard().await;
+            for track in self.tracks.iter() {
+                track.discard().await;
+            }
+        }
+        // 后处理中取消时已保存的文件保留, 下载仍按取消处理
+        if matches!(result, Ok(true)) && !self.clone().post_process().await {
+            result = Err(DownloadError::Cancelled.into());
+        }
+        self.set_status(match result {
+            Ok(true) if self.post_process_error.lock().unwrap().is_some() => DownloadStatus::PostProcessFailed,
+            Ok(true) => DownloadStatus::Completed,
+            Err(_) if self.control.is_cancelled() => DownloadStatus::Cancelled,
+            _ => DownloadStatus::Failed,
+        });
+        self.report().await;
+        result
+    }
+
+    /// 依次执行后处理步骤, 不响应暂停, 取消或失败时停止并保留已完成的文件
+    /// 失败的原因与步骤保留在进度中, 最终状态为PostProcessFailed, 被取消时不记录错误并返回false
+    async fn post_process(self: Arc<Self>) -> bool {
+        let processor = match self.post_processor.as_ref() {
+            Some(processor) => processor,
+            None => return true,
+        };
+        self.set_status(DownloadStatus::PostProcessing);
+        let reporter = self.clone().spawn_reporter();
+        let mut path = self.get_save_path();
+        let step = self.step.clone();
+        let download_id = self.download_id.as_deref();
+        let result = processor.run(&mut path, self.collision, download_id, &self.control.cancel_token(), move |progress| {
+            *step.lock().unwrap() = Some(progress);
+        }).await;
+        reporter.abort();
+
+        let cancelled = result.is_err() && self.control.is_cancelled();
+        match result {
+            Ok(_) => {
+                self.step.lock().unwrap().take();
+            },
+            Err(_) if cancelled => {
+                self.step.lock().unwrap().take();
+            },
+            Err(e) => {
+                error!("Failed to post-process {}, error: {:?}", path, e);
+                *self.post_process_error.lock().unwrap() = Some(e.to_string());
+            },
+        }
+        if path != self.get_save_path() {
+            *self.renamed.lock().unwrap() = Some(path);
+        }
+        !cancelled
+    }
+
+    /// 取消后删除临时文件与状态文件
+    async fn discard(&self) {
+        self.writer.lock().unwrap().take();
+        let _ = tokio::fs::remove_file(self.partpath.as_str()).await;
+        DownloadState::remove(self.savepath.as_str()).await;
+    }
+
+    /// 丢弃所有分片的进度与状态文件
+    async fn reset_ranges(&self) {
+        {
+            let mut state = self.state.lock().await;
+            state.reset();
+            let range_progress = self.range_progress.read().unwrap();
+            for (range, progress) in state.ranges.iter().zip(range_progress.iter()) {
+                progress.set_offset(range.start);
+                progress.set_status(ChunkStatus::Pending);
+            }
+        }
+        self.downloaded.store(0, Ordering::Release);
+        DownloadState::remove(self.savepath.as_str()).await;
+    }
+
+    /// 取得本次下载的存储, 全新下载时重新创建临时文件, 避免残留的旧数据
+    fn writer(&self, size: u64) -> Result<FileWriter> {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(writer) = writer.as_ref() {
+            return Ok(writer.clone());
         }
-        if !self.is_support_range() {
-            return self.plain_download().await;
+        let created = if self.downloaded_size() == 0 {
+            FileWriter::create(self.storage, self.partpath.as_str(), size)?
+        } else {
+            FileWriter::open(self.storage, self.partpath.as_str())?
+        };
+        *writer = Some(created.clone());
+        Ok(created)
+    }
+
+    /// 不支持range或大小未知时单连接从头下载
+    async fn run_plain(&self) -> Result<bool> {
+        let writer = self.writer(0)?;
+        let hasher = self.plain_download(&writer).await?;
+        self.finish(writer, hasher).await?;
+        Ok(true)
+    }
+
+    /// 下载单个分段并解密, 失败后按重试策略从头重新下载这个分段
+    async fn segment_download(self: Arc<Self>, segments: Arc<SegmentStream>, index: usize, token: CancellationToken) -> Result<Vec<u8>> {
+        let state = &segments.states[index];
+        let mut attempt = 0;
+        loop {
+            state.set_status(if attempt == 0 { ChunkStatus::Downloading } else { ChunkStatus::Retrying });
+            let e = match self.segment_download_once(&segments, index, &token).await {
+                Ok(data) => {
+                    state.set_status(ChunkStatus::Completed);
+                    return Ok(data);
+                },
+                Err(_) if token.is_cancelled() => {
+                    state.set_status(ChunkStatus::Pending);
+                    return Err(DownloadError::Interrupted.into());
+                },
+                Err(e) => e,
+            };
+            error!("Failed to download segment {}, error: {:?}", index, e);
+            self.downloaded.fetch_sub(state.reset(), Ordering::AcqRel);
+            if attempt >= self.retry.max_retries {
+                state.set_status(ChunkStatus::Failed);
+                return Err(e);
+            }
+            interruptible(&token, sleep(self.retry.backoff(attempt))).await?;
+            attempt += 1;
         }
+    }
 
-        let chunk_size = self.total_size() / self.chunk_count() as u64;
-        let mut range_list = vec![];
-        let mut start = 0;
-        let mut end = 0;
+    async fn segment_download_once(&self, segments: &SegmentStream, index: usize, token: &CancellationToken) -> Result<Vec<u8>> {
+        let segment = &segments.segments[index];
+        let state = &segments.states[index];
+        self.downloaded.fetch_sub(state.reset(), Ordering::AcqRel);
 
-        while end <= self.total_size() {
-            end += chunk_size;
-            range_list.push((start, end));
-            start = end + 1;
+        let mut request = self.client.get(segment.url.as_str());
+        if let Some((len, offset)) = segment.byte_range {
+            request = request.header("Range", format!("bytes={}-{}", offset, offset + len.saturating_sub(1)));
+        }
+        let mut response = self.receive(token, request.send()).await?.error_for_status()?;
+
+        let mut data = vec![];
+        while let Some(bytes) = self.receive(token, response.chunk()).await? {
+            data.extend_from_slice(&bytes);
+            state.add(bytes.len() as u64);
+            self.downloaded.fetch_add(bytes.len() as u64, Ordering::AcqRel);
+            interruptible(token, self.throttle(bytes.len() as u64)).await?;
+        }
+        segments.decrypt(&self.api_client, index, data).await
+    }
+
+    /// 同时下载最多chunk_count个分段, 按顺序追加写入文件, 暂停后从第一个未写入的分段继续
+    async fn run_segments(self: Arc<Self>, segments: Arc<SegmentStream>) -> Result<bool> {
+        let token = self.control.token();
+        let writer = self.writer(0)?;
+        let (start, mut offset) = segments.written();
+        // 丢弃上次暂停时没有写入的分段
+        for state in &segments.states[start..] {
+            state.reset();
+            state.set_status(ChunkStatus::Pending);
+        }
+        self.downloaded.store(offset, Ordering::Release);
+        // 从头写入时同时计算摘要, 暂停后继续的下载在校验时重新读取文件
+        let mut hasher = match start {
+            0 => self.checksum.as_ref().as_ref().map(StreamHasher::new),
+            _ => None,
+        };
+
+        let concurrency = std::cmp::max(self.chunk_count() as usize, 1);
+        self.connections.store(concurrency, Ordering::Release);
+        let mut downloads = stream::iter(start..segments.segments.len())
+            .map(|index| {
+                let s = self.clone();
+                let segments = segments.clone();
+                let token = token.clone();
+                async move { (index, s.segment_download(segments, index, token).await) }
+            })
+            .buffered(concurrency);
+
+        let mut result = Ok(());
+        while let Some((index, data)) = downloads.next().await {
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            let len = data.len() as u64;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&data);
+            }
+            if let Err(e) = writer.write_at(data, offset).await {
+                result = Err(space::write_error(e));
+                break;
+            }
+            offset += len;
+            segments.set_written(index + 1, offset);
         }
+        drop(downloads);
+        self.connections.store(0, Ordering::Release);
+        result?;
 
-        let mut handler_list = vec![];
+        // 解密时去掉了填充, 以实际写入的字节数为准
+        self.downloaded.store(offset, Ordering::Release);
+        self.finish(writer, hasher).await?;
+        Ok(true)
+    }
 
-        for range in range_list {
-            let s = self.clone();
-            let handler = tokio::spawn(async move {
-                let _ = s.chunk_download(range).await;
+    /// 按顺序下载每个轨道, 已完成的轨道在暂停后继续时不再下载
+    async fn run_tracks(self: Arc<Self>) -> Result<bool> {
+        for track in self.tracks.iter() {
+            if track.status() == DownloadStatus::Completed {
+                continue;
+            }
+            track.set_status(DownloadStatus::Downloading);
+            let result = track.clone().run_file().await;
+            track.set_status(match result {
+                Ok(true) => DownloadStatus::Completed,
+                _ if track.control.is_paused() => DownloadStatus::Paused,
+                _ => DownloadStatus::Failed,
             });
-            handler_list.push(handler);
+            if !result? {
+                return Ok(false);
+            }
         }
-        
-        join_all(handler_list).await;
+        self.merge_tracks().await?;
+        Ok(true)
+    }
+
+    /// 只有一个轨道时直接作为目标文件, 音视频轨道合并为一个MP4
+    /// 无法合并时(例如WebM)保留各自的文件, 以视频轨道作为保存路径
+    async fn merge_tracks(&self) -> Result<()> {
+        self.set_status(DownloadStatus::Verifying);
+        let paths = self.tracks.iter().map(|track| track.get_save_path()).collect::<Vec<String>>();
+        match paths.as_slice() {
+            [path] => self.rename_track(path).await?,
+            [video, audio] => {
+                self.set_status(DownloadStatus::Remuxing);
+                self.report().await;
+                match crate::mux::mux_files(Path::new(video), Path::new(audio), Path::new(self.savepath.as_str())).await {
+                    Ok(_) => {
+                        let _ = tokio::fs::remove_file(video).await;
+                        let _ = tokio::fs::remove_file(audio).await;
+                    },
+                    Err(e) => {
+                        error!("Failed to mux {} and {}, error: {:?}", video, audio, e);
+                        *self.renamed.lock().unwrap() = Some(video.clone());
+                    },
+                }
+            },
+            _ => *self.renamed.lock().unwrap() = paths.first().cloned(),
+        }
+        // 本下载只占用了目标文件名, 没有实际数据
+        let _ = tokio::fs::remove_file(self.partpath.as_str()).await;
+        DownloadState::remove(self.savepath.as_str()).await;
+        Ok(())
+    }
+
+    /// 单个轨道的容器不一定是MP4, 扩展名以轨道文件为准, 例如WebM或只有音频的M4A
+    async fn rename_track(&self, path: &str) -> Result<()> {
+        let target = match Path::new(path).extension() {
+            Some(extension) => Path::new(self.savepath.as_str()).with_extension(extension).to_string_lossy().to_string(),
+            None => self.savepath.to_string(),
+        };
+        if target == *self.savepath {
+            return tokio::fs::rename(path, target).await.map_err(space::write_error);
+        }
+        // 本下载只占用了MP4的文件名, 换了扩展名后需要重新按冲突策略占用
+        match filename::claim_save_path(&target, self.collision, self.download_id.as_deref())? {
+            // 按策略跳过已存在的同名文件时保留轨道文件
+            (_, true) => *self.renamed.lock().unwrap() = Some(path.to_string()),
+            (target, false) => {
+                let result = tokio::fs::rename(path, &target).await;
+                let _ = tokio::fs::remove_file(format!("{}{}", target, PART_FILE_SUFFIX)).await;
+                result.map_err(space::write_error)?;
+                *self.renamed.lock().unwrap() = Some(target);
+            },
+        }
+        Ok(())
+    }
+
+    async fn run(self: Arc<Self>) -> Result<bool> {
+        match self.tracks.is_empty() {
+            true => self.run_file().await,
+            false => self.run_tracks().await,
+        }
+    }
+
+    /// 下载单个文件或分段流, DASH的每个轨道也由这里下载
+    async fn run_file(self: Arc<Self>) -> Result<bool> {
+
+        if let Some(segments) = self.segments.clone() {
+            return self.run_segments(segments).await;
+        }
+
+        let total_size = match self.total_size() {
+            Some(0) => return Ok(false),
+            Some(total_size) if self.is_support_range() => total_size,
+            _ => return self.run_plain().await,
+        };
+
+        let writer = self.writer(total_size)?;
+
+        let failed = match self.clone().download_ranges(&writer).await {
+            Err(e) if is_range_not_supported(&e) => {
+                // 探测时支持range, 下载时却返回完整文件, 改为单连接下载
+                self.support_range.store(false, Ordering::Release);
+                self.reset_ranges().await;
+                return self.run_plain().await;
+            },
+            Err(e) if is_remote_changed(&e) => {
+                // 远程文件已变更, 大小, 校验值与分片都以探测时为准, 不能在本下载中继续
+                // 丢弃旧版本的数据, 临时文件与状态文件, 重新开始下载时会重新探测
+                self.reset_ranges().await;
+                self.discard().await;
+                return Err(e);
+            },
+            result => result?,
+        };
+
+        // 所有worker都已结束, 确保数据落盘后再保存状态
+        writer.sync().await.map_err(space::write_error)?;
+
+        if !failed.is_empty() {
+            self.save_state().await;
+            return Err(DownloadError::RangesFailed(failed).into());
+        }
+
+        // 分片乱序写入, 校验时读取整个文件计算摘要
+        self.finish(writer, None).await?;
 
         Ok(true)
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use md5::{Md5, Digest};
+    use super::*;
+    use super::test_server::{TestServer, temp_dir};
+
+    fn test_body(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pause_during_finish_completes() {
+        let body = test_body(8 * 1024 * 1024);
+        let md5 = Md5::digest(&body).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let server = TestServer::start(body.clone(), true).await;
+        let savepath = temp_dir("pause_during_finish").join("file.bin").to_string_lossy().to_string();
+
+        let options = DownloadOptions { checksum: Some(Checksum::Md5(md5)), ..Default::default() };
+        let downloader = Downloader::with_options(server.url("file.bin"), savepath.clone(), options).await.unwrap();
+
+        // 校验摘要期间暂停, 稍后再继续
+        let mut progress = downloader.subscribe();
+        let d = downloader.clone();
+        let watcher = tokio::spawn(async move {
+            while progress.changed().await.is_ok() {
+                if progress.borrow().status == DownloadStatus::Verifying {
+                    d.pause();
+                    sleep(Duration::from_millis(200)).await;
+                    d.resume();
+                    return true;
+                }
+            }
+            false
+        });
+
+        let result = timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap();
+        assert!(watcher.await.unwrap());
+        assert!(result.unwrap());
+        assert_eq!(downloader.status(), DownloadStatus::Completed);
+        assert_eq!(std::fs::read(&savepath).unwrap(), body);
+        assert!(!Path::new(&format!("{}{}", savepath, PART_FILE_SUFFIX)).exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn quick_pause_and_resume_completes() {
+        let body = test_body(4 * 1024 * 1024);
+        let server = TestServer::start(body.clone(), true).await;
+        let savepath = temp_dir("quick_pause").join("file.bin").to_string_lossy().to_string();
+
+        // 限速使暂停时下载仍在进行
+        let options = DownloadOptions { rate_limit: 4 * 1024 * 1024, ..Default::default() };
+        let downloader = Downloader::with_options(server.url("file.bin"), savepath.clone(), options).await.unwrap();
+        let mut progress = downloader.subscribe();
+        let d = downloader.clone();
+        let watcher = tokio::spawn(async move {
+            while progress.changed().await.is_ok() {
+                if progress.borrow().downloaded > 0 {
+                    // 运行还没结束就已经继续
+                    d.pause();
+                    d.resume();
+                    d.set_rate_limit(0);
+                    return true;
+                }
+            }
+            false
+        });
+
+        let result = timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap();
+        assert!(watcher.await.unwrap());
+        assert!(result.unwrap());
+        assert_eq!(downloader.status(), DownloadStatus::Completed);
+        assert_eq!(std::fs::read(&savepath).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn remote_change_discards_partial_download() {
+        let body = test_body(4 * 1024 * 1024);
+        let server = TestServer::start(body.clone(), true).await;
+        let savepath = temp_dir("remote_change").join("file.bin").to_string_lossy().to_string();
+        let downloader = Downloader::with_options(server.url("file.bin"), savepath.clone(), Default::default()).await.unwrap();
+
+        // 探测之后远程文件被替换, 带If-Range的请求收到完整的新文件
+        server.replace(test_body(2 * 1024 * 1024), "\"v2\"");
+        let result = timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap();
+
+        assert!(is_remote_changed(&result.unwrap_err()));
+        assert_eq!(downloader.status(), DownloadStatus::Failed);
+        assert!(!Path::new(&savepath).exists());
+        assert!(!Path::new(&format!("{}{}", savepath, PART_FILE_SUFFIX)).exists());
+        assert!(!DownloadState::path_for(&savepath).exists());
+
+        // 重新开始的下载按新文件探测, 仍然使用原来的文件名
+        let downloader = Downloader::with_options(server.url("file.bin"), savepath.clone(), Default::default()).await.unwrap();
+        assert!(timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap().unwrap());
+        assert_eq!(downloader.get_save_path(), savepath);
+        assert_eq!(std::fs::read(&savepath).unwrap().len(), 2 * 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn reuses_preflight_probe() {
+        let body = test_body(1024 * 1024);
+        let server = TestServer::start(body.clone(), true).await;
+        let savepath = temp_dir("preflight_probe").join("file.bin").to_string_lossy().to_string();
+
+        let probe = probe(&HttpClient::default().client(), &server.url("file.bin")).await.unwrap();
+        assert_eq!(probe.filesize, Some(body.len() as u64));
+
+        // 创建下载时不再探测
+        let requests = server.requests();
+        let options = DownloadOptions { probe: Some(probe), ..Default::default() };
+        let downloader = Downloader::with_options(server.url("file.bin"), savepath.clone(), options).await.unwrap();
+        assert_eq!(server.requests(), requests);
+
+        assert!(timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap().unwrap());
+        assert_eq!(std::fs::read(&savepath).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn verifies_plain_download_checksum() {
+        let body = test_body(3 * 1024 * 1024 + 123);
+        let md5 = Md5::digest(&body).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let server = TestServer::start(body.clone(), false).await;
+        let dir = temp_dir("plain_checksum");
+
+        // 不支持range时顺序下载, 摘要在下载的同时计算
+        let savepath = dir.join("file.bin").to_string_lossy().to_string();
+        let options = DownloadOptions { checksum: Some(Checksum::Md5(md5)), ..Default::default() };
+        let downloader = Downloader::with_options(server.url("file.bin"), savepath.clone(), options).await.unwrap();
+        assert!(timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap().unwrap());
+        assert_eq!(std::fs::read(&savepath).unwrap(), body);
+
+        let savepath = dir.join("mismatch.bin").to_string_lossy().to_string();
+        let options = DownloadOptions { checksum: Some(Checksum::Md5("0".repeat(32))), ..Default::default() };
+        let downloader = Downloader::with_options(server.url("file.bin"), savepath.clone(), options).await.unwrap();
+        let error = timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap().unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(DownloadError::ChecksumMismatch)));
+        assert!(!Path::new(&savepath).exists());
+    }
+
+    #[tokio::test]
+    async fn claims_corrected_extension() {
+        let body = [b"FLV\x01\x05\0\0\0\x09".to_vec(), test_body(64 * 1024)].concat();
+        let server = TestServer::start(body.clone(), true).await;
+        // (冲突策略, 已存在的文件, 保存的文件名)
+        let cases = [
+            (CollisionPolicy::AppendCounter, "video.flv", "video (1).flv"),
+            // 其他下载已占用修正后的文件名
+            (CollisionPolicy::AppendCounter, "video.flv.part", "video (1).flv"),
+            (CollisionPolicy::Skip, "video.flv", "video.mp4"),
+        ];
+        for (collision, existing, expected) in cases {
+            let dir = temp_dir("corrected_extension");
+            std::fs::write(dir.join(existing), b"existing").unwrap();
+            let savepath = dir.join("video.mp4").to_string_lossy().to_string();
+            let options = DownloadOptions { collision, ..Default::default() };
+            let downloader = Downloader::with_options(server.url("video"), savepath, options).await.unwrap();
+            assert!(timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap().unwrap());
+
+            assert_eq!(Path::new(&downloader.get_save_path()), dir.join(expected), "{:?} {}", collision, existing);
+            assert_eq!(std::fs::read(dir.join(expected)).unwrap(), body);
+            assert_eq!(std::fs::read(dir.join(existing)).unwrap(), b"existing");
+            assert!(!dir.join(format!("{}{}", expected, PART_FILE_SUFFIX)).exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cancel_during_post_process() {
+        use std::os::unix::fs::PermissionsExt;
+        let body = test_body(64 * 1024);
+        let server = TestServer::start(body.clone(), true).await;
+        let dir = temp_dir("cancel_post_process");
+        // 只有输入时输出媒体信息, 执行步骤时一直等待到被取消
+        let ffmpeg = dir.join("ffmpeg");
+        std::fs::write(&ffmpeg, "#!/bin/sh\ncase \"$*\" in *-progress*) exec sleep 30 ;; esac\necho '  Duration: 00:00:02.00, start: 0.000000' >&2\nexit 1\n").unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = PostProcessConfig {
+            ffmpeg_path: Some(ffmpeg.to_string_lossy().to_string()),
+            steps: vec![PostStep::Trim { start: Some(1.0), end: None }],
+        };
+
+        let savepath = dir.join("file.bin").to_string_lossy().to_string();
+        let options = DownloadOptions { post_process: PostProcessor::from_config(&config).unwrap(), ..Default::default() };
+        let downloader = Downloader::with_options(server.url("file.bin"), savepath.clone(), options).await.unwrap();
+        let mut progress = downloader.subscribe();
+        let d = downloader.clone();
+        tokio::spawn(async move {
+            while progress.changed().await.is_ok() {
+                if progress.borrow().step.is_some() {
+                    d.cancel();
+                    return;
+                }
+            }
+        });
+
+        let error = timeout(Duration::from_secs(10), downloader.clone().download()).await.unwrap().unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(DownloadError::Cancelled)));
+        assert_eq!(downloader.status(), DownloadStatus::Cancelled);
+        let progress = downloader.snapshot().await;
+        assert!(progress.post_process_error.is_none());
+        // 下载完成的文件保留
+        assert_eq!(std::fs::read(&savepath).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn keeps_single_track_container() {
+        // 测试服务器对所有地址返回同一个文件, MPD中的轨道文件就是MPD本身
+        let manifest = br#"<MPD mediaPresentationDuration="PT4S"><Period><AdaptationSet mimeType="video/webm"><Representation id="v" bandwidth="1000"><BaseURL>video.webm</BaseURL></Representation></AdaptationSet></Period></MPD>"#.to_vec();
+        let server = TestServer::start(manifest.clone(), true).await;
+        let dir = temp_dir("single_track");
+        let savepath = dir.join("video.mpd").to_string_lossy().to_string();
+
+        let downloader = Downloader::with_options(server.url("manifest.mpd"), savepath, Default::default()).await.unwrap();
+        assert!(timeout(Duration::from_secs(30), downloader.clone().download()).await.unwrap().unwrap());
+
+        let expected = dir.join("video.webm");
+        assert_eq!(Path::new(&downloader.get_save_path()), expected);
+        assert_eq!(std::fs::read(&expected).unwrap(), manifest);
+        assert!(!dir.join("video.mp4").exists());
+        assert!(!dir.join("video.mp4.part").exists());
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::{Mutex, atomic::{AtomicBool, AtomicU64, Ordering}};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// 下载的暂停/继续/取消控制, 每次运行使用一个新的CancellationToken
+#[derive(Debug)]
+pub struct DownloadControl {
+    token: Mutex<CancellationToken>,
+    paused: AtomicBool,
+    // 暂停的次数, 用于判断一次运行是否因暂停而中止, 暂停后立即继续时paused已经恢复
+    pauses: AtomicU64,
+    cancelled: AtomicBool,
+    // 只在取消时触发, 不受暂停影响
+    cancel_token: CancellationToken,
+    resume: Notify,
+}
+
+impl Default for DownloadControl {
+    fn default() -> Self {
+        Self {
+            token: Mutex::new(CancellationToken::new()),
+            paused: AtomicBool::new(false),
+            pauses: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            cancel_token: CancellationToken::new(),
+            resume: Notify::new(),
+        }
+    }
+}
+
+impl DownloadControl {
+
+    /// 当前运行的token, 暂停或取消时被触发
+    pub fn token(&self) -> CancellationToken {
+        self.token.lock().unwrap().clone()
+    }
+
+    /// 下载完成后的处理不响应暂停, 只在取消时中止
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel_token.clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    pub fn pauses(&self) -> u64 {
+        self.pauses.load(Ordering::Acquire)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    pub fn pause(&self) {
+        if self.is_cancelled() {
+            return;
+        }
+        self.paused.store(true, Ordering::Release);
+        self.pauses.fetch_add(1, Ordering::AcqRel);
+        self.token.lock().unwrap().cancel();
+    }
+
+    /// 先换上新的token再唤醒等待中的下载
+    pub fn resume(&self) {
+        if !self.paused.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        *self.token.lock().unwrap() = CancellationToken::new();
+        self.resume.notify_one();
+    }
+
+    /// 出现无法通过重试恢复的错误时中止本次运行的所有worker, 不改变暂停与取消状态
+    pub fn abort(&self) {
+        self.token.lock().unwrap().cancel();
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+        self.token.lock().unwrap().cancel();
+        self.cancel_token.cancel();
+        self.resume.notify_one();
+    }
+
+    /// 等待继续或取消
+    pub async fn wait_resume(&self) {
+        while self.is_paused() && !self.is_cancelled() {
+            self.resume.notified().await;
+        }
+    }
+}
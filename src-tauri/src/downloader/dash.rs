@@ -0,0 +1,513 @@
+use reqwest::{Client, Response, Url, header::HeaderMap};
+use roxmltree::{Document, Node};
+use anyhow::Result;
+use thiserror::Error;
+use super::header_value;
+use super::segment::{Segment, StreamOptions};
+
+// SegmentTemplate省略startNumber时的第一个分段序号
+const DEFAULT_START_NUMBER: u64 = 1;
+
+#[derive(Error, Debug)]
+pub enum DashError {
+
+    #[error("MPD格式错误: {0}")]
+    InvalidManifestError(String),
+
+    #[error("MPD中没有可下载的音视频")]
+    NoRepresentationError,
+
+    #[error("暂不支持直播流")]
+    LiveStreamError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackKind {
+    Video,
+    Audio,
+}
+
+impl TrackKind {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrackKind::Video => "video",
+            TrackKind::Audio => "audio",
+        }
+    }
+}
+
+/// 轨道数据的寻址方式
+#[derive(Debug, Clone)]
+pub enum TrackSource {
+    // SegmentBase或只有BaseURL, 整个轨道是一个文件, 使用分片下载
+    File(Url),
+    // SegmentList或SegmentTemplate, 初始化分段与媒体分段按顺序拼接
+    Segments(Vec<Segment>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Representation {
+    pub kind: TrackKind,
+    pub mime_type: String,
+    pub codecs: Option<String>,
+    pub bandwidth: u64,
+    pub height: Option<u32>,
+    pub source: TrackSource,
+}
+
+impl Representation {
+
+    /// 轨道文件的扩展名
+    pub fn extension(&self) -> &'static str {
+        match self.kind {
+            _ if self.mime_type.ends_with("webm") => "webm",
+            TrackKind::Video => "mp4",
+            TrackKind::Audio => "m4a",
+        }
+    }
+}
+
+/// 响应类型或地址的扩展名是mpd时按DASH下载
+pub fn is_manifest(headers: &HeaderMap, url: &Url) -> bool {
+    let content_type = header_value(headers, "Content-Type")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    content_type.contains("dash+xml") || url.path().to_ascii_lowercase().ends_with(".mpd")
+}
+
+/// ISO 8601时长, 例如: PT1H2M3.5S, 只支持天及以下的单位
+fn parse_duration(value: &str) -> Option<f64> {
+    let value = value.trim().strip_prefix('P')?;
+    let (days, time) = value.split_once('T').unwrap_or((value, ""));
+    let mut seconds = match days.strip_suffix('D') {
+        Some(days) => days.parse::<f64>().ok()? * 86400.0,
+        None if days.is_empty() => 0.0,
+        None => return None,
+    };
+    let mut number = String::new();
+    for c in time.chars() {
+        let unit = match c {
+            'H' => 3600.0,
+            'M' => 60.0,
+            'S' => 1.0,
+            c => {
+                number.push(c);
+                continue;
+            }
+        };
+        seconds += number.parse::<f64>().ok()? * unit;
+        number.clear();
+    }
+    Some(seconds)
+}
+
+/// 闭区间的字节范围, 例如: 0-863, 返回长度与起始位置
+fn parse_range(value: &str) -> Option<(u64, u64)> {
+    let (start, end) = value.split_once('-')?;
+    let (start, end): (u64, u64) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    Some((end.checked_sub(start)? + 1, start))
+}
+
+fn children<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'static str) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn child<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'static str) -> Option<Node<'a, 'input>> {
+    children(node, name).next()
+}
+
+/// 从最内层到最外层依次查找属性, Representation上的属性覆盖AdaptationSet上的
+fn inherited<'a, 'input: 'a>(nodes: &[Node<'a, 'input>], name: &str) -> Option<&'a str> {
+    nodes.iter().find_map(|node| node.attribute(name))
+}
+
+fn join(base: &Url, uri: &str) -> Result<Url, DashError> {
+    base.join(uri.trim()).map_err(|_| DashError::InvalidManifestError(uri.to_string()))
+}
+
+/// 每一级的BaseURL都相对于上一级解析
+fn base_url(node: Node, base: &Url) -> Result<Url, DashError> {
+    match child(node, "BaseURL").and_then(|n| n.text()) {
+        Some(uri) => join(base, uri),
+        None => Ok(base.clone()),
+    }
+}
+
+/// 替换SegmentTemplate中的标识符, 例如: $RepresentationID$/$Number%05d$.m4s
+fn expand_template(template: &str, id: &str, bandwidth: u64, number: u64, time: u64) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = match after.find('$') {
+            Some(end) => end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let identifier = &after[..end];
+        let (name, format) = match identifier.split_once('%') {
+            Some((name, format)) => (name, Some(format)),
+            None => (identifier, None),
+        };
+        // 数字可以指定printf格式的宽度, 例如: %05d
+        let width = format
+            .and_then(|f| f.trim_end_matches('d').parse::<usize>().ok())
+            .unwrap_or(0);
+        match name {
+            "" => result.push('$'),
+            "RepresentationID" => result.push_str(id),
+            "Bandwidth" => result.push_str(&format!("{:0width$}", bandwidth, width = width)),
+            "Number" => result.push_str(&format!("{:0width$}", number, width = width)),
+            "Time" => result.push_str(&format!("{:0width$}", time, width = width)),
+            _ => {
+                result.push('$');
+                result.push_str(identifier);
+                result.push('$');
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// SegmentTimeline中每个分段的开始时间, r为-1时重复到时段结束
+fn timeline_times(timeline: Node, end_time: Option<u64>) -> Result<Vec<u64>, DashError> {
+    let mut times = vec![];
+    let mut time = 0;
+    for s in children(timeline, "S") {
+        let invalid = || DashError::InvalidManifestError("SegmentTimeline".to_string());
+        if let Some(t) = s.attribute("t") {
+            time = t.parse().map_err(|_| invalid())?;
+        }
+        let duration: u64 = s.attribute("d").and_then(|d| d.parse().ok()).ok_or_else(invalid)?;
+        if duration == 0 {
+            return Err(invalid());
+        }
+        let repeat: i64 = s.attribute("r").and_then(|r| r.parse().ok()).unwrap_or(0);
+        let mut count = 0;
+        loop {
+            times.push(time);
+            time += duration;
+            count += 1;
+            let done = match repeat {
+                r if r < 0 => end_time.map_or(true, |end| time >= end),
+                r => count > r,
+            };
+            if done {
+                break;
+            }
+        }
+    }
+    Ok(times)
+}
+
+/// SegmentTemplate寻址, 模板可以分别写在Representation与AdaptationSet上
+fn template_segments(templates: &[Node], id: &str, bandwidth: u64, base: &Url, duration: Option<f64>) -> Result<Vec<Segment>, DashError> {
+    let attribute = |name: &str| inherited(templates, name);
+    let number = |name: &str, default: u64| -> Result<u64, DashError> {
+        match attribute(name) {
+            Some(value) => value.trim().parse().map_err(|_| DashError::InvalidManifestError(value.to_string())),
+            None => Ok(default),
+        }
+    };
+    let media = attribute("media").ok_or_else(|| DashError::InvalidManifestError("SegmentTemplate".to_string()))?;
+    let start_number = number("startNumber", DEFAULT_START_NUMBER)?;
+    let timescale = std::cmp::max(number("timescale", 1)?, 1);
+    let end_time = duration.map(|d| (d * timescale as f64).round() as u64);
+
+    // 有SegmentTimeline时按时间线生成, 否则按固定的分段时长与时段长度计算分段数
+    let times = match templates.iter().find_map(|t| child(*t, "SegmentTimeline")) {
+        Some(timeline) => timeline_times(timeline, end_time)?,
+        None => {
+            let segment_duration = number("duration", 0)?;
+            let end_time = end_time.ok_or(DashError::LiveStreamError)?;
+            if segment_duration == 0 {
+                return Err(DashError::InvalidManifestError("SegmentTemplate@duration".to_string()));
+            }
+            let count = (end_time + segment_duration - 1) / segment_duration;
+            (0..count).map(|i| i * segment_duration).collect()
+        }
+    };
+
+    let mut segments = vec![];
+    if let Some(initialization) = attribute("initialization") {
+        let url = join(base, &expand_template(initialization, id, bandwidth, start_number, 0))?;
+        segments.push(Segment { url, sequence: 0, byte_range: None, key: None });
+    }
+    for (i, time) in times.into_iter().enumerate() {
+        let number = start_number + i as u64;
+        let url = join(base, &expand_template(media, id, bandwidth, number, time))?;
+        segments.push(Segment { url, sequence: number, byte_range: None, key: None });
+    }
+    Ok(segments)
+}
+
+/// SegmentList寻址, 每个SegmentURL可以是单独的文件或同一文件中的字节范围
+fn list_segments(list: Node, base: &Url) -> Result<Vec<Segment>, DashError> {
+    let range = |node: Node, name: &str| -> Result<Option<(u64, u64)>, DashError> {
+        match node.attribute(name) {
+            Some(value) => parse_range(value)
+                .map(Some)
+                .ok_or_else(|| DashError::InvalidManifestError(value.to_string())),
+            None => Ok(None),
+        }
+    };
+    let mut segments = vec![];
+    if let Some(initialization) = child(list, "Initialization") {
+        let url = match initialization.attribute("sourceURL") {
+            Some(uri) => join(base, uri)?,
+            None => base.clone(),
+        };
+        segments.push(Segment { url, sequence: 0, byte_range: range(initialization, "range")?, key: None });
+    }
+    for (i, segment) in children(list, "SegmentURL").enumerate() {
+        let url = match segment.attribute("media") {
+            Some(uri) => join(base, uri)?,
+            None => base.clone(),
+        };
+        segments.push(Segment { url, sequence: i as u64 + 1, byte_range: range(segment, "mediaRange")?, key: None });
+    }
+    Ok(segments)
+}
+
+fn parse_representation(period: Node, adaptation: Node, representation: Node, base: &Url, duration: Option<f64>) -> Result<Option<Representation>, DashError> {
+    let nodes = [representation, adaptation];
+    let mime_type = inherited(&nodes, "mimeType").unwrap_or_default().to_ascii_lowercase();
+    let content_type = inherited(&nodes, "contentType").unwrap_or_default().to_ascii_lowercase();
+    // 字幕等其他类型的轨道不下载
+    let kind = if mime_type.starts_with("video/") || content_type == "video" {
+        TrackKind::Video
+    } else if mime_type.starts_with("audio/") || content_type == "audio" {
+        TrackKind::Audio
+    } else {
+        return Ok(None);
+    };
+    let id = representation.attribute("id").unwrap_or_default().to_string();
+    let bandwidth = representation.attribute("bandwidth").and_then(|b| b.parse().ok()).unwrap_or(0);
+
+    let templates = [representation, adaptation, period].iter()
+        .filter_map(|node| child(*node, "SegmentTemplate"))
+        .collect::<Vec<Node>>();
+    let list = child(representation, "SegmentList").or_else(|| child(adaptation, "SegmentList"));
+    let source = if !templates.is_empty() {
+        TrackSource::Segments(template_segments(&templates, &id, bandwidth, base, duration)?)
+    } else if let Some(list) = list {
+        TrackSource::Segments(list_segments(list, base)?)
+    } else {
+        TrackSource::File(base.clone())
+    };
+
+    Ok(Some(Representation {
+        kind,
+        mime_type,
+        codecs: inherited(&nodes, "codecs").map(String::from),
+        bandwidth,
+        height: inherited(&nodes, "height").and_then(|h| h.parse().ok()),
+        source,
+    }))
+}
+
+/// 解析MPD中第一个时段的所有音视频轨道, 点播的MPD通常只有一个时段
+pub fn parse_manifest(text: &str, base: &Url) -> Result<Vec<Representation>, DashError> {
+    let document = Document::parse(text).map_err(|e| DashError::InvalidManifestError(e.to_string()))?;
+    let mpd = document.root_element();
+    if mpd.tag_name().name() != "MPD" {
+        return Err(DashError::InvalidManifestError(mpd.tag_name().name().to_string()));
+    }
+    if mpd.attribute("type") == Some("dynamic") {
+        return Err(DashError::LiveStreamError);
+    }
+    let base = base_url(mpd, base)?;
+    let period = child(mpd, "Period").ok_or_else(|| DashError::InvalidManifestError("Period".to_string()))?;
+    let duration = period.attribute("duration")
+        .or_else(|| mpd.attribute("mediaPresentationDuration"))
+        .and_then(parse_duration);
+    let period_base = base_url(period, &base)?;
+
+    let mut representations = vec![];
+    for adaptation in children(period, "AdaptationSet") {
+        let adaptation_base = base_url(adaptation, &period_base)?;
+        for representation in children(adaptation, "Representation") {
+            let representation_base = base_url(representation, &adaptation_base)?;
+            if let Some(r) = parse_representation(period, adaptation, representation, &representation_base, duration)? {
+                representations.push(r);
+            }
+        }
+    }
+    Ok(representations)
+}
+
+/// 视频按options选择, 音频选择码率最高的, 只有一种时只下载这一个轨道
+pub fn select_tracks(representations: Vec<Representation>, options: &StreamOptions) -> Vec<Representation> {
+    let (videos, audios): (Vec<Representation>, Vec<Representation>) = representations.into_iter()
+        .partition(|r| r.kind == TrackKind::Video);
+    let video = options.select(&videos, |r| (r.bandwidth, r.height, r.codecs.clone()));
+    let audio = StreamOptions::default().select(&audios, |r| (r.bandwidth, None, None));
+    video.into_iter().chain(audio).cloned().collect()
+}
+
+/// 读取MPD并选择要下载的轨道, body为探测时服务器已返回的MPD响应
+pub async fn load(client: &Client, url: &Url, body: Option<Response>, options: &StreamOptions) -> Result<Vec<Representation>> {
+    let response = match body {
+        Some(response) => response,
+        None => client.get(url.as_str()).send().await?.error_for_status()?,
+    };
+    // 重定向后相对地址以最终地址为准
+    let base = response.url().clone();
+    let text = response.text().await?;
+    let tracks = select_tracks(parse_manifest(&text, &base)?, options);
+    if tracks.is_empty() {
+        return Err(DashError::NoRepresentationError.into());
+    }
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Vec<Representation> {
+        parse_manifest(text, &Url::parse("https://example.com/vod/manifest.mpd").unwrap()).unwrap()
+    }
+
+    // 地址, 序号与字节范围
+    type SegmentInfo = (String, u64, Option<(u64, u64)>);
+
+    fn segments(representation: &Representation) -> Vec<SegmentInfo> {
+        match &representation.source {
+            TrackSource::Segments(segments) => segments.iter()
+                .map(|s| (s.url.to_string(), s.sequence, s.byte_range))
+                .collect(),
+            TrackSource::File(url) => panic!("expected segments, got {}", url),
+        }
+    }
+
+    fn file(representation: &Representation) -> &str {
+        match &representation.source {
+            TrackSource::File(url) => url.as_str(),
+            TrackSource::Segments(_) => panic!("expected a single file"),
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        let cases = [
+            ("PT1M30.5S", Some(90.5)),
+            ("PT1H2M3S", Some(3723.0)),
+            ("P1DT1S", Some(86401.0)),
+            ("PT0S", Some(0.0)),
+            ("1M30S", None),
+            ("P1Y", None),
+            ("PTxS", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_duration(value), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn parses_segment_base() {
+        let representations = parse(include_str!("fixtures/segment_base.mpd"));
+        // 字幕轨道不下载
+        assert_eq!(representations.len(), 3);
+        assert_eq!(file(&representations[0]), "https://media.example.com/webm/video_360.webm");
+        assert_eq!(file(&representations[1]), "https://media.example.com/webm/video_720.webm");
+        assert_eq!(file(&representations[2]), "https://media.example.com/webm/audio.webm");
+        assert_eq!(representations[1].height, Some(720));
+        assert_eq!(representations[1].codecs.as_deref(), Some("vp9"));
+        assert_eq!(representations[2].kind, TrackKind::Audio);
+        assert!(representations.iter().all(|r| r.extension() == "webm"));
+
+        let tracks = select_tracks(representations, &StreamOptions::default());
+        let bandwidths = tracks.iter().map(|r| r.bandwidth).collect::<Vec<u64>>();
+        assert_eq!(bandwidths, [2000000, 128000]);
+    }
+
+    #[test]
+    fn parses_template_with_number() {
+        let representations = parse(include_str!("fixtures/template_number.mpd"));
+        assert_eq!(representations.len(), 2);
+        assert_eq!(representations[0].extension(), "mp4");
+        assert_eq!(representations[1].extension(), "m4a");
+
+        // 时段9.5秒, 每段4秒, 从第5段开始
+        assert_eq!(segments(&representations[0]), [
+            ("https://example.com/vod/dash/v1/init.mp4".to_string(), 0, None),
+            ("https://example.com/vod/dash/v1/seg-00005-1500000.m4s".to_string(), 5, None),
+            ("https://example.com/vod/dash/v1/seg-00006-1500000.m4s".to_string(), 6, None),
+            ("https://example.com/vod/dash/v1/seg-00007-1500000.m4s".to_string(), 7, None),
+        ]);
+        assert_eq!(segments(&representations[1]), [
+            ("https://example.com/vod/dash/a1/init.mp4".to_string(), 0, None),
+            ("https://example.com/vod/dash/a1/1.m4s".to_string(), 1, None),
+            ("https://example.com/vod/dash/a1/2.m4s".to_string(), 2, None),
+            ("https://example.com/vod/dash/a1/3.m4s".to_string(), 3, None),
+        ]);
+    }
+
+    #[test]
+    fn parses_template_with_timeline() {
+        let representations = parse(include_str!("fixtures/template_time.mpd"));
+        assert_eq!(segments(&representations[0]), [
+            ("https://example.com/vod/init-hd.mp4".to_string(), 0, None),
+            ("https://example.com/vod/chunk-hd-0.m4s".to_string(), 1, None),
+            ("https://example.com/vod/chunk-hd-180000.m4s".to_string(), 2, None),
+            ("https://example.com/vod/chunk-hd-360000.m4s".to_string(), 3, None),
+            ("https://example.com/vod/chunk-hd-540000.m4s".to_string(), 4, None),
+        ]);
+        // r=-1时重复到时段结束
+        assert_eq!(segments(&representations[1]), [
+            ("https://example.com/vod/audio-1000.m4s".to_string(), 1, None),
+            ("https://example.com/vod/audio-5000.m4s".to_string(), 2, None),
+            ("https://example.com/vod/audio-9000.m4s".to_string(), 3, None),
+        ]);
+    }
+
+    #[test]
+    fn parses_segment_list() {
+        let representations = parse(include_str!("fixtures/segment_list.mpd"));
+        assert_eq!(segments(&representations[0]), [
+            ("https://example.com/vod/sd.mp4".to_string(), 0, Some((864, 0))),
+            ("https://example.com/vod/sd.mp4".to_string(), 1, Some((50000, 864))),
+            ("https://example.com/vod/sd.mp4".to_string(), 2, Some((49136, 50864))),
+        ]);
+        assert_eq!(segments(&representations[1]), [
+            ("https://example.com/vod/hd/init.mp4".to_string(), 0, None),
+            ("https://example.com/vod/hd/1.m4s".to_string(), 1, None),
+            ("https://example.com/vod/hd/2.m4s".to_string(), 2, None),
+        ]);
+
+        // 只有视频时只下载一个轨道
+        let options = StreamOptions { max_height: Some(480), ..Default::default() };
+        let tracks = select_tracks(representations, &options);
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].height, Some(480));
+    }
+
+    #[test]
+    fn rejects_unsupported_manifests() {
+        let base = Url::parse("https://example.com/vod/manifest.mpd").unwrap();
+        let live = r#"<MPD type="dynamic"><Period/></MPD>"#;
+        assert!(matches!(parse_manifest(live, &base), Err(DashError::LiveStreamError)));
+        // 没有时长时无法按固定分段时长计算分段数
+        let unknown_duration = r#"<MPD><Period><AdaptationSet mimeType="video/mp4"><SegmentTemplate duration="4" media="$Number$.m4s"/><Representation id="v"/></AdaptationSet></Period></MPD>"#;
+        assert!(matches!(parse_manifest(unknown_duration, &base), Err(DashError::LiveStreamError)));
+
+        let invalid = [
+            "not xml",
+            "<Playlist/>",
+            "<MPD/>",
+            r#"<MPD mediaPresentationDuration="PT4S"><Period><AdaptationSet mimeType="video/mp4"><SegmentTemplate media="$Number$.m4s"/><Representation id="v"/></AdaptationSet></Period></MPD>"#,
+            r#"<MPD><Period><AdaptationSet mimeType="video/mp4"><SegmentTemplate media="$Time$.m4s"><SegmentTimeline><S d="0"/></SegmentTimeline></SegmentTemplate><Representation id="v"/></AdaptationSet></Period></MPD>"#,
+            r#"<MPD><Period><AdaptationSet mimeType="video/mp4"><Representation id="v"><SegmentList><SegmentURL mediaRange="100-50"/></SegmentList></Representation></AdaptationSet></Period></MPD>"#,
+        ];
+        for text in invalid {
+            assert!(matches!(parse_manifest(text, &base), Err(DashError::InvalidManifestError(_))), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,560 @@
+use std::{fs, io, path::{Path, PathBuf}};
+use reqwest::{Url, header::HeaderMap};
+use serde::{Serialize, Deserialize};
+use super::{header_value, PART_FILE_SUFFIX};
+
+// 无法确定扩展名时使用
+const DEFAULT_EXTENSION: &'static str = ".mp4";
+
+// 保存到目录且服务器没有提供文件名时使用
+const DEFAULT_FILE_STEM: &'static str = "download";
+
+// 识别文件类型时读取的文件头长度, MPEG-TS需要检查多个188字节的包
+pub(super) const SNIFF_SIZE: usize = 4096;
+
+// 大多数文件系统限制文件名为255字节(NTFS为255个UTF-16字符, 不会比这更严格)
+const MAX_FILENAME_BYTES: usize = 255;
+
+// 为.part/.state后缀与冲突时追加的序号预留的字节数
+const SUFFIX_RESERVE_BYTES: usize = 32;
+
+// 追加序号时最多尝试的次数
+const MAX_COLLISION_COUNTER: u32 = 9999;
+
+// Windows保留的设备名, 不区分大小写, 带扩展名时同样不可用
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// 目标文件已存在时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    // 已存在时不再下载, 直接使用已有文件
+    Skip,
+    Overwrite,
+    // 追加序号, 例如: 标题 (1).mp4
+    AppendCounter,
+    // 追加下载ID, 仍然冲突时再追加序号
+    AppendId,
+}
+
+impl Default for CollisionPolicy {
+    fn default() -> Self {
+        CollisionPolicy::AppendCounter
+    }
+}
+
+// 从URL中只接受这些扩展名, 避免把.php, .html之类的接口地址当作文件类型
+const KNOWN_EXTENSIONS: &[&str] = &[
+    ".mp4", ".m4v", ".m4a", ".mov", ".flv", ".ts", ".m3u8", ".mpd", ".webm", ".mkv", ".3gp", ".3gpp",
+    ".avi", ".wmv", ".mp3", ".aac", ".wav", ".ogg", ".jpg", ".jpeg", ".png", ".webp", ".gif",
+];
+
+fn content_type_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    let extension = match mime.as_str() {
+        "video/x-flv" => ".flv",
+        "video/mp4" => ".mp4",
+        "application/x-mpegurl" | "application/vnd.apple.mpegurl" => ".m3u8",
+        "application/dash+xml" => ".mpd",
+        "video/mp2t" => ".ts",
+        "video/webm" => ".webm",
+        "video/x-matroska" => ".mkv",
+        "video/3gpp" => ".3gpp",
+        "video/quicktime" => ".mov",
+        "video/x-msvideo" => ".avi",
+        "video/x-ms-wmv" => ".wmv",
+        "audio/x-wav" => ".wav",
+        "audio/x-mp3" | "audio/mpeg" => ".mp3",
+        "audio/mp4" => ".m4a",
+        "application/ogg" => ".ogg",
+        "image/jpeg" => ".jpeg",
+        "image/png" => ".png",
+        "image/webp" => ".webp",
+        "image/tiff" => ".tiff",
+        "image/gif" => ".gif",
+        "image/svg+xml" => ".svg",
+        // application/octet-stream等无法说明文件类型
+        _ => return None,
+    };
+    Some(extension)
+}
+
+/// 按文件头识别文件类型, 返回可接受的扩展名, 第一个为默认扩展名
+pub fn sniff_extensions(bytes: &[u8]) -> Option<&'static [&'static str]> {
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return Some(match &bytes[8..12] {
+            b"qt  " => &[".mov"],
+            b"M4A " => &[".m4a", ".mp4"],
+            brand if brand.starts_with(b"3g") => &[".3gp", ".3gpp"],
+            _ => &[".mp4", ".m4v", ".mov"],
+        });
+    }
+    if bytes.starts_with(b"FLV") {
+        return Some(&[".flv"]);
+    }
+    if bytes.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        let is_webm = bytes.windows(4).any(|w| w == b"webm");
+        return Some(if is_webm { &[".webm"] } else { &[".mkv"] });
+    }
+    if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        return Some(&[".jpg", ".jpeg"]);
+    }
+    if bytes.starts_with(&[0x89, b'P', b'N', b'G']) {
+        return Some(&[".png"]);
+    }
+    if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        return Some(&[".webp"]);
+    }
+    if bytes.starts_with(b"GIF8") {
+        return Some(&[".gif"]);
+    }
+    if crate::mux::is_mpeg_ts(bytes) {
+        return Some(&[".ts"]);
+    }
+    None
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|d| d as u8)
+}
+
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    decoded
+}
+
+/// 按分号拆分参数, 引号中的分号不拆分
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = vec![];
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        },
+        None => value.to_string(),
+    }
+}
+
+/// RFC 5987格式: charset'language'percent-encoded
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.trim().splitn(3, '\'');
+    let charset = parts.next()?.to_ascii_lowercase();
+    let _language = parts.next()?;
+    let bytes = percent_decode(parts.next()?);
+    match charset.as_str() {
+        "utf-8" => String::from_utf8(bytes).ok(),
+        "iso-8859-1" => Some(bytes.into_iter().map(char::from).collect()),
+        _ => None,
+    }
+}
+
+/// 从Content-Disposition中读取文件名, filename*优先于filename
+pub fn content_disposition_filename(value: &str) -> Option<String> {
+    let mut filename = None;
+    let mut ext_filename = None;
+    for param in split_params(value).into_iter().skip(1) {
+        let (key, value) = match param.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => ext_filename = decode_ext_value(value),
+            "filename" => filename = Some(unquote(value)),
+            _ => {},
+        }
+    }
+    ext_filename.or(filename)
+        .and_then(|name| base_name(&name))
+}
+
+/// 只保留最后一段, 防止文件名中带有路径
+fn base_name(name: &str) -> Option<String> {
+    let name = name.rsplit(|c| c == '/' || c == '\\').next()?.trim();
+    match name {
+        "" | "." | ".." => None,
+        name => Some(name.to_string()),
+    }
+}
+
+fn url_filename(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.last()?;
+    let segment = String::from_utf8(percent_decode(segment)).ok()?;
+    base_name(&segment)
+}
+
+/// 返回带点的小写扩展名
+fn extension(name: &str) -> Option<String> {
+    let extension = Path::new(name).extension()?.to_str()?;
+    if extension.is_empty() || extension.len() > 5 || !extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some(format!(".{}", extension.to_ascii_lowercase()))
+}
+
+fn file_stem(name: &str) -> String {
+    Path::new(name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(DEFAULT_FILE_STEM)
+        .to_string()
+}
+
+fn is_invalid_char(c: char) -> bool {
+    matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+        || c.is_control()
+        // 双向文本控制符会让文件名显示的顺序与实际不同
+        || matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// 零宽连接符, 变体选择符, 肤色修饰符与组合附加符号不能单独出现在截断后的结尾
+fn is_joining_char(c: char) -> bool {
+    matches!(c, '\u{200d}' | '\u{fe0e}' | '\u{fe0f}' | '\u{1f3fb}'..='\u{1f3ff}' | '\u{0300}'..='\u{036f}')
+}
+
+/// 按UTF-8字节数截断, 不会拆开多字节字符, 也不会在emoji组合序列中间留下连接符
+fn truncate_bytes(value: &str, max_bytes: usize) -> &str {
+    if value.len() <= max_bytes {
+        return value;
+    }
+    let mut end = max_bytes;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut truncated = &value[..end];
+    while let Some(c) = truncated.chars().last() {
+        if !is_joining_char(c) {
+            break;
+        }
+        truncated = &truncated[..truncated.len() - c.len_utf8()];
+        // 连接符前面的字符与后面被截掉的部分属于同一个emoji
+        if c == '\u{200d}' {
+            if let Some(base) = truncated.chars().last() {
+                truncated = &truncated[..truncated.len() - base.len_utf8()];
+            }
+        }
+    }
+    truncated
+}
+
+/// 生成在Windows, macOS与Linux上都可用的文件名
+/// 替换非法字符, 去掉Windows不允许的结尾空格与点, 避开保留设备名, 并按字节数截断文件名主体
+pub fn sanitize_filename(name: &str) -> String {
+    let name = name.chars()
+        .map(|c| if is_invalid_char(c) { '_' } else { c })
+        .collect::<String>();
+    let name = name.trim().trim_end_matches(|c| c == '.' || c == ' ');
+
+    let (stem, file_extension) = match extension(name) {
+        Some(file_extension) => (&name[..name.len() - file_extension.len()], file_extension),
+        None => (name, String::new()),
+    };
+    let max_stem = MAX_FILENAME_BYTES - SUFFIX_RESERVE_BYTES - file_extension.len();
+    let stem = truncate_bytes(stem.trim(), max_stem).trim_end_matches(|c| c == '.' || c == ' ');
+    let mut stem = match stem {
+        "" => DEFAULT_FILE_STEM.to_string(),
+        stem => stem.to_string(),
+    };
+    // 设备名后面带点时同样是保留名, 在第一个点之前追加
+    let device = stem.split('.').next().unwrap_or_default().len();
+    if WINDOWS_RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(&stem[..device])) {
+        stem.insert(device, '_');
+    }
+    format!("{}{}", stem, file_extension)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> String {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(DEFAULT_FILE_STEM);
+    let file_extension = path.extension()
+        .and_then(|s| s.to_str())
+        .map(|e| format!(".{}", e))
+        .unwrap_or_default();
+    let name = sanitize_filename(&format!("{}{}{}", stem, suffix, file_extension));
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+/// 以独占方式创建临时文件占用路径, 同时开始的两个下载不会选中同一个文件名
+fn try_claim(savepath: &str) -> io::Result<bool> {
+    let partpath = format!("{}{}", savepath, PART_FILE_SUFFIX);
+    match fs::OpenOptions::new().write(true).create_new(true).open(partpath) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// 按冲突策略确定最终的保存路径, 返回(路径, 是否跳过下载)
+/// 临时文件已存在说明有其他下载正在使用该路径, 此时无论哪种策略都改用其他文件名
+pub fn claim_save_path(savepath: &str, policy: CollisionPolicy, download_id: Option<&str>) -> io::Result<(String, bool)> {
+    let path = Path::new(savepath);
+    let exists = path.exists();
+    if exists && policy == CollisionPolicy::Skip {
+        return Ok((savepath.to_string(), true));
+    }
+    if (!exists || policy == CollisionPolicy::Overwrite) && try_claim(savepath)? {
+        return Ok((savepath.to_string(), false));
+    }
+    if policy == CollisionPolicy::AppendId {
+        if let Some(download_id) = download_id {
+            let candidate = with_suffix(path, &format!("_{}", download_id));
+            if !Path::new(&candidate).exists() && try_claim(&candidate)? {
+                return Ok((candidate, false));
+            }
+        }
+    }
+    for counter in 1..=MAX_COLLISION_COUNTER {
+        let candidate = with_suffix(path, &format!(" ({})", counter));
+        if !Path::new(&candidate).exists() && try_claim(&candidate)? {
+            return Ok((candidate, false));
+        }
+    }
+    Err(io::ErrorKind::AlreadyExists.into())
+}
+
+/// 根据响应头与重定向后的地址确定保存路径
+/// savepath是已存在的目录时使用服务器提供的文件名, 否则保留调用方的文件名只修正扩展名
+/// 扩展名依次取自Content-Disposition, URL, Content-Type, 最后才是调用方给出的扩展名
+pub fn resolve_save_path(savepath: &str, headers: &HeaderMap, url: &Url) -> String {
+    let disposition = header_value(headers, "Content-Disposition")
+        .and_then(|v| content_disposition_filename(&v));
+    let url_name = url_filename(url);
+
+    let path = Path::new(savepath);
+    let (dir, stem, own_extension) = if path.is_dir() {
+        let name = disposition.clone()
+            .or_else(|| url_name.clone())
+            .unwrap_or_else(|| DEFAULT_FILE_STEM.to_string());
+        (path.to_path_buf(), file_stem(&name), None)
+    } else {
+        let name = path.file_name().and_then(|s| s.to_str()).unwrap_or(DEFAULT_FILE_STEM);
+        (path.parent().map(Path::to_path_buf).unwrap_or_default(), file_stem(name), extension(name))
+    };
+
+    let file_extension = disposition.as_deref()
+        .and_then(extension)
+        .or_else(|| url_name.as_deref()
+            .and_then(extension)
+            .filter(|e| KNOWN_EXTENSIONS.contains(&e.as_str())))
+        .or_else(|| header_value(headers, "Content-Type")
+            .and_then(|v| content_type_extension(&v))
+            .map(String::from))
+        .or(own_extension)
+        .unwrap_or_else(|| DEFAULT_EXTENSION.to_string());
+
+    // 服务器提供的文件名同样可能带有非法字符
+    dir.join(sanitize_filename(&format!("{}{}", stem, file_extension)))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// 下载完成后按文件头检查扩展名, 与实际类型不符时返回修正后的路径
+pub fn correct_extension(head: &[u8], savepath: &str) -> Option<String> {
+    let extensions = sniff_extensions(head)?;
+    let path = PathBuf::from(savepath);
+    let current = path.file_name()
+        .and_then(|s| s.to_str())
+        .and_then(extension);
+    if current.as_deref().map_or(false, |e| extensions.contains(&e)) {
+        return None;
+    }
+    Some(path.with_extension(&extensions[0][1..]).to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+    use super::*;
+    use super::super::test_server::temp_dir;
+
+    #[test]
+    fn parses_content_disposition() {
+        let cases = [
+            (r#"attachment; filename="video.mp4""#, Some("video.mp4")),
+            ("attachment; filename=video.mp4", Some("video.mp4")),
+            (r#"inline; FILENAME="video.mp4""#, Some("video.mp4")),
+            ("attachment; filename*=UTF-8''%E4%B8%AD%E6%96%87.mp4", Some("中文.mp4")),
+            (r#"attachment; filename="fallback.mp4"; filename*=UTF-8''real.mp4"#, Some("real.mp4")),
+            (r#"attachment; filename*=utf-8'en'%E2%82%AC%20rates.pdf; filename="rates.pdf""#, Some("€ rates.pdf")),
+            ("attachment; filename*=iso-8859-1'en'%A3%20rates.pdf", Some("£ rates.pdf")),
+            // 不支持的字符集与无效的UTF-8退回到filename
+            (r#"attachment; filename*=gbk''%D6%D0.mp4; filename="gbk.mp4""#, Some("gbk.mp4")),
+            ("attachment; filename*=UTF-8''%E4%B8.mp4", None),
+            (r#"attachment; filename="a;b.mp4""#, Some("a;b.mp4")),
+            (r#"attachment; filename="a\"b.mp4""#, Some("a\"b.mp4")),
+            // 只保留路径的最后一段
+            (r#"attachment; filename="../../etc/passwd""#, Some("passwd")),
+            (r#"attachment; filename="C:\\dir\\video.mp4""#, Some("video.mp4")),
+            (r#"attachment; filename="..""#, None),
+            (r#"attachment; filename="""#, None),
+            ("attachment", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(content_disposition_filename(value).as_deref(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn truncates_at_char_boundaries() {
+        let cases = [
+            ("hello", 10, "hello"),
+            ("hello", 3, "hel"),
+            ("中文字", 6, "中文"),
+            ("中文字", 4, "中"),
+            ("中文字", 2, ""),
+            ("a😀", 4, "a"),
+            // 截断后结尾的肤色修饰符无法确定是否完整, 连同前面的emoji一起保留或去掉
+            ("👍🏽x", 8, "👍"),
+            ("👍🏽x", 6, "👍"),
+            // 零宽连接符连同前面的字符一起去掉
+            ("a👨\u{200d}👩\u{200d}👧", 11, "a"),
+            ("a👨\u{200d}👩\u{200d}👧", 15, "a"),
+            ("a👨\u{200d}👩\u{200d}👧", 18, "a"),
+            ("a👨\u{200d}👩\u{200d}👧", 19, "a👨\u{200d}👩\u{200d}👧"),
+            ("ae\u{301}x", 4, "ae"),
+        ];
+        for (value, max_bytes, expected) in cases {
+            let truncated = truncate_bytes(value, max_bytes);
+            assert_eq!(truncated, expected, "{:?} {}", value, max_bytes);
+            assert!(truncated.len() <= max_bytes);
+        }
+    }
+
+    #[test]
+    fn sanitizes_filenames() {
+        let long_stem = "啊".repeat(100);
+        let truncated_stem = "啊".repeat((MAX_FILENAME_BYTES - SUFFIX_RESERVE_BYTES - ".mp4".len()) / 3);
+        let cases = [
+            ("video.mp4", "video.mp4".to_string()),
+            ("a<b>c:d\"e/f\\g|h?i*j.mp4", "a_b_c_d_e_f_g_h_i_j.mp4".to_string()),
+            ("tab\tname\n.mp4", "tab_name_.mp4".to_string()),
+            ("a\u{202e}4pm.exe", "a_4pm.exe".to_string()),
+            ("  name.mp4  ", "name.mp4".to_string()),
+            ("name. . ", "name".to_string()),
+            ("name .mp4", "name.mp4".to_string()),
+            ("video.MP4", "video.mp4".to_string()),
+            ("", "download".to_string()),
+            ("...", "download".to_string()),
+            // 以点开头的是隐藏文件, 没有扩展名
+            (".mp4", ".mp4".to_string()),
+            ("CON", "CON_".to_string()),
+            ("con.mp4", "con_.mp4".to_string()),
+            ("lpt1.tar.gz", "lpt1_.tar.gz".to_string()),
+            ("CONSOLE.mp4", "CONSOLE.mp4".to_string()),
+            (&format!("{}.mp4", long_stem), format!("{}.mp4", truncated_stem)),
+        ];
+        for (name, expected) in cases.iter() {
+            let sanitized = sanitize_filename(name);
+            assert_eq!(&sanitized, expected, "{:?}", name);
+            assert!(sanitized.len() <= MAX_FILENAME_BYTES - SUFFIX_RESERVE_BYTES);
+        }
+    }
+
+    #[test]
+    fn resolves_save_paths() {
+        let dir = temp_dir("resolve_save_path");
+        let dir_path = dir.to_string_lossy().to_string();
+        let file_path = dir.join("标题.mp4").to_string_lossy().to_string();
+        let no_extension = dir.join("标题").to_string_lossy().to_string();
+        // (保存路径, Content-Disposition, Content-Type, 地址, 文件名)
+        let cases = [
+            (&dir_path, Some(r#"attachment; filename="report.flv""#), None, "https://example.com/get?id=1", "report.flv"),
+            (&dir_path, None, None, "https://example.com/path/clip.webm?x=1", "clip.webm"),
+            (&dir_path, None, Some("video/mp4"), "https://example.com/play.php", "play.mp4"),
+            (&dir_path, None, None, "https://example.com/", "download.mp4"),
+            (&dir_path, Some(r#"attachment; filename="a:b?.mp4""#), None, "https://example.com/", "a_b_.mp4"),
+            (&dir_path, None, None, "https://example.com/%E4%B8%AD%E6%96%87.mp4", "中文.mp4"),
+            (&file_path, None, Some("video/x-flv"), "https://example.com/play", "标题.flv"),
+            (&file_path, None, Some("video/mp4"), "https://example.com/a/b.m3u8", "标题.m3u8"),
+            (&file_path, Some(r#"attachment; filename="../../evil.mkv""#), None, "https://example.com/", "标题.mkv"),
+            (&file_path, None, Some("application/octet-stream"), "https://example.com/download", "标题.mp4"),
+            (&no_extension, None, Some("audio/mpeg"), "https://example.com/download", "标题.mp3"),
+            (&no_extension, None, None, "https://example.com/download", "标题.mp4"),
+        ];
+        for (savepath, disposition, content_type, url, expected) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(disposition) = disposition {
+                headers.insert("Content-Disposition", HeaderValue::from_str(disposition).unwrap());
+            }
+            if let Some(content_type) = content_type {
+                headers.insert("Content-Type", HeaderValue::from_static(content_type));
+            }
+            let resolved = resolve_save_path(savepath, &headers, &Url::parse(url).unwrap());
+            assert_eq!(resolved, dir.join(expected).to_string_lossy(), "{} {}", savepath, url);
+        }
+    }
+
+    #[test]
+    fn corrects_extensions() {
+        let mp4 = b"\0\0\0\x20ftypisom\0\0\x02\0".to_vec();
+        let m4a = b"\0\0\0\x20ftypM4A \0\0\x02\0".to_vec();
+        let flv = b"FLV\x01\x05\0\0\0\x09".to_vec();
+        let webm = b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\x82\x84webm".to_vec();
+        let jpeg = b"\xff\xd8\xff\xe0\0\x10JFIF".to_vec();
+        let mut ts = vec![0u8; 188 * 3];
+        for packet in ts.chunks_mut(188) {
+            packet[0] = 0x47;
+        }
+        let cases = [
+            (&mp4, "a.mp4", None),
+            (&mp4, "a.MOV", None),
+            (&mp4, "a.flv", Some("a.mp4")),
+            (&m4a, "a.mp4", None),
+            (&m4a, "a.ts", Some("a.m4a")),
+            (&flv, "a.mp4", Some("a.flv")),
+            (&webm, "a.mkv", Some("a.webm")),
+            (&jpeg, "a.jpeg", None),
+            (&jpeg, "a.png", Some("a.jpg")),
+            (&jpeg, "dir/a", Some("dir/a.jpg")),
+            (&ts, "a.mp4", Some("a.ts")),
+            // 无法识别的文件不修改
+            (&b"<html></html>".to_vec(), "a.mp4", None),
+        ];
+        for (head, savepath, expected) in cases {
+            assert_eq!(correct_extension(head, savepath).as_deref(), expected, "{}", savepath);
+        }
+    }
+}
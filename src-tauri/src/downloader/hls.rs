@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use reqwest::{Client, Response, Url, header::HeaderMap};
+use anyhow::Result;
+use thiserror::Error;
+use super::header_value;
+use super::segment::{Segment, SegmentKey, SegmentStream, StreamOptions};
+
+#[derive(Error, Debug)]
+pub enum HlsError {
+
+    #[error("m3u8播放列表格式错误: {0}")]
+    InvalidPlaylistError(String),
+
+    #[error("播放列表中没有可下载的清晰度")]
+    NoVariantError,
+
+    #[error("暂不支持直播流")]
+    LiveStreamError,
+
+    #[error("不支持的加密方式: {0}")]
+    UnsupportedEncryptionError(String),
+}
+
+/// 主播放列表中的一个清晰度
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub url: Url,
+    pub bandwidth: u64,
+    // 宽与高
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaPlaylist {
+    pub segments: Vec<Segment>,
+    // 有EXT-X-ENDLIST时为true, 否则是还在更新的直播流
+    pub ended: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Playlist {
+    Master(Vec<Variant>),
+    Media(MediaPlaylist),
+}
+
+/// 响应类型或地址的扩展名是m3u8时按HLS下载
+pub fn is_playlist(headers: &HeaderMap, url: &Url) -> bool {
+    let content_type = header_value(headers, "Content-Type")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    content_type.contains("mpegurl") || url.path().to_ascii_lowercase().ends_with(".m3u8")
+}
+
+/// 解析属性列表, 例如: BANDWIDTH=1280000,RESOLUTION=1280x720,CODECS="avc1.4d401f,mp4a.40.2"
+fn parse_attributes(value: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let (name, value) = match rest.split_once('=') {
+            Some(pair) => pair,
+            None => break,
+        };
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, next)) => (value, next),
+                None => (quoted, ""),
+            },
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        attributes.insert(name.trim().to_ascii_uppercase(), value.to_string());
+        rest = next.trim_start_matches(',').trim();
+    }
+    attributes
+}
+
+fn parse_resolution(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.split_once(|c| c == 'x' || c == 'X')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+/// 十六进制的IV, 例如: 0x0000000000000000000000000000000A
+fn parse_iv(value: &str) -> Option<[u8; 16]> {
+    let hex = value.trim().strip_prefix("0x").or_else(|| value.trim().strip_prefix("0X"))?;
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut iv = [0u8; 16];
+    for (i, byte) in iv.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(iv)
+}
+
+/// EXT-X-BYTERANGE:<长度>[@<起始位置>], 省略起始位置时紧接同一地址的上一个分段
+fn parse_byte_range(value: &str, last_end: Option<u64>) -> Option<(u64, u64)> {
+    let (len, offset) = match value.split_once('@') {
+        Some((len, offset)) => (len, Some(offset.trim().parse().ok()?)),
+        None => (value, None),
+    };
+    Some((len.trim().parse().ok()?, offset.or(last_end).unwrap_or(0)))
+}
+
+fn parse_key(value: &str, base: &Url) -> Result<Option<SegmentKey>, HlsError> {
+    let attributes = parse_attributes(value);
+    match attributes.get("METHOD").map(|m| m.as_str()) {
+        Some("NONE") => Ok(None),
+        Some("AES-128") => {
+            let uri = attributes.get("URI")
+                .ok_or_else(|| HlsError::InvalidPlaylistError(value.to_string()))?;
+            let url = base.join(uri)
+                .map_err(|_| HlsError::InvalidPlaylistError(uri.clone()))?;
+            let iv = match attributes.get("IV") {
+                Some(iv) => Some(parse_iv(iv).ok_or_else(|| HlsError::InvalidPlaylistError(iv.clone()))?),
+                None => None,
+            };
+            Ok(Some(SegmentKey { url, iv }))
+        },
+        Some(method) => Err(HlsError::UnsupportedEncryptionError(method.to_string())),
+        None => Err(HlsError::InvalidPlaylistError(value.to_string())),
+    }
+}
+
+/// 解析m3u8播放列表, 相对地址以播放列表的地址为准
+pub fn parse_playlist(text: &str, base: &Url) -> Result<Playlist, HlsError> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err(HlsError::InvalidPlaylistError("#EXTM3U".to_string()));
+    }
+
+    let mut variants = vec![];
+    let mut segments = vec![];
+    let mut ended = false;
+    let mut sequence = 0;
+    let mut key: Option<SegmentKey> = None;
+    // 等待下一行地址的EXT-X-STREAM-INF属性或分段的EXT-X-BYTERANGE
+    let mut stream_inf: Option<HashMap<String, String>> = None;
+    let mut byte_range: Option<&str> = None;
+    // 上一个分段的地址与结束位置, 用于省略了起始位置的EXT-X-BYTERANGE
+    let mut last_range: Option<(Url, u64)> = None;
+
+    let resolve = |uri: &str| base.join(uri).map_err(|_| HlsError::InvalidPlaylistError(uri.to_string()));
+
+    for line in lines {
+        if let Some(tag) = line.strip_prefix('#') {
+            let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+            match name {
+                "EXT-X-STREAM-INF" => stream_inf = Some(parse_attributes(value)),
+                "EXT-X-MEDIA-SEQUENCE" => {
+                    sequence = value.trim().parse()
+                        .map_err(|_| HlsError::InvalidPlaylistError(line.to_string()))?;
+                },
+                "EXT-X-KEY" => key = parse_key(value, base)?,
+                "EXT-X-BYTERANGE" => byte_range = Some(value),
+                // fMP4的初始化分段, 放在后续分段之前写入
+                "EXT-X-MAP" => {
+                    let attributes = parse_attributes(value);
+                    let uri = attributes.get("URI")
+                        .ok_or_else(|| HlsError::InvalidPlaylistError(line.to_string()))?;
+                    let byte_range = match attributes.get("BYTERANGE") {
+                        Some(range) => Some(parse_byte_range(range, None)
+                            .ok_or_else(|| HlsError::InvalidPlaylistError(line.to_string()))?),
+                        None => None,
+                    };
+                    segments.push(Segment { url: resolve(uri)?, sequence, byte_range, key: key.clone() });
+                },
+                "EXT-X-ENDLIST" => ended = true,
+                _ => {},
+            }
+            continue;
+        }
+
+        let url = resolve(line)?;
+        if let Some(attributes) = stream_inf.take() {
+            variants.push(Variant {
+                url,
+                bandwidth: attributes.get("BANDWIDTH").and_then(|b| b.parse().ok()).unwrap_or(0),
+                resolution: attributes.get("RESOLUTION").and_then(|r| parse_resolution(r)),
+                codecs: attributes.get("CODECS").cloned(),
+            });
+            continue;
+        }
+        let range = match byte_range.take() {
+            Some(value) => {
+                let last_end = last_range.as_ref()
+                    .filter(|(last_url, _)| *last_url == url)
+                    .map(|(_, end)| *end);
+                let range = parse_byte_range(value, last_end)
+                    .ok_or_else(|| HlsError::InvalidPlaylistError(value.to_string()))?;
+                last_range = Some((url.clone(), range.1 + range.0));
+                Some(range)
+            },
+            None => None,
+        };
+        segments.push(Segment { url, sequence, byte_range: range, key: key.clone() });
+        sequence += 1;
+    }
+
+    if !variants.is_empty() {
+        return Ok(Playlist::Master(variants));
+    }
+    Ok(Playlist::Media(MediaPlaylist { segments, ended }))
+}
+
+async fn fetch_playlist(client: &Client, url: &Url, body: Option<Response>) -> Result<Playlist> {
+    let response = match body {
+        Some(response) => response,
+        None => client.get(url.as_str()).send().await?.error_for_status()?,
+    };
+    // 重定向后相对地址以最终地址为准
+    let base = response.url().clone();
+    let text = response.text().await?;
+    Ok(parse_playlist(&text, &base)?)
+}
+
+/// 读取播放列表, 主播放列表时按options选择清晰度后再读取对应的媒体播放列表
+/// body为探测时服务器已返回的播放列表响应
+pub async fn load(client: &Client, url: &Url, body: Option<Response>, options: &StreamOptions) -> Result<SegmentStream> {
+    let playlist = match fetch_playlist(client, url, body).await? {
+        Playlist::Master(variants) => {
+            let variant = options.select(&variants, |v| (v.bandwidth, v.resolution.map(|(_, height)| height), v.codecs.clone()))
+                .ok_or(HlsError::NoVariantError)?;
+            match fetch_playlist(client, &variant.url, None).await? {
+                Playlist::Media(playlist) => playlist,
+                Playlist::Master(_) => return Err(HlsError::InvalidPlaylistError(variant.url.to_string()).into()),
+            }
+        },
+        Playlist::Media(playlist) => playlist,
+    };
+    if !playlist.ended {
+        return Err(HlsError::LiveStreamError.into());
+    }
+    if playlist.segments.is_empty() {
+        return Err(HlsError::NoVariantError.into());
+    }
+    Ok(SegmentStream::new(playlist.segments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_server::TestServer;
+
+    fn base() -> Url {
+        Url::parse("https://example.com/video/index.m3u8").unwrap()
+    }
+
+    fn media(text: &str) -> MediaPlaylist {
+        match parse_playlist(text, &base()).unwrap() {
+            Playlist::Media(playlist) => playlist,
+            Playlist::Master(_) => panic!("expected a media playlist"),
+        }
+    }
+
+    #[test]
+    fn parses_master_playlist() {
+        let variants = match parse_playlist(include_str!("fixtures/master.m3u8"), &base()).unwrap() {
+            Playlist::Master(variants) => variants,
+            Playlist::Media(_) => panic!("expected a master playlist"),
+        };
+        let urls = variants.iter().map(|v| v.url.as_str()).collect::<Vec<&str>>();
+        assert_eq!(urls, [
+            "https://example.com/video/360p/index.m3u8",
+            "https://example.com/video/720p/index.m3u8",
+            "https://cdn.example.com/1080p/index.m3u8",
+        ]);
+        assert_eq!(variants[1].bandwidth, 2800000);
+        assert_eq!(variants[1].resolution, Some((1280, 720)));
+        assert_eq!(variants[1].codecs.as_deref(), Some("avc1.4d401f,mp4a.40.2"));
+
+        let cases = [
+            (StreamOptions::default(), 5000000),
+            (StreamOptions { max_height: Some(720), ..Default::default() }, 2800000),
+            (StreamOptions { max_bandwidth: Some(1000000), ..Default::default() }, 800000),
+            (StreamOptions { codecs: vec!["avc1".to_string()], ..Default::default() }, 2800000),
+            // 都超过限制时选择码率最低的
+            (StreamOptions { max_height: Some(240), ..Default::default() }, 800000),
+        ];
+        for (options, bandwidth) in cases {
+            let variant = options.select(&variants, |v| (v.bandwidth, v.resolution.map(|(_, height)| height), v.codecs.clone())).unwrap();
+            assert_eq!(variant.bandwidth, bandwidth, "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn parses_encryption_keys() {
+        let playlist = media(include_str!("fixtures/encrypted.m3u8"));
+        assert!(playlist.ended);
+
+        let sequences = playlist.segments.iter().map(|s| s.sequence).collect::<Vec<u64>>();
+        assert_eq!(sequences, [7, 8, 9]);
+
+        let key = playlist.segments[0].key.as_ref().unwrap();
+        assert_eq!(key.url.as_str(), "https://example.com/video/segment.key");
+        assert_eq!(key.iv, None);
+
+        let key = playlist.segments[1].key.as_ref().unwrap();
+        assert_eq!(key.url.as_str(), "https://example.com/keys/next.key");
+        let mut iv = [0u8; 16];
+        iv[15] = 10;
+        assert_eq!(key.iv, Some(iv));
+
+        assert!(playlist.segments[2].key.is_none());
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        let playlist = media(include_str!("fixtures/byterange.m3u8"));
+        let segments = playlist.segments.iter()
+            .map(|s| (s.url.as_str(), s.byte_range))
+            .collect::<Vec<(&str, Option<(u64, u64)>)>>();
+        assert_eq!(segments, [
+            // EXT-X-MAP的初始化分段
+            ("https://example.com/video/video.mp4", Some((720, 0))),
+            ("https://example.com/video/video.mp4", Some((100000, 720))),
+            // 省略起始位置时紧接上一个分段
+            ("https://example.com/video/video.mp4", Some((80000, 100720))),
+            ("https://example.com/video/other.mp4", Some((5000, 0))),
+        ]);
+    }
+
+    #[test]
+    fn rejects_invalid_playlists() {
+        let cases = [
+            "#EXT-X-VERSION:3\nseg0.ts",
+            "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:abc\nseg0.ts",
+            "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128\nseg0.ts",
+            "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"k\",IV=0x1234\nseg0.ts",
+            "#EXTM3U\n#EXT-X-BYTERANGE:abc\nseg0.ts",
+            "#EXTM3U\n#EXT-X-MAP:BYTERANGE=\"720@0\"",
+        ];
+        for text in cases {
+            assert!(matches!(parse_playlist(text, &base()), Err(HlsError::InvalidPlaylistError(_))), "{}", text);
+        }
+        let result = parse_playlist("#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k\"\nseg0.ts", &base());
+        assert!(matches!(result, Err(HlsError::UnsupportedEncryptionError(method)) if method == "SAMPLE-AES"));
+    }
+
+    #[tokio::test]
+    async fn rejects_live_streams() {
+        let server = TestServer::start(include_bytes!("fixtures/live.m3u8").to_vec(), false).await;
+        let url = Url::parse(&server.url("live.m3u8")).unwrap();
+        let result = load(&Client::new(), &url, None, &StreamOptions::default()).await;
+        assert!(matches!(result.unwrap_err().downcast_ref::<HlsError>(), Some(HlsError::LiveStreamError)));
+    }
+}
@@ -0,0 +1,528 @@
+use std::{path::{Path, PathBuf}, process::Stdio};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, BufReader}, process::Command};
+use tokio_util::sync::CancellationToken;
+use anyhow::Result;
+use super::progress::StepProgress;
+use super::filename::{claim_save_path, CollisionPolicy};
+use super::PART_FILE_SUFFIX;
+
+// 图形界面程序在macOS上拿不到shell的PATH, 额外查找Homebrew的安装位置
+#[cfg(target_os = "macos")]
+const FALLBACK_DIRS: &[&str] = &["/opt/homebrew/bin", "/usr/local/bin"];
+#[cfg(not(target_os = "macos"))]
+const FALLBACK_DIRS: &[&str] = &[];
+
+// 失败时错误信息中保留的ffmpeg输出行数
+const ERROR_TAIL_LINES: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum PostProcessError {
+
+    #[error("未找到ffmpeg, 请安装ffmpeg或在设置中指定路径")]
+    FfmpegNotFoundError,
+
+    #[error("ffmpeg执行失败: {0}")]
+    FfmpegFailedError(String),
+
+    #[error("后处理已取消")]
+    CancelledError,
+}
+
+fn default_crf() -> u8 {
+    23
+}
+
+fn default_preset() -> String {
+    "medium".to_string()
+}
+
+fn default_bitrate() -> u32 {
+    192
+}
+
+fn default_loudness() -> f64 {
+    -16.0
+}
+
+/// 下载完成后执行的一个ffmpeg步骤
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PostStep {
+    // H.265等编码转为H.264以兼容老设备, 已是H.264时跳过
+    TranscodeH264 {
+        #[serde(default = "default_crf")]
+        crf: u8,
+        #[serde(default = "default_preset")]
+        preset: String,
+    },
+    // 提取音频为同名的MP3文件, 原文件保留
+    ExtractMp3 {
+        // 单位为kbps
+        #[serde(default = "default_bitrate")]
+        bitrate: u32,
+    },
+    // 按EBU R128标准化响度, 视频流直接复制
+    NormalizeLoudness {
+        // 目标响度, 单位为LUFS
+        #[serde(default = "default_loudness")]
+        target: f64,
+    },
+    // 截取片段, 单位为秒, 不重新编码, 切点会落在关键帧上
+    Trim {
+        #[serde(default)]
+        start: Option<f64>,
+        #[serde(default)]
+        end: Option<f64>,
+    },
+}
+
+/// ffmpeg -i 输出中需要的媒体信息
+#[derive(Debug, Default)]
+struct MediaInfo {
+    // 单位为秒
+    duration: Option<f64>,
+    video_codec: Option<String>,
+    has_audio: bool,
+}
+
+/// 一个步骤的具体执行方式
+struct Plan {
+    extension: String,
+    // 放在输入与输出文件之间的ffmpeg参数
+    args: Vec<String>,
+    // 输出的时长, 用于计算进度
+    duration: Option<f64>,
+    // 为true时输出替换原文件, 否则作为附加文件保存在原文件旁边
+    replace: bool,
+}
+
+/// 把"HH:MM:SS.xx"转成秒
+fn parse_clock(value: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in value.trim().split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+fn parse_media_info(output: &str) -> MediaInfo {
+    let mut info = MediaInfo::default();
+    for line in output.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("Duration:") {
+            info.duration = rest.split(',').next().and_then(parse_clock);
+        }
+        if !line.starts_with("Stream #") {
+            continue;
+        }
+        if let Some((_, rest)) = line.split_once("Video: ") {
+            if info.video_codec.is_none() {
+                info.video_codec = rest.split([' ', ',']).next().map(|c| c.to_string());
+            }
+        } else if line.contains("Audio: ") {
+            info.has_audio = true;
+        }
+    }
+    info
+}
+
+/// 标准化响度时的音频编码器, 保持容器可用
+fn audio_encoder(extension: &str) -> &'static str {
+    match extension {
+        "mp3" => "libmp3lame",
+        _ => "aac",
+    }
+}
+
+impl PostStep {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PostStep::TranscodeH264 { .. } => "transcode_h264",
+            PostStep::ExtractMp3 { .. } => "extract_mp3",
+            PostStep::NormalizeLoudness { .. } => "normalize_loudness",
+            PostStep::Trim { .. } => "trim",
+        }
+    }
+
+    /// 不需要处理时返回None
+    fn plan(&self, extension: &str, info: &MediaInfo) -> Option<Plan> {
+        let plan = match self {
+            PostStep::TranscodeH264 { crf, preset } => {
+                // 没有视频流或已是H.264
+                if let None | Some("h264") = info.video_codec.as_deref() {
+                    return None;
+                }
+                Plan {
+                    extension: "mp4".to_string(),
+                    args: vec![
+                        "-c:v".into(), "libx264".into(),
+                        "-crf".into(), crf.to_string(),
+                        "-preset".into(), preset.clone(),
+                        "-pix_fmt".into(), "yuv420p".into(),
+                        "-c:a".into(), "copy".into(),
+                        "-movflags".into(), "+faststart".into(),
+                    ],
+                    duration: info.duration,
+                    replace: true,
+                }
+            },
+            PostStep::ExtractMp3 { bitrate } => {
+                if !info.has_audio || extension == "mp3" {
+                    return None;
+                }
+                Plan {
+                    extension: "mp3".to_string(),
+                    args: vec![
+                        "-vn".into(),
+                        "-c:a".into(), "libmp3lame".into(),
+                        "-b:a".into(), format!("{}k", bitrate),
+                    ],
+                    duration: info.duration,
+                    replace: false,
+                }
+            },
+            PostStep::NormalizeLoudness { target } => {
+                if !info.has_audio {
+                    return None;
+                }
+                Plan {
+                    extension: extension.to_string(),
+                    args: vec![
+                        "-c:v".into(), "copy".into(),
+                        "-af".into(), format!("loudnorm=I={}:TP=-1.5:LRA=11", target),
+                        "-c:a".into(), audio_encoder(extension).into(),
+                        "-b:a".into(), format!("{}k", default_bitrate()),
+                    ],
+                    duration: info.duration,
+                    replace: true,
+                }
+            },
+            PostStep::Trim { start, end } => {
+                if start.is_none() && end.is_none() {
+                    return None;
+                }
+                let mut args = vec![];
+                if let Some(start) = start {
+                    args.extend(["-ss".to_string(), start.to_string()]);
+                }
+                if let Some(end) = end {
+                    args.extend(["-to".to_string(), end.to_string()]);
+                }
+                args.extend(["-c".to_string(), "copy".to_string()]);
+                let end = match (end, info.duration) {
+                    (Some(end), Some(duration)) => Some(end.min(duration)),
+                    (end, duration) => end.or(duration),
+                };
+                Plan {
+                    extension: extension.to_string(),
+                    args,
+                    duration: end.map(|end| (end - start.unwrap_or(0.0)).max(0.0)),
+                    replace: true,
+                }
+            },
+        };
+        Some(plan)
+    }
+}
+
+/// 后处理设置, 没有步骤时不做后处理
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostProcessConfig {
+    // ffmpeg可执行文件的路径, 为空时在PATH中查找
+    pub ffmpeg_path: Option<String>,
+    // 按顺序执行的步骤
+    pub steps: Vec<PostStep>,
+}
+
+/// 优先使用设置中的路径, 否则在PATH中查找
+pub fn find_ffmpeg(configured: Option<&str>) -> Option<PathBuf> {
+    if let Some(path) = configured.map(str::trim).filter(|p| !p.is_empty()) {
+        let path = PathBuf::from(path);
+        return if path.is_file() { Some(path) } else { None };
+    }
+    let name = if cfg!(windows) { "ffmpeg.exe" } else { "ffmpeg" };
+    let mut dirs: Vec<PathBuf> = std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).collect())
+        .unwrap_or_default();
+    dirs.extend(FALLBACK_DIRS.iter().map(PathBuf::from));
+    dirs.into_iter()
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// 下载完成后依次执行的ffmpeg步骤
+#[derive(Debug, Clone)]
+pub struct PostProcessor {
+    ffmpeg: PathBuf,
+    steps: Vec<PostStep>,
+}
+
+impl PostProcessor {
+
+    /// 没有步骤时返回None, 有步骤却找不到ffmpeg时报错
+    pub fn from_config(config: &PostProcessConfig) -> Result<Option<Self>, PostProcessError> {
+        if config.steps.is_empty() {
+            return Ok(None);
+        }
+        let ffmpeg = find_ffmpeg(config.ffmpeg_path.as_deref()).ok_or(PostProcessError::FfmpegNotFoundError)?;
+        Ok(Some(Self { ffmpeg, steps: config.steps.clone() }))
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.ffmpeg);
+        command.args(["-hide_banner", "-nostdin"])
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        // 不弹出控制台窗口
+        #[cfg(windows)]
+        command.creation_flags(0x08000000);
+        command
+    }
+
+    /// 没有输出文件时ffmpeg以错误退出, 媒体信息在stderr中
+    async fn probe(&self, input: &Path) -> Result<MediaInfo> {
+        let output = self.command()
+            .arg("-i")
+            .arg(input)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        Ok(parse_media_info(&String::from_utf8_lossy(&output.stderr)))
+    }
+
+    /// 执行一次ffmpeg, 从-progress的输出中解析已处理的时长
+    async fn execute(&self, input: &Path, plan: &Plan, output: &Path, token: &CancellationToken, on_progress: impl Fn(Option<u8>)) -> Result<()> {
+        // 输出文件都已按冲突策略占用, 不覆盖任何已存在的文件
+        let mut child = self.command()
+            .arg("-n")
+            .arg("-i")
+            .arg(input)
+            .args(&plan.args)
+            .args(["-progress", "pipe:1", "-nostats"])
+            .arg(output)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // stderr必须持续读取, 否则管道写满后ffmpeg会阻塞
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let stderr_task = tokio::spawn(async move {
+            let mut buffer = vec![];
+            let _ = stderr.read_to_end(&mut buffer).await;
+            String::from_utf8_lossy(&buffer).to_string()
+        });
+
+        let mut lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+        let status = loop {
+            tokio::select! {
+                _ = token.cancelled() => {
+                    let _ = child.kill().await;
+                    return Err(PostProcessError::CancelledError.into());
+                },
+                line = lines.next_line() => match line? {
+                    Some(line) => {
+                        // out_time_ms实际也是微秒
+                        let micros = line.strip_prefix("out_time_us=")
+                            .or_else(|| line.strip_prefix("out_time_ms="))
+                            .and_then(|v| v.trim().parse::<u64>().ok());
+                        if let (Some(micros), Some(duration)) = (micros, plan.duration.filter(|d| *d > 0.0)) {
+                            let percentage = (micros as f64 / 1_000_000.0 / duration * 100.0).min(100.0);
+                            on_progress(Some(percentage as u8));
+                        }
+                    },
+                    None => break child.wait().await?,
+                },
+            }
+        };
+
+        let stderr = stderr_task.await.unwrap_or_default();
+        if !status.success() {
+            let lines: Vec<&str> = stderr.lines().filter(|l| !l.trim().is_empty()).collect();
+            let tail = lines[lines.len().saturating_sub(ERROR_TAIL_LINES)..].join("\n");
+            return Err(PostProcessError::FfmpegFailedError(tail).into());
+        }
+        on_progress(Some(100));
+        Ok(())
+    }
+
+    /// 依次执行所有步骤, path随之更新为最新的文件, 某一步失败时停止并保留已完成的结果
+    /// 新生成的文件按collision占用文件名, 按策略跳过已存在的文件时跳过该步骤
+    pub async fn run(&self, path: &mut String, collision: CollisionPolicy, download_id: Option<&str>, token: &CancellationToken, on_progress: impl Fn(StepProgress)) -> Result<()> {
+        let count = self.steps.len();
+        for (index, step) in self.steps.iter().enumerate() {
+            let input = PathBuf::from(path.as_str());
+            let extension = input.extension()
+                .map(|e| e.to_string_lossy().to_ascii_lowercase())
+                .unwrap_or_default();
+            let info = self.probe(&input).await?;
+            let plan = match step.plan(&extension, &info) {
+                Some(plan) => plan,
+                None => continue,
+            };
+
+            // 替换原文件且扩展名不变时覆盖的是本下载的文件, 其他情况不能覆盖已有的文件
+            let target = input.with_extension(&plan.extension);
+            let claimed = !(plan.replace && target == input);
+            let target = match claimed {
+                true => match claim(&target, collision, download_id)? {
+                    Some(target) => target,
+                    None => continue,
+                },
+                false => target,
+            };
+            // ffmpeg按扩展名选择容器, 临时文件保留原扩展名
+            let output = match claim(&target.with_extension(format!("postprocess.{}", plan.extension)), CollisionPolicy::AppendCounter, None)? {
+                Some(output) => output,
+                None => continue,
+            };
+
+            let report = |percentage| on_progress(StepProgress {
+                index,
+                count,
+                name: step.name().to_string(),
+                percentage,
+            });
+            report(plan.duration.map(|_| 0));
+
+            let result = match self.execute(&input, &plan, &output, token, report).await {
+                Ok(_) => tokio::fs::rename(&output, &target).await.map_err(anyhow::Error::from),
+                Err(e) => {
+                    let _ = tokio::fs::remove_file(&output).await;
+                    Err(e)
+                },
+            };
+            release(&output).await;
+            if claimed {
+                release(&target).await;
+            }
+            result?;
+            if plan.replace {
+                if target != input {
+                    tokio::fs::remove_file(&input).await?;
+                }
+                *path = target.to_string_lossy().to_string();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 按冲突策略占用文件名, 按策略跳过时返回None
+fn claim(path: &Path, collision: CollisionPolicy, download_id: Option<&str>) -> Result<Option<PathBuf>> {
+    match claim_save_path(&path.to_string_lossy(), collision, download_id)? {
+        (_, true) => Ok(None),
+        (path, false) => Ok(Some(PathBuf::from(path))),
+    }
+}
+
+/// 删除占用文件名时创建的临时文件
+async fn release(path: &Path) {
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART_FILE_SUFFIX);
+    let _ = tokio::fs::remove_file(part).await;
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use super::*;
+    use super::super::test_server::temp_dir;
+
+    // 模拟ffmpeg: 只有输入时输出媒体信息, 否则把输入复制到最后一个参数, 带-n时不覆盖已有文件
+    const FAKE_FFMPEG: &str = r#"#!/bin/sh
+input=""; output=""; overwrite=1
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -i) input="$2"; shift ;;
+        -n) overwrite=0 ;;
+    esac
+    output="$1"; shift
+done
+if [ "$output" = "$input" ]; then
+    echo "  Duration: 00:00:02.00, start: 0.000000, bitrate: 128 kb/s" >&2
+    echo "  Stream #0:0: Video: h264, yuv420p" >&2
+    echo "  Stream #0:1: Audio: aac, 44100 Hz, stereo" >&2
+    exit 1
+fi
+if [ $overwrite = 0 ] && [ -e "$output" ]; then
+    echo "File '$output' already exists. Exiting." >&2
+    exit 1
+fi
+cp "$input" "$output"
+echo "out_time_us=2000000"
+"#;
+
+    fn processor(dir: &Path, steps: Vec<PostStep>) -> PostProcessor {
+        let ffmpeg = dir.join("ffmpeg");
+        std::fs::write(&ffmpeg, FAKE_FFMPEG).unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        PostProcessor { ffmpeg, steps }
+    }
+
+    #[tokio::test]
+    async fn extract_mp3_keeps_existing_files() {
+        let dir = temp_dir("extract-mp3");
+        let video = dir.join("video.mp4");
+        std::fs::write(&video, b"video").unwrap();
+        std::fs::write(dir.join("video.mp3"), b"existing").unwrap();
+        let processor = processor(&dir, vec![PostStep::ExtractMp3 { bitrate: 192 }]);
+
+        let mut path = video.to_string_lossy().to_string();
+        processor.run(&mut path, CollisionPolicy::AppendCounter, None, &CancellationToken::new(), |_| {}).await.unwrap();
+
+        // 原文件与已有的同名MP3都保留, 新文件按冲突策略改名
+        assert_eq!(path, video.to_string_lossy());
+        assert_eq!(std::fs::read(&video).unwrap(), b"video");
+        assert_eq!(std::fs::read(dir.join("video.mp3")).unwrap(), b"existing");
+        assert_eq!(std::fs::read(dir.join("video (1).mp3")).unwrap(), b"video");
+        let mut names: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["ffmpeg", "video (1).mp3", "video.mp3", "video.mp4"]);
+
+        // 跳过策略下不生成新文件
+        processor.run(&mut path, CollisionPolicy::Skip, None, &CancellationToken::new(), |_| {}).await.unwrap();
+        assert!(!dir.join("video (2).mp3").exists());
+    }
+
+    #[tokio::test]
+    async fn replaces_input_in_place() {
+        let dir = temp_dir("replace-input");
+        let video = dir.join("video.mp4");
+        std::fs::write(&video, b"video").unwrap();
+        let processor = processor(&dir, vec![PostStep::Trim { start: Some(1.0), end: None }]);
+
+        let mut path = video.to_string_lossy().to_string();
+        processor.run(&mut path, CollisionPolicy::Skip, None, &CancellationToken::new(), |_| {}).await.unwrap();
+
+        assert_eq!(path, video.to_string_lossy());
+        assert_eq!(std::fs::read(&video).unwrap(), b"video");
+        assert!(!dir.join("video.postprocess.mp4").exists());
+        assert!(!dir.join(format!("video.postprocess.mp4{}", PART_FILE_SUFFIX)).exists());
+    }
+
+    #[tokio::test]
+    async fn reports_ffmpeg_failures() {
+        let dir = temp_dir("ffmpeg-failure");
+        let video = dir.join("video.mp4");
+        std::fs::write(&video, b"video").unwrap();
+        // 执行失败时不留下输出文件与占用文件名的临时文件
+        let mut processor = processor(&dir, vec![PostStep::ExtractMp3 { bitrate: 192 }]);
+        let ffmpeg = dir.join("failing");
+        std::fs::write(&ffmpeg, FAKE_FFMPEG.replace("cp \"$input\" \"$output\"", "echo 'Conversion failed!' >&2; exit 1")).unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        processor.ffmpeg = ffmpeg;
+
+        let mut path = video.to_string_lossy().to_string();
+        let error = processor.run(&mut path, CollisionPolicy::AppendCounter, None, &CancellationToken::new(), |_| {}).await.unwrap_err();
+        assert!(error.to_string().contains("Conversion failed!"));
+        assert!(!dir.join("video.mp3").exists());
+        assert!(!dir.join(format!("video.mp3{}", PART_FILE_SUFFIX)).exists());
+        assert!(!dir.join("video.postprocess.mp3").exists());
+    }
+}
@@ -0,0 +1,112 @@
+use reqwest::{Client, Response, StatusCode, Url, header::HeaderMap};
+use anyhow::Result;
+use super::{header_value, verify};
+
+/// 探测得到的文件信息, 用于创建Downloader
+#[derive(Debug)]
+pub struct Probe {
+    // 重定向后的最终地址
+    pub url: Url,
+    pub headers: HeaderMap,
+    // 服务器没有返回文件大小时为None
+    pub filesize: Option<u64>,
+    pub support_range: bool,
+    // headers是否描述完整文件, 206响应中的Content-MD5只对应探测的那一个字节
+    pub full_response: bool,
+    // 服务器忽略了Range请求并返回完整文件时保留响应, 下载时直接读取, 不再重新请求
+    pub body: Option<Response>,
+}
+
+/// HEAD响应的Content-Length需要直接读取响应头, reqwest按响应体计算的长度为0
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    header_value(headers, "Content-Length").and_then(|v| v.trim().parse().ok())
+}
+
+fn accept_ranges(headers: &HeaderMap) -> Option<String> {
+    header_value(headers, "Accept-Ranges").map(|v| v.trim().to_ascii_lowercase())
+}
+
+impl Probe {
+
+    /// 分段下载不需要探测, 大小在下载完成前未知
+    pub fn unknown(url: Url) -> Self {
+        Self {
+            url,
+            headers: HeaderMap::new(),
+            filesize: None,
+            support_range: false,
+            full_response: false,
+            body: None,
+        }
+    }
+
+    /// 复制探测得到的信息, 不包含响应体
+    pub fn detached(&self) -> Self {
+        Self {
+            url: self.url.clone(),
+            headers: self.headers.clone(),
+            filesize: self.filesize,
+            support_range: self.support_range,
+            full_response: self.full_response,
+            body: None,
+        }
+    }
+
+    /// 先发送HEAD请求, 能确定大小与range支持时直接使用
+    /// 否则请求第一个字节: 返回206时从Content-Range读取总大小, 返回200说明服务器不支持range
+    pub async fn run(client: &Client, url: &str) -> Result<Self> {
+        if let Ok(response) = client.head(url).send().await {
+            if response.status().is_success() {
+                let filesize = content_length(response.headers());
+                let accept_ranges = accept_ranges(response.headers());
+                let support_range = match (filesize, accept_ranges.as_deref()) {
+                    (_, Some("none")) => Some(false),
+                    (Some(size), Some("bytes")) if size > 0 => Some(true),
+                    _ => None,
+                };
+                if let Some(support_range) = support_range {
+                    return Ok(Self {
+                        url: response.url().clone(),
+                        headers: response.headers().clone(),
+                        filesize,
+                        support_range,
+                        full_response: true,
+                        body: None,
+                    });
+                }
+            }
+        }
+
+        let response = client.get(url)
+            .header("Range", "bytes=0-0")
+            .send()
+            .await?
+            .error_for_status()?;
+        let url = response.url().clone();
+        let headers = response.headers().clone();
+
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            let filesize = header_value(&headers, "Content-Range")
+                .as_deref()
+                .and_then(verify::parse_content_range)
+                .and_then(|(start, _, total)| if start == 0 { total } else { None });
+            return Ok(Self {
+                url,
+                headers,
+                filesize,
+                support_range: filesize.is_some(),
+                full_response: false,
+                body: None,
+            });
+        }
+
+        Ok(Self {
+            url,
+            filesize: content_length(&headers),
+            headers,
+            support_range: false,
+            full_response: true,
+            body: Some(response),
+        })
+    }
+}
@@ -0,0 +1,267 @@
+use std::{sync::atomic::{AtomicU8, AtomicU64, Ordering}, time::Instant};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkStatus {
+    Pending,
+    Downloading,
+    Retrying,
+    Completed,
+    Failed,
+}
+
+impl ChunkStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ChunkStatus::Downloading,
+            2 => ChunkStatus::Retrying,
+            3 => ChunkStatus::Completed,
+            4 => ChunkStatus::Failed,
+            _ => ChunkStatus::Pending,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadStatus {
+    Pending,
+    Downloading,
+    Verifying,
+    Completed,
+    Failed,
+    Paused,
+    Cancelled,
+    // 下载完成后转封装或合并音视频为MP4
+    Remuxing,
+    // 下载完成后执行ffmpeg后处理
+    PostProcessing,
+    // 下载已完成, 但后处理失败, 保留最后一个成功步骤的文件
+    PostProcessFailed,
+}
+
+impl DownloadStatus {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => DownloadStatus::Downloading,
+            2 => DownloadStatus::Verifying,
+            3 => DownloadStatus::Completed,
+            4 => DownloadStatus::Failed,
+            5 => DownloadStatus::Paused,
+            6 => DownloadStatus::Cancelled,
+            7 => DownloadStatus::Remuxing,
+            8 => DownloadStatus::PostProcessing,
+            9 => DownloadStatus::PostProcessFailed,
+            _ => DownloadStatus::Pending,
+        }
+    }
+}
+
+/// 单个分片的实时进度, 下载过程中只通过原子操作更新
+#[derive(Debug)]
+pub struct RangeProgress {
+    offset: AtomicU64,
+    // 后半部分被其他worker拆走时会变小
+    end: AtomicU64,
+    status: AtomicU8,
+}
+
+impl RangeProgress {
+
+    pub fn new(offset: u64, end: u64) -> Self {
+        Self {
+            offset: AtomicU64::new(offset),
+            end: AtomicU64::new(end),
+            status: AtomicU8::new(ChunkStatus::Pending as u8),
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Acquire)
+    }
+
+    pub fn set_offset(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Release);
+    }
+
+    pub fn add(&self, len: u64) {
+        self.offset.fetch_add(len, Ordering::AcqRel);
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end.load(Ordering::Acquire)
+    }
+
+    pub fn set_end(&self, end: u64) {
+        self.end.store(end, Ordering::Release);
+    }
+
+    /// 还未写入文件的字节数
+    pub fn remaining(&self) -> u64 {
+        (self.end() + 1).saturating_sub(self.offset())
+    }
+
+    pub fn status(&self) -> ChunkStatus {
+        ChunkStatus::from_u8(self.status.load(Ordering::Acquire))
+    }
+
+    pub fn set_status(&self, status: ChunkStatus) {
+        self.status.store(status as u8, Ordering::Release);
+    }
+}
+
+/// HLS分段的实时进度, 分段大小在下载完成前未知, 只记录已收到的字节数
+#[derive(Debug, Default)]
+pub struct SegmentState {
+    downloaded: AtomicU64,
+    status: AtomicU8,
+}
+
+impl SegmentState {
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Acquire)
+    }
+
+    pub fn add(&self, len: u64) {
+        self.downloaded.fetch_add(len, Ordering::AcqRel);
+    }
+
+    /// 清空已收到的字节数, 返回清空前的值
+    pub fn reset(&self) -> u64 {
+        self.downloaded.swap(0, Ordering::AcqRel)
+    }
+
+    pub fn status(&self) -> ChunkStatus {
+        ChunkStatus::from_u8(self.status.load(Ordering::Acquire))
+    }
+
+    pub fn set_status(&self, status: ChunkStatus) {
+        self.status.store(status as u8, Ordering::Release);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentProgress {
+    pub index: usize,
+    pub downloaded: u64,
+    pub status: ChunkStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkProgress {
+    pub start: u64,
+    pub end: u64,
+    pub downloaded: u64,
+    pub status: ChunkStatus,
+}
+
+/// 后处理步骤的进度, index从0开始
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepProgress {
+    pub index: usize,
+    pub count: usize,
+    pub name: String,
+    // 0-100, 媒体时长未知时为空
+    pub percentage: Option<u8>,
+}
+
+/// 通过Downloader::subscribe推送给调用方的进度快照, 速度单位为字节/秒, eta单位为秒
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Progress {
+    pub status: DownloadStatus,
+    pub downloaded: u64,
+    // 服务器没有返回文件大小时为空, 此时只能显示已下载的字节数
+    pub total_size: Option<u64>,
+    pub chunks: Vec<ChunkProgress>,
+    // HLS下载时每个分段的进度
+    pub segments: Vec<SegmentProgress>,
+    // DASH下载时每个音视频轨道的进度
+    pub tracks: Vec<Progress>,
+    // 下载完成后正在执行的后处理步骤, 后处理失败时为失败的步骤
+    pub step: Option<StepProgress>,
+    // 后处理失败的原因
+    pub post_process_error: Option<String>,
+    // 当前使用的连接数
+    pub connections: usize,
+    pub speed: u64,
+    pub average_speed: u64,
+    pub eta: Option<u64>,
+}
+
+impl Progress {
+
+    pub fn new(total_size: Option<u64>) -> Self {
+        Self {
+            status: DownloadStatus::Pending,
+            downloaded: 0,
+            total_size,
+            chunks: vec![],
+            segments: vec![],
+            tracks: vec![],
+            step: None,
+            post_process_error: None,
+            connections: 0,
+            speed: 0,
+            average_speed: 0,
+            eta: None,
+        }
+    }
+
+    pub fn percentage(&self) -> u8 {
+        match self.total_size {
+            Some(total_size) if total_size > 0 => {
+                std::cmp::min((self.downloaded as f64 * 100.0 / total_size as f64).round() as u64, 100) as u8
+            },
+            // HLS的总大小未知, 按已完成的分段数计算
+            _ if !self.segments.is_empty() => {
+                let completed = self.segments.iter().filter(|s| s.status == ChunkStatus::Completed).count();
+                (completed * 100 / self.segments.len()) as u8
+            },
+            _ => 0,
+        }
+    }
+}
+
+/// 根据相邻两次采样计算瞬时速度(指数平滑)与本次运行的平均速度
+#[derive(Debug)]
+pub struct SpeedMeter {
+    started: Instant,
+    start_bytes: u64,
+    last: Instant,
+    last_bytes: u64,
+    speed: f64,
+}
+
+impl SpeedMeter {
+
+    pub fn new(bytes: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            start_bytes: bytes,
+            last: now,
+            last_bytes: bytes,
+            speed: 0.0,
+        }
+    }
+
+    pub fn sample(&mut self, bytes: u64) -> (u64, u64) {
+        let now = Instant::now();
+        let interval = now.duration_since(self.last).as_secs_f64();
+        if interval > 0.0 {
+            let instant_speed = bytes.saturating_sub(self.last_bytes) as f64 / interval;
+            self.speed = if self.speed == 0.0 { instant_speed } else { self.speed * 0.7 + instant_speed * 0.3 };
+            self.last = now;
+            self.last_bytes = bytes;
+        }
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let average = if elapsed > 0.0 {
+            bytes.saturating_sub(self.start_bytes) as f64 / elapsed
+        } else {
+            0.0
+        };
+        (self.speed as u64, average as u64)
+    }
+}
@@ -0,0 +1,123 @@
+use std::{sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+use chrono::NaiveTime;
+use serde::{Serialize, Deserialize};
+use tokio::time::sleep;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+struct LimiterInner {
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+/// 令牌桶限速器, rate为每秒字节数, 0表示不限速, 可以在下载过程中随时修改
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    inner: Arc<LimiterInner>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl RateLimiter {
+
+    pub fn new(rate: u64) -> Self {
+        Self {
+            inner: Arc::new(LimiterInner {
+                rate: AtomicU64::new(rate),
+                bucket: Mutex::new(Bucket { tokens: 0.0, last: Instant::now() }),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.inner.rate.load(Ordering::Acquire)
+    }
+
+    /// 修改速率时清空桶内令牌, 避免按旧速率积累的令牌或欠账影响新速率
+    pub fn set_rate(&self, rate: u64) {
+        if self.inner.rate.swap(rate, Ordering::AcqRel) != rate {
+            let mut bucket = self.inner.bucket.lock().unwrap();
+            bucket.tokens = 0.0;
+            bucket.last = Instant::now();
+        }
+    }
+
+    /// 取走len个令牌, 令牌不足时记为欠账并等待到欠账还清
+    pub async fn acquire(&self, len: u64) {
+        let wait = {
+            let rate = self.rate();
+            if rate == 0 {
+                return;
+            }
+            let mut bucket = self.inner.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * rate as f64;
+            // 最多积累1秒的令牌
+            bucket.tokens = (bucket.tokens + refill).min(rate as f64);
+            bucket.last = now;
+            bucket.tokens -= len as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        };
+        sleep(wait).await;
+    }
+}
+
+/// 按时间段限速, start/end格式为HH:MM, end早于start表示跨越零点
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    pub start: String,
+    pub end: String,
+    pub limit: u64,
+}
+
+impl ScheduleRule {
+
+    fn parse_time(value: &str) -> Option<NaiveTime> {
+        NaiveTime::parse_from_str(value.trim(), "%H:%M").ok()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        Self::parse_time(&self.start).is_some() && Self::parse_time(&self.end).is_some()
+    }
+
+    fn contains(&self, now: NaiveTime) -> bool {
+        match (Self::parse_time(&self.start), Self::parse_time(&self.end)) {
+            (Some(start), Some(end)) if start <= end => now >= start && now < end,
+            (Some(start), Some(end)) => now >= start || now < end,
+            _ => false,
+        }
+    }
+}
+
+/// 全局限速配置, 不在任何时间段内时使用default_limit
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BandwidthSchedule {
+    pub default_limit: u64,
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl BandwidthSchedule {
+
+    pub fn is_valid(&self) -> bool {
+        self.rules.iter().all(|rule| rule.is_valid())
+    }
+
+    /// 第一条包含当前时间的规则生效
+    pub fn current_limit(&self, now: NaiveTime) -> u64 {
+        self.rules.iter()
+            .find(|rule| rule.contains(now))
+            .map_or(self.default_limit, |rule| rule.limit)
+    }
+}
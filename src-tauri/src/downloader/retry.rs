@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+/// 分片下载失败后的重试策略, 重试间隔按指数增长
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    /// 第attempt次重试前需要等待的时间
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        std::cmp::min(self.initial_backoff.saturating_mul(factor), self.max_backoff)
+    }
+}
@@ -0,0 +1,200 @@
+use std::{cmp::Reverse, collections::HashMap};
+use aes::Aes128;
+use cbc::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use reqwest::{Client, Url};
+use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
+use anyhow::Result;
+use thiserror::Error;
+use super::progress::SegmentState;
+
+#[derive(Error, Debug)]
+pub enum SegmentError {
+
+    #[error("解密密钥无效")]
+    InvalidKeyError,
+
+    #[error("分段解密失败")]
+    DecryptError,
+}
+
+/// 选择清晰度的条件, HLS与DASH共用
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamOptions {
+    pub max_bandwidth: Option<u64>,
+    pub max_height: Option<u32>,
+    // 优先使用的编码, 按前缀匹配, 越靠前越优先, 例如: ["avc1", "hev1"]
+    pub codecs: Vec<String>,
+}
+
+impl StreamOptions {
+
+    /// 在不超过限制的清晰度中按编码偏好与码率选择, 都超过限制时选择码率最低的
+    /// info返回码率, 视频高度与编码
+    pub fn select<'a, T>(&self, items: &'a [T], info: impl Fn(&T) -> (u64, Option<u32>, Option<String>)) -> Option<&'a T> {
+        let codec_rank = |codecs: Option<String>| {
+            let codecs = codecs.unwrap_or_default();
+            self.codecs.iter()
+                .position(|prefix| codecs.split(',').any(|c| c.trim().starts_with(prefix.as_str())))
+                .unwrap_or(self.codecs.len())
+        };
+        items.iter()
+            .filter(|item| {
+                let (bandwidth, height, _) = info(item);
+                self.max_bandwidth.map_or(true, |max| bandwidth <= max)
+                    && self.max_height.map_or(true, |max| height.map_or(true, |height| height <= max))
+            })
+            .min_by_key(|item| {
+                let (bandwidth, _, codecs) = info(item);
+                (codec_rank(codecs), Reverse(bandwidth))
+            })
+            .or_else(|| items.iter().min_by_key(|item| info(item).0))
+    }
+}
+
+/// 分段的AES-128密钥
+#[derive(Debug, Clone)]
+pub struct SegmentKey {
+    pub url: Url,
+    // 没有指定IV时以分段的序号作为IV
+    pub iv: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub url: Url,
+    pub sequence: u64,
+    // 长度与起始位置
+    pub byte_range: Option<(u64, u64)>,
+    pub key: Option<SegmentKey>,
+}
+
+/// 用AES-128-CBC解密分段, 去掉PKCS7填充
+pub fn decrypt(key: &[u8; 16], iv: &[u8; 16], mut data: Vec<u8>) -> Result<Vec<u8>, SegmentError> {
+    let len = cbc::Decryptor::<Aes128>::new(key.into(), iv.into())
+        .decrypt_padded_mut::<Pkcs7>(&mut data)
+        .map_err(|_| SegmentError::DecryptError)?
+        .len();
+    data.truncate(len);
+    Ok(data)
+}
+
+/// 由多个分段组成的流与下载进度, 分段按顺序追加写入同一个文件
+#[derive(Debug)]
+pub struct SegmentStream {
+    pub segments: Vec<Segment>,
+    pub states: Vec<SegmentState>,
+    // 已经下载过的密钥, 同一个密钥通常用于所有分段
+    keys: Mutex<HashMap<Url, [u8; 16]>>,
+    // 已按顺序写入文件的分段数与字节数, 暂停后从这里继续
+    written: std::sync::Mutex<(usize, u64)>,
+}
+
+impl SegmentStream {
+
+    pub fn new(segments: Vec<Segment>) -> Self {
+        let states = segments.iter().map(|_| SegmentState::default()).collect();
+        Self {
+            segments,
+            states,
+            keys: Mutex::new(HashMap::new()),
+            written: std::sync::Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn written(&self) -> (usize, u64) {
+        *self.written.lock().unwrap()
+    }
+
+    pub fn set_written(&self, count: usize, len: u64) {
+        *self.written.lock().unwrap() = (count, len);
+    }
+
+    async fn key(&self, client: &Client, url: &Url) -> Result<[u8; 16]> {
+        let mut keys = self.keys.lock().await;
+        if let Some(key) = keys.get(url) {
+            return Ok(*key);
+        }
+        let bytes = client.get(url.as_str())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        if bytes.len() != 16 {
+            return Err(SegmentError::InvalidKeyError.into());
+        }
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&bytes);
+        keys.insert(url.clone(), key);
+        Ok(key)
+    }
+
+    /// 按分段的密钥解密, 没有加密时原样返回
+    pub async fn decrypt(&self, client: &Client, index: usize, data: Vec<u8>) -> Result<Vec<u8>> {
+        let segment = &self.segments[index];
+        let key = match segment.key.as_ref() {
+            Some(key) => key,
+            None => return Ok(data),
+        };
+        let iv = key.iv.unwrap_or_else(|| (segment.sequence as u128).to_be_bytes());
+        let secret = self.key(client, &key.url).await?;
+        Ok(decrypt(&secret, &iv, data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::hls::{parse_playlist, Playlist};
+    use super::super::test_server::TestServer;
+
+    const KEY: &[u8; 16] = include_bytes!("fixtures/segment.key");
+    const PLAIN: &[u8] = include_bytes!("fixtures/segment.bin");
+    // openssl enc -aes-128-cbc -K 000102030405060708090a0b0c0d0e0f -iv 00000000000000000000000000000007
+    const ENCRYPTED: &[u8] = include_bytes!("fixtures/segment.enc");
+
+    #[test]
+    fn decrypts_aes_128_cbc() {
+        let mut iv = [0u8; 16];
+        iv[15] = 7;
+        assert_eq!(decrypt(KEY, &iv, ENCRYPTED.to_vec()).unwrap(), PLAIN);
+
+        // 长度不是块大小的整数倍
+        let truncated = ENCRYPTED[..ENCRYPTED.len() - 1].to_vec();
+        assert!(matches!(decrypt(KEY, &iv, truncated), Err(SegmentError::DecryptError)));
+        // 不是用该密钥加密的数据, 解密后的填充无效
+        assert!(matches!(decrypt(KEY, &iv, PLAIN[..16 * 23].to_vec()), Err(SegmentError::DecryptError)));
+    }
+
+    #[tokio::test]
+    async fn decrypts_segments_with_sequence_iv() {
+        // 测试服务器对所有地址返回同一个文件, 这里只用来提供密钥
+        let server = TestServer::start(KEY.to_vec(), false).await;
+        let base = Url::parse(&server.url("index.m3u8")).unwrap();
+        let segments = match parse_playlist(include_str!("fixtures/encrypted.m3u8"), &base).unwrap() {
+            Playlist::Media(playlist) => playlist.segments,
+            Playlist::Master(_) => panic!("expected a media playlist"),
+        };
+        let stream = SegmentStream::new(segments);
+        let client = Client::new();
+
+        // 第一个分段没有IV, 以序号7作为IV
+        assert_eq!(stream.decrypt(&client, 0, ENCRYPTED.to_vec()).await.unwrap(), PLAIN);
+        // METHOD=NONE的分段原样返回
+        assert_eq!(stream.decrypt(&client, 2, PLAIN.to_vec()).await.unwrap(), PLAIN);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_keys() {
+        let server = TestServer::start(b"not a key".to_vec(), false).await;
+        let base = Url::parse(&server.url("index.m3u8")).unwrap();
+        let segments = match parse_playlist(include_str!("fixtures/encrypted.m3u8"), &base).unwrap() {
+            Playlist::Media(playlist) => playlist.segments,
+            Playlist::Master(_) => panic!("expected a media playlist"),
+        };
+        let result = SegmentStream::new(segments).decrypt(&Client::new(), 0, ENCRYPTED.to_vec()).await;
+        assert!(matches!(result.unwrap_err().downcast_ref::<SegmentError>(), Some(SegmentError::InvalidKeyError)));
+    }
+}
@@ -0,0 +1,47 @@
+use std::{io, path::Path};
+use anyhow::Result;
+use super::DownloadError;
+
+// 磁盘已满时写入返回的系统错误码
+#[cfg(unix)]
+const DISK_FULL_ERRORS: &[i32] = &[
+    28, // ENOSPC
+];
+
+#[cfg(windows)]
+const DISK_FULL_ERRORS: &[i32] = &[
+    39,  // ERROR_HANDLE_DISK_FULL
+    112, // ERROR_DISK_FULL
+];
+
+#[cfg(not(any(unix, windows)))]
+const DISK_FULL_ERRORS: &[i32] = &[];
+
+/// 目标路径所在磁盘的可用空间, 路径还不存在时使用最近的已存在的上级目录
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let dir = path.ancestors()
+        .find(|p| p.is_dir())
+        .unwrap_or_else(|| Path::new("."));
+    fs2::available_space(dir)
+}
+
+/// 可用空间不足required字节时返回InsufficientSpace
+pub fn check_space(path: &Path, required: u64) -> Result<()> {
+    let available = available_space(path)?;
+    if required > available {
+        return Err(DownloadError::InsufficientSpace { required, available }.into());
+    }
+    Ok(())
+}
+
+pub fn is_disk_full(e: &io::Error) -> bool {
+    e.raw_os_error().map_or(false, |code| DISK_FULL_ERRORS.contains(&code))
+}
+
+/// 磁盘已满无法通过重试恢复, 转换为DiskFull以便中止下载
+pub fn write_error(e: io::Error) -> anyhow::Error {
+    if is_disk_full(&e) {
+        return DownloadError::DiskFull.into();
+    }
+    e.into()
+}
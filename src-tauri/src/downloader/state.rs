@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+
+const STATE_FILE_SUFFIX: &'static str = ".state";
+
+/// 单个分片的下载进度, offset为下一个待写入字节的绝对位置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RangeState {
+    pub start: u64,
+    pub end: u64,
+    pub offset: u64,
+}
+
+impl RangeState {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self { start, end, offset: start }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.offset - self.start
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset > self.end
+    }
+}
+
+/// 保存在目标文件旁边的断点续传状态文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadState {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub total_size: u64,
+    pub ranges: Vec<RangeState>,
+}
+
+impl DownloadState {
+
+    pub fn new(url: &str, etag: Option<String>, last_modified: Option<String>, total_size: u64, ranges: Vec<(u64, u64)>) -> Self {
+        Self {
+            url: url.to_string(),
+            etag,
+            last_modified,
+            total_size,
+            ranges: ranges.into_iter().map(|(start, end)| RangeState::new(start, end)).collect(),
+        }
+    }
+
+    pub fn path_for(savepath: &str) -> PathBuf {
+        PathBuf::from(format!("{}{}", savepath, STATE_FILE_SUFFIX))
+    }
+
+    /// 读取状态文件, 文件不存在或内容无法解析时返回None
+    pub async fn load(savepath: &str) -> Option<Self> {
+        let data = tokio::fs::read(Self::path_for(savepath)).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// 先写入临时文件再重命名, 避免中途退出留下半截的状态文件
+    pub async fn save(&self, savepath: &str) -> Result<()> {
+        let path = Self::path_for(savepath);
+        let tmp_path = path.with_extension("state.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+
+    pub async fn remove(savepath: &str) {
+        let _ = tokio::fs::remove_file(Self::path_for(savepath)).await;
+    }
+
+    /// 远程文件的URL, 大小与校验信息都一致时才能继续使用此状态
+    pub fn matches(&self, url: &str, total_size: u64, etag: &Option<String>, last_modified: &Option<String>) -> bool {
+        if self.url != url || self.total_size != total_size {
+            return false;
+        }
+        match (&self.etag, etag) {
+            (Some(a), Some(b)) if a != b => return false,
+            _ => {},
+        }
+        match (&self.last_modified, last_modified) {
+            (Some(a), Some(b)) if a != b => return false,
+            _ => {},
+        }
+        self.validator().is_some()
+    }
+
+    /// If-Range请求头的值, 弱ETag不能用于If-Range, 此时退回到Last-Modified
+    pub fn validator(&self) -> Option<String> {
+        match &self.etag {
+            Some(etag) if !etag.starts_with("W/") => Some(etag.clone()),
+            _ => self.last_modified.clone(),
+        }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.ranges.iter().map(|r| r.downloaded()).sum()
+    }
+
+    /// 将分片剩余部分的后半段拆分为新分片, 剩余不足两倍min_size时不拆分
+    pub fn split(&mut self, index: usize, min_size: u64) -> Option<usize> {
+        let range = &mut self.ranges[index];
+        let remaining = (range.end + 1).saturating_sub(range.offset);
+        if remaining < min_size * 2 {
+            return None;
+        }
+        let mid = range.offset + remaining / 2;
+        let end = range.end;
+        range.end = mid - 1;
+        self.ranges.push(RangeState::new(mid, end));
+        Some(self.ranges.len() - 1)
+    }
+
+    pub fn reset(&mut self) {
+        for range in self.ranges.iter_mut() {
+            range.offset = range.start;
+        }
+    }
+}
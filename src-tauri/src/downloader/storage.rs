@@ -0,0 +1,344 @@
+use std::{fmt, fs, io, sync::{Arc, Mutex, RwLock}};
+use futures::future::BoxFuture;
+use serde::{Serialize, Deserialize};
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+/// 下载数据的存储后端, 所有worker通过定位读写共用同一个存储
+pub trait Storage: Send + Sync + fmt::Debug {
+
+    fn set_len(&self, size: u64) -> BoxFuture<'_, io::Result<()>>;
+
+    /// 写入全部数据后把缓冲区交还给调用方复用
+    fn write_at(&self, bytes: Vec<u8>, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>>;
+
+    /// 读取最多len个字节, 到达末尾时返回的数据会更短
+    fn read_at(&self, len: usize, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>>;
+
+    fn len(&self) -> BoxFuture<'_, io::Result<u64>>;
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>>;
+
+    /// 下载完成后把数据保存到目标文件, 之后不能再读写
+    fn persist<'a>(&'a self, partpath: &'a str, savepath: &'a str) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// 运行时选择的存储后端
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    // 在阻塞线程池中定位读写临时文件
+    Std,
+    // Linux下通过io_uring读写, 需要启用io-uring feature, 否则退回到Std
+    IoUring,
+    // 数据只保存在内存中, 完成后一次写入目标文件, 不支持断点续传
+    Memory,
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        StorageBackend::Std
+    }
+}
+
+impl StorageBackend {
+
+    /// 数据是否写入临时文件, 只有这样才能在重启后断点续传
+    pub fn is_persistent(&self) -> bool {
+        !matches!(self, StorageBackend::Memory)
+    }
+
+    /// 创建新的存储并预分配空间, 文件系统不支持预分配时只设置文件长度
+    pub fn create(&self, filepath: &str, size: u64) -> io::Result<Arc<dyn Storage>> {
+        if *self == StorageBackend::Memory {
+            return Ok(Arc::new(MemoryStorage { data: Mutex::new(vec![0; size as usize]) }));
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(filepath)?;
+        if fs2::FileExt::allocate(&file, size).is_err() {
+            file.set_len(size)?;
+        }
+        self.wrap_file(file)
+    }
+
+    /// 打开已存在的临时文件继续写入, 不会清空已有内容
+    pub fn open(&self, filepath: &str) -> io::Result<Arc<dyn Storage>> {
+        if *self == StorageBackend::Memory {
+            return Ok(Arc::new(MemoryStorage::default()));
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(filepath)?;
+        self.wrap_file(file)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn wrap_file(&self, file: fs::File) -> io::Result<Arc<dyn Storage>> {
+        match self {
+            StorageBackend::IoUring => Ok(Arc::new(uring::UringStorage::new(file)?)),
+            _ => Ok(Arc::new(StdStorage::new(file))),
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    fn wrap_file(&self, file: fs::File) -> io::Result<Arc<dyn Storage>> {
+        Ok(Arc::new(StdStorage::new(file)))
+    }
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &fs::File, mut bytes: &[u8], mut offset: u64) -> Result<(), io::Error> {
+    use std::os::windows::fs::FileExt;
+    while !bytes.is_empty() {
+        let n = file.seek_write(bytes, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        bytes = &bytes[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &fs::File, bytes: &[u8], offset: u64) -> Result<(), io::Error> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(bytes, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> Result<usize, io::Error> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[cfg(unix)]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> Result<usize, io::Error> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "storage is already persisted")
+}
+
+/// 在阻塞线程池中定位读写临时文件
+#[derive(Debug)]
+pub struct StdStorage {
+    // persist时关闭文件, Windows下打开的文件无法重命名
+    file: RwLock<Option<Arc<fs::File>>>,
+}
+
+impl StdStorage {
+
+    pub fn new(file: fs::File) -> Self {
+        Self { file: RwLock::new(Some(Arc::new(file))) }
+    }
+
+    fn file(&self) -> io::Result<Arc<fs::File>> {
+        self.file.read().unwrap().clone().ok_or_else(closed)
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&fs::File) -> io::Result<T> + Send + 'static,
+    {
+        let file = self.file()?;
+        tokio::task::spawn_blocking(move || f(&file)).await?
+    }
+}
+
+impl Storage for StdStorage {
+
+    fn set_len(&self, size: u64) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.blocking(move |file| file.set_len(size)))
+    }
+
+    fn write_at(&self, bytes: Vec<u8>, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(self.blocking(move |file| write_all_at(file, &bytes, offset).map(|_| bytes)))
+    }
+
+    fn read_at(&self, len: usize, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(self.blocking(move |file| {
+            let mut buf = vec![0u8; len];
+            let mut read = 0;
+            while read < len {
+                match read_at(file, &mut buf[read..], offset + read as u64)? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            buf.truncate(read);
+            Ok(buf)
+        }))
+    }
+
+    fn len(&self) -> BoxFuture<'_, io::Result<u64>> {
+        Box::pin(self.blocking(|file| Ok(file.metadata()?.len())))
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.blocking(|file| file.sync_all()))
+    }
+
+    fn persist<'a>(&'a self, partpath: &'a str, savepath: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.file.write().unwrap().take();
+            tokio::fs::rename(partpath, savepath).await
+        })
+    }
+}
+
+/// 数据只保存在内存中, 用于测试与不希望产生临时文件的小文件
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    data: Mutex<Vec<u8>>,
+}
+
+impl Storage for MemoryStorage {
+
+    fn set_len(&self, size: u64) -> BoxFuture<'_, io::Result<()>> {
+        self.data.lock().unwrap().resize(size as usize, 0);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn write_at(&self, bytes: Vec<u8>, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        {
+            let mut data = self.data.lock().unwrap();
+            let offset = offset as usize;
+            let end = offset + bytes.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[offset..end].copy_from_slice(&bytes);
+        }
+        Box::pin(async { Ok(bytes) })
+    }
+
+    fn read_at(&self, len: usize, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        let bytes = {
+            let data = self.data.lock().unwrap();
+            let start = std::cmp::min(offset as usize, data.len());
+            let end = std::cmp::min(start + len, data.len());
+            data[start..end].to_vec()
+        };
+        Box::pin(async { Ok(bytes) })
+    }
+
+    fn len(&self) -> BoxFuture<'_, io::Result<u64>> {
+        let len = self.data.lock().unwrap().len() as u64;
+        Box::pin(async move { Ok(len) })
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// 写入目标文件, 并删除占用文件名时创建的空临时文件
+    fn persist<'a>(&'a self, partpath: &'a str, savepath: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        let data = std::mem::take(&mut *self.data.lock().unwrap());
+        Box::pin(async move {
+            tokio::fs::write(savepath, data).await?;
+            let _ = tokio::fs::remove_file(partpath).await;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+    use super::super::test_server::temp_dir;
+
+    // 需要测试的后端, io_uring只在启用feature时测试, 否则会退回到Std
+    fn backends() -> Vec<StorageBackend> {
+        let mut backends = vec![StorageBackend::Std, StorageBackend::Memory];
+        if cfg!(all(target_os = "linux", feature = "io-uring")) {
+            backends.push(StorageBackend::IoUring);
+        }
+        backends
+    }
+
+    fn filepath(backend: StorageBackend, name: &str) -> String {
+        temp_dir(&format!("storage-{:?}", backend)).join(name).to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_at_offsets() {
+        for backend in backends() {
+            let partpath = filepath(backend, "file.part");
+            let storage = backend.create(&partpath, 10).unwrap();
+            assert_eq!(storage.len().await.unwrap(), 10, "{:?}", backend);
+
+            // 乱序写入, 写入的缓冲区交还给调用方
+            assert_eq!(storage.write_at(b"world".to_vec(), 5).await.unwrap(), b"world", "{:?}", backend);
+            storage.write_at(b"hello".to_vec(), 0).await.unwrap();
+            storage.write_at(b"J".to_vec(), 0).await.unwrap();
+            storage.sync().await.unwrap();
+
+            assert_eq!(storage.read_at(10, 0).await.unwrap(), b"Jelloworld", "{:?}", backend);
+            assert_eq!(storage.read_at(3, 4).await.unwrap(), b"owo", "{:?}", backend);
+            // 到达末尾时返回的数据更短
+            assert_eq!(storage.read_at(10, 8).await.unwrap(), b"ld", "{:?}", backend);
+            assert!(storage.read_at(10, 20).await.unwrap().is_empty(), "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_len_truncates_and_extends() {
+        for backend in backends() {
+            let partpath = filepath(backend, "file.part");
+            let storage = backend.create(&partpath, 0).unwrap();
+            storage.write_at(b"0123456789".to_vec(), 0).await.unwrap();
+            assert_eq!(storage.len().await.unwrap(), 10, "{:?}", backend);
+
+            storage.set_len(4).await.unwrap();
+            assert_eq!(storage.len().await.unwrap(), 4, "{:?}", backend);
+            assert_eq!(storage.read_at(10, 0).await.unwrap(), b"0123", "{:?}", backend);
+
+            // 扩展的部分填充为0
+            storage.set_len(6).await.unwrap();
+            assert_eq!(storage.read_at(10, 0).await.unwrap(), b"0123\0\0", "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn persists_to_save_path() {
+        for backend in backends() {
+            let partpath = filepath(backend, "file.part");
+            let savepath = Path::new(&partpath).with_file_name("file.bin").to_string_lossy().to_string();
+            let storage = backend.create(&partpath, 5).unwrap();
+            storage.write_at(b"hello".to_vec(), 0).await.unwrap();
+            storage.persist(&partpath, &savepath).await.unwrap();
+
+            assert_eq!(std::fs::read(&savepath).unwrap(), b"hello", "{:?}", backend);
+            assert!(!Path::new(&partpath).exists(), "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn reopens_existing_data() {
+        for backend in backends().into_iter().filter(StorageBackend::is_persistent) {
+            let partpath = filepath(backend, "file.part");
+            let storage = backend.create(&partpath, 5).unwrap();
+            storage.write_at(b"hello".to_vec(), 0).await.unwrap();
+            storage.sync().await.unwrap();
+            drop(storage);
+
+            // 重新打开时不清空已有内容
+            let storage = backend.open(&partpath).unwrap();
+            assert_eq!(storage.len().await.unwrap(), 5, "{:?}", backend);
+            assert_eq!(storage.read_at(5, 0).await.unwrap(), b"hello", "{:?}", backend);
+        }
+    }
+}
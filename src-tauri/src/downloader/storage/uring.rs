@@ -0,0 +1,121 @@
+use std::{fs, io, rc::Rc, sync::Arc};
+use futures::future::BoxFuture;
+use tokio::sync::{mpsc, oneshot};
+use tokio_uring::buf::IoBuf;
+use super::Storage;
+
+enum Command {
+    Write { bytes: Vec<u8>, offset: u64, reply: oneshot::Sender<io::Result<Vec<u8>>> },
+    Read { len: usize, offset: u64, reply: oneshot::Sender<io::Result<Vec<u8>>> },
+    Sync { reply: oneshot::Sender<io::Result<()>> },
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "io_uring worker has stopped")
+}
+
+async fn write_all_at(file: &tokio_uring::fs::File, mut bytes: Vec<u8>, offset: u64) -> io::Result<Vec<u8>> {
+    let mut written = 0;
+    while written < bytes.len() {
+        let (res, slice) = file.write_at(bytes.slice(written..), offset + written as u64).await;
+        bytes = slice.into_inner();
+        match res? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => written += n,
+        }
+    }
+    Ok(bytes)
+}
+
+async fn read_at(file: &tokio_uring::fs::File, len: usize, offset: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let (res, buf) = file.read_at(Vec::with_capacity(len - data.len()), offset + data.len() as u64).await;
+        match res? {
+            0 => break,
+            _ => data.extend_from_slice(&buf),
+        }
+    }
+    Ok(data)
+}
+
+async fn handle(file: Rc<tokio_uring::fs::File>, command: Command) {
+    match command {
+        Command::Write { bytes, offset, reply } => {
+            let _ = reply.send(write_all_at(&file, bytes, offset).await);
+        },
+        Command::Read { len, offset, reply } => {
+            let _ = reply.send(read_at(&file, len, offset).await);
+        },
+        Command::Sync { reply } => {
+            let _ = reply.send(file.sync_all().await);
+        },
+    }
+}
+
+/// io_uring的文件只能在tokio_uring自己的运行时中使用, 这里在单独的线程中运行并通过channel提交读写
+/// 设置长度与读取元数据仍使用普通的文件句柄
+#[derive(Debug)]
+pub struct UringStorage {
+    tx: mpsc::UnboundedSender<Command>,
+    file: Arc<fs::File>,
+}
+
+impl UringStorage {
+
+    pub fn new(file: fs::File) -> io::Result<Self> {
+        let uring_file = file.try_clone()?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+        std::thread::Builder::new()
+            .name("download-io-uring".to_string())
+            .spawn(move || {
+                tokio_uring::start(async move {
+                    let file = Rc::new(tokio_uring::fs::File::from_std(uring_file));
+                    // 所有UringStorage的发送端被释放后退出线程
+                    while let Some(command) = rx.recv().await {
+                        tokio_uring::spawn(handle(file.clone(), command));
+                    }
+                })
+            })?;
+        Ok(Self { tx, file: Arc::new(file) })
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<io::Result<T>>) -> Command) -> io::Result<T> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(command(reply)).map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())?
+    }
+}
+
+impl Storage for UringStorage {
+
+    fn set_len(&self, size: u64) -> BoxFuture<'_, io::Result<()>> {
+        let file = self.file.clone();
+        Box::pin(async move { tokio::task::spawn_blocking(move || file.set_len(size)).await? })
+    }
+
+    fn write_at(&self, bytes: Vec<u8>, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(self.request(move |reply| Command::Write { bytes, offset, reply }))
+    }
+
+    fn read_at(&self, len: usize, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(self.request(move |reply| Command::Read { len, offset, reply }))
+    }
+
+    fn len(&self) -> BoxFuture<'_, io::Result<u64>> {
+        let file = self.file.clone();
+        Box::pin(async move { tokio::task::spawn_blocking(move || Ok(file.metadata()?.len())).await? })
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.request(|reply| Command::Sync { reply }))
+    }
+
+    /// 只在Linux下使用, 打开的文件不影响重命名
+    fn persist<'a>(&'a self, partpath: &'a str, savepath: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.sync().await?;
+            tokio::fs::rename(partpath, savepath).await
+        })
+    }
+}
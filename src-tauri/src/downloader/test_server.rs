@@ -0,0 +1,131 @@
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+// 同一进程中的测试各自使用不同的临时目录
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// 为一个测试创建空的临时目录
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "downloader-test-{}-{}-{}",
+        std::process::id(),
+        name,
+        TEMP_COUNTER.fetch_add(1, Ordering::SeqCst),
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[derive(Debug)]
+struct Resource {
+    body: Vec<u8>,
+    etag: String,
+    support_range: bool,
+}
+
+/// 测试用的本地HTTP服务器, 所有路径返回同一个文件, 支持HEAD, Range与If-Range
+#[derive(Debug, Clone)]
+pub struct TestServer {
+    addr: SocketAddr,
+    resource: Arc<Mutex<Resource>>,
+    // 收到的请求数
+    requests: Arc<AtomicUsize>,
+}
+
+impl TestServer {
+
+    pub async fn start(body: Vec<u8>, support_range: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Self {
+            addr: listener.local_addr().unwrap(),
+            resource: Arc::new(Mutex::new(Resource { body, etag: "\"v1\"".to_string(), support_range })),
+            requests: Arc::new(AtomicUsize::new(0)),
+        };
+        let s = server.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(s.clone().handle(stream));
+            }
+        });
+        server
+    }
+
+    /// 替换远程文件, 模拟下载过程中文件被更新
+    pub fn replace(&self, body: Vec<u8>, etag: &str) {
+        let mut resource = self.resource.lock().unwrap();
+        resource.body = body;
+        resource.etag = etag.to_string();
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path)
+    }
+
+    async fn handle(self, mut stream: TcpStream) {
+        let mut request = vec![];
+        let mut buf = [0u8; 4096];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let request = String::from_utf8_lossy(&request).to_string();
+        let mut lines = request.lines();
+        let mut parts = lines.next().unwrap_or_default().split(' ');
+        let method = parts.next().unwrap_or_default().to_string();
+        let headers: HashMap<String, String> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+
+        let (body, etag, support_range) = {
+            let resource = self.resource.lock().unwrap();
+            (resource.body.clone(), resource.etag.clone(), resource.support_range)
+        };
+        let total = body.len() as u64;
+        // If-Range与当前版本不一致时返回完整文件
+        let range = headers.get("range")
+            .filter(|_| support_range)
+            .filter(|_| headers.get("if-range").map_or(true, |v| *v == etag))
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| v.split_once('-'))
+            .and_then(|(start, end)| {
+                let start: u64 = start.parse().ok()?;
+                let end = match end {
+                    "" => total.saturating_sub(1),
+                    end => std::cmp::min(end.parse().ok()?, total.saturating_sub(1)),
+                };
+                if start <= end { Some((start, end)) } else { None }
+            });
+
+        let (status, content) = match range {
+            Some((start, end)) => ("206 Partial Content", &body[start as usize..=end as usize]),
+            None => ("200 OK", &body[..]),
+        };
+        let mut head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nETag: {}\r\nAccept-Ranges: {}\r\nConnection: close\r\n",
+            status,
+            content.len(),
+            etag,
+            if support_range { "bytes" } else { "none" },
+        );
+        if let Some((start, end)) = range {
+            head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n", start, end, total));
+        }
+        head.push_str("\r\n");
+        if stream.write_all(head.as_bytes()).await.is_err() {
+            return;
+        }
+        if method != "HEAD" {
+            let _ = stream.write_all(content).await;
+        }
+        let _ = stream.shutdown().await;
+    }
+}
@@ -0,0 +1,155 @@
+use md5::Md5;
+use sha2::{Sha256, Digest};
+use anyhow::Result;
+use super::{DownloadError, writer::FileWriter};
+
+/// 期望的文件摘要, 统一保存为小写十六进制字符串
+#[derive(Debug, Clone, PartialEq)]
+pub enum Checksum {
+    Md5(String),
+    Sha256(String),
+}
+
+impl Checksum {
+
+    pub fn hex(&self) -> &str {
+        match self {
+            Checksum::Md5(hex) => hex,
+            Checksum::Sha256(hex) => hex,
+        }
+    }
+
+    /// Content-MD5为摘要的base64编码
+    pub fn from_content_md5(value: &str) -> Option<Self> {
+        let bytes = base64::decode(value.trim()).ok()?;
+        if bytes.len() != 16 {
+            return None;
+        }
+        Some(Checksum::Md5(to_hex(&bytes)))
+    }
+
+    /// 部分对象存储的ETag就是文件的MD5, 分段上传产生的ETag带有"-"不能使用
+    pub fn from_etag(etag: &str) -> Option<Self> {
+        let value = etag.trim_start_matches("W/").trim_matches('"');
+        if value.len() == 32 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Some(Checksum::Md5(value.to_ascii_lowercase()));
+        }
+        None
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 解析Content-Range响应头, 例如: bytes 0-99/1000, 总大小未知时为None
+pub fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let value = value.trim().strip_prefix("bytes")?.trim();
+    let (range, total) = value.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?, total))
+}
+
+// 计算摘要时每次读取的字节数
+const HASH_READ_SIZE: usize = 1024 * 1024;
+
+async fn hash_file<D: Digest>(writer: &FileWriter) -> Result<String> {
+    let mut hasher = D::new();
+    let mut offset = 0;
+    loop {
+        let buf = writer.read_at(HASH_READ_SIZE, offset).await?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.update(&buf);
+        offset += buf.len() as u64;
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub async fn verify_size(writer: &FileWriter, expected: u64) -> Result<()> {
+    let actual = writer.len().await?;
+    if actual != expected {
+        return Err(DownloadError::SizeMismatch { expected, actual }.into());
+    }
+    Ok(())
+}
+
+/// 按顺序写入文件的同时计算摘要, 校验时不用再读取整个文件
+pub enum StreamHasher {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl StreamHasher {
+
+    pub fn new(checksum: &Checksum) -> Self {
+        match checksum {
+            Checksum::Md5(_) => StreamHasher::Md5(Md5::new()),
+            Checksum::Sha256(_) => StreamHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            StreamHasher::Md5(hasher) => hasher.update(bytes),
+            StreamHasher::Sha256(hasher) => hasher.update(bytes),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            StreamHasher::Md5(hasher) => to_hex(&hasher.finalize()),
+            StreamHasher::Sha256(hasher) => to_hex(&hasher.finalize()),
+        }
+    }
+}
+
+/// 有顺序写入时算好的摘要就直接使用, 分片下载或断点续传时重新读取整个文件
+pub async fn verify_checksum(writer: &FileWriter, checksum: &Checksum, hasher: Option<StreamHasher>) -> Result<()> {
+    let actual = match (hasher, checksum) {
+        (Some(hasher), _) => hasher.finalize(),
+        (None, Checksum::Md5(_)) => hash_file::<Md5>(writer).await?,
+        (None, Checksum::Sha256(_)) => hash_file::<Sha256>(writer).await?,
+    };
+    if !actual.eq_ignore_ascii_case(checksum.hex()) {
+        return Err(DownloadError::ChecksumMismatch.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::storage::StorageBackend;
+
+    #[tokio::test]
+    async fn stream_hasher_matches_file_hash() {
+        let body: Vec<u8> = (0..3 * HASH_READ_SIZE + 17).map(|i| (i % 251) as u8).collect();
+        let writer = FileWriter::create(StorageBackend::Memory, "", 0).unwrap();
+        writer.write_at(body.clone(), 0).await.unwrap();
+
+        let checksums = [
+            Checksum::Md5(hash_file::<Md5>(&writer).await.unwrap()),
+            Checksum::Sha256(hash_file::<Sha256>(&writer).await.unwrap()),
+        ];
+        for checksum in checksums.iter() {
+            let mut hasher = StreamHasher::new(checksum);
+            for chunk in body.chunks(4096) {
+                hasher.update(chunk);
+            }
+            verify_checksum(&writer, checksum, Some(hasher)).await.unwrap();
+            verify_checksum(&writer, checksum, None).await.unwrap();
+
+            // 少算一部分数据时校验失败
+            let mut hasher = StreamHasher::new(checksum);
+            hasher.update(&body[1..]);
+            let error = verify_checksum(&writer, checksum, Some(hasher)).await.unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(DownloadError::ChecksumMismatch)));
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::{io, sync::Arc};
+use super::storage::{Storage, StorageBackend};
+
+// 网络分片通常只有几KB, 攒够这么多字节后再写入文件
+const WRITE_BUFFER_SIZE: usize = 512 * 1024;
+
+/// 一次下载共用的存储, 所有worker通过定位写入同一个文件
+#[derive(Debug, Clone)]
+pub struct FileWriter {
+    storage: Arc<dyn Storage>,
+}
+
+impl FileWriter {
+
+    /// 打开已存在的文件继续写入, 不会清空已有内容
+    pub fn open(backend: StorageBackend, filepath: &str) -> Result<Self, io::Error> {
+        Ok(Self { storage: backend.open(filepath)? })
+    }
+
+    /// 创建新文件并预分配空间
+    pub fn create(backend: StorageBackend, filepath: &str, size: u64) -> Result<Self, io::Error> {
+        Ok(Self { storage: backend.create(filepath, size)? })
+    }
+
+    pub async fn set_len(&self, size: u64) -> Result<(), io::Error> {
+        self.storage.set_len(size).await
+    }
+
+    /// 写完后把缓冲区交还给调用方复用
+    pub async fn write_at(&self, bytes: Vec<u8>, offset: u64) -> Result<Vec<u8>, io::Error> {
+        self.storage.write_at(bytes, offset).await
+    }
+
+    pub async fn read_at(&self, len: usize, offset: u64) -> Result<Vec<u8>, io::Error> {
+        self.storage.read_at(len, offset).await
+    }
+
+    pub async fn len(&self) -> Result<u64, io::Error> {
+        self.storage.len().await
+    }
+
+    pub async fn sync(&self) -> Result<(), io::Error> {
+        self.storage.sync().await
+    }
+
+    /// 把临时文件保存为目标文件, 之后不能再写入
+    pub async fn persist(&self, partpath: &str, savepath: &str) -> Result<(), io::Error> {
+        self.storage.persist(partpath, savepath).await
+    }
+}
+
+/// 合并连续的小块数据, 攒满缓冲区后一次写入
+pub struct BufferedWriter {
+    writer: FileWriter,
+    buf: Vec<u8>,
+    offset: u64,
+}
+
+impl BufferedWriter {
+
+    pub fn new(writer: FileWriter, offset: u64) -> Self {
+        Self {
+            writer,
+            buf: Vec::with_capacity(WRITE_BUFFER_SIZE),
+            offset,
+        }
+    }
+
+    /// 追加数据, 返回本次实际写入文件的字节数
+    pub async fn push(&mut self, bytes: &[u8]) -> Result<u64, io::Error> {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() < WRITE_BUFFER_SIZE {
+            return Ok(0);
+        }
+        self.flush().await
+    }
+
+    /// 写入缓冲区中剩余的数据, 返回写入的字节数
+    pub async fn flush(&mut self) -> Result<u64, io::Error> {
+        if self.buf.is_empty() {
+            return Ok(0);
+        }
+        let buf = std::mem::take(&mut self.buf);
+        let len = buf.len() as u64;
+        let mut buf = self.writer.write_at(buf, self.offset).await?;
+        buf.clear();
+        self.buf = buf;
+        self.offset += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,270 @@
+use std::{sync::{Arc, RwLock}, collections::HashMap, time::Duration};
+use reqwest::{Client, Proxy, Url, cookie::Jar, header::{HeaderMap, HeaderName, HeaderValue}};
+use serde::{Serialize, Deserialize};
+use tauri::State;
+use thiserror::Error;
+
+pub const DEFAULT_USER_AGENT: &'static str = "Mozilla/5.0 (iPhone; CPU iPhone OS 13_2_3 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/13.0.3 Mobile/15E148 Safari/604.1";
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 15;
+const DEFAULT_READ_TIMEOUT: u64 = 30;
+
+#[derive(Error, Debug)]
+pub enum HttpError {
+
+    #[error("代理地址格式错误, 支持http://, https://与socks5://")]
+    InvalidProxyError,
+
+    #[error("请求头格式错误: {0}")]
+    InvalidHeaderError(String),
+
+    #[error("Cookie对应的URL格式错误: {0}")]
+    InvalidCookieUrlError(String),
+
+    #[error("创建网络客户端失败")]
+    ClientBuildError,
+}
+
+/// 所有请求共用的网络配置, 超时单位为秒, 0表示不限制
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    // 代理地址, 例如: socks5://127.0.0.1:1080
+    pub proxy: Option<String>,
+    pub connect_timeout: u64,
+    // 两次收到数据之间的最长等待时间
+    pub read_timeout: u64,
+    // 为空时使用DEFAULT_USER_AGENT
+    pub user_agent: Option<String>,
+    pub headers: HashMap<String, String>,
+    // 预置的Cookie, key为所属的URL, value为Cookie请求头格式, 例如: a=1; b=2
+    pub cookies: HashMap<String, String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            user_agent: None,
+            headers: HashMap::new(),
+            cookies: HashMap::new(),
+        }
+    }
+}
+
+impl HttpConfig {
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        match self.read_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// timeout为整个请求的超时, 包括读取响应体
+    fn build(&self, jar: Arc<Jar>, timeout: Option<Duration>) -> Result<Client, HttpError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| HttpError::InvalidHeaderError(name.clone()))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| HttpError::InvalidHeaderError(name.to_string()))?;
+            headers.insert(name, value);
+        }
+
+        let user_agent = match self.user_agent.as_deref() {
+            Some(user_agent) if !user_agent.trim().is_empty() => user_agent,
+            _ => DEFAULT_USER_AGENT,
+        };
+
+        let mut builder = Client::builder()
+            .user_agent(user_agent)
+            .default_headers(headers)
+            .cookie_provider(jar);
+        if self.connect_timeout > 0 {
+            builder = builder.connect_timeout(Duration::from_secs(self.connect_timeout));
+        }
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        match self.proxy.as_deref().map(str::trim) {
+            Some(proxy) if !proxy.is_empty() => {
+                builder = builder.proxy(Proxy::all(proxy).map_err(|_| HttpError::InvalidProxyError)?);
+            },
+            _ => {},
+        }
+        builder.build().map_err(|_| HttpError::ClientBuildError)
+    }
+}
+
+#[derive(Debug)]
+struct ClientInner {
+    config: HttpConfig,
+    client: Client,
+    api_client: Client,
+}
+
+impl ClientInner {
+
+    /// 按配置创建新的Cookie jar, 两个客户端共用, 之前的配置与服务器设置的Cookie都不再保留
+    fn new(config: HttpConfig) -> Result<Self, HttpError> {
+        let mut cookies = vec![];
+        for (url, cookie) in config.cookies.iter() {
+            let url = Url::parse(url).map_err(|_| HttpError::InvalidCookieUrlError(url.clone()))?;
+            cookies.push((url, cookie));
+        }
+        let jar = Arc::new(Jar::default());
+        for (url, cookie) in cookies {
+            for pair in cookie.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+                jar.add_cookie_str(pair, &url);
+            }
+        }
+        let client = config.build(jar.clone(), None)?;
+        let api_client = config.build(jar, config.read_timeout())?;
+        Ok(Self { config, client, api_client })
+    }
+}
+
+/// 共用的网络客户端, 作为Tauri state管理, 修改配置后新发起的请求立即使用新的客户端
+/// reqwest::Client内部共享连接池, clone的开销很小
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    inner: Arc<RwLock<ClientInner>>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        let inner = ClientInner::new(HttpConfig::default()).expect("failed to build default http client");
+        Self {
+            inner: Arc::new(RwLock::new(inner)),
+        }
+    }
+}
+
+impl HttpClient {
+
+    /// 下载文件使用, 没有整体超时, 由Downloader按两次收到数据的间隔判断超时
+    pub fn client(&self) -> Client {
+        self.inner.read().unwrap().client.clone()
+    }
+
+    /// 接口, 播放列表与密钥等一次读完响应的请求使用, 整个请求超过read_timeout时失败
+    pub fn api_client(&self) -> Client {
+        self.inner.read().unwrap().api_client.clone()
+    }
+
+    pub fn config(&self) -> HttpConfig {
+        self.inner.read().unwrap().config.clone()
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.inner.read().unwrap().config.read_timeout()
+    }
+
+    /// 配置有误时保留原来的客户端, 正在进行的下载继续使用原来的客户端
+    pub fn configure(&self, config: HttpConfig) -> Result<(), HttpError> {
+        let inner = ClientInner::new(config)?;
+        *self.inner.write().unwrap() = inner;
+        Ok(())
+    }
+}
+
+#[tauri::command]
+pub async fn http_set_config(config: HttpConfig, http: State<'_, HttpClient>) -> Result<(), String> {
+    http.configure(config).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn http_get_config(http: State<'_, HttpClient>) -> Result<HttpConfig, String> {
+    Ok(http.config())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, sync::mpsc};
+
+    /// 返回空响应, 并把每个请求的Cookie请求头发送给测试
+    async fn cookie_server() -> (String, mpsc::UnboundedReceiver<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let cookie = String::from_utf8_lossy(&request).lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("cookie"))
+                    .map(|(_, value)| value.trim().to_string());
+                let _ = tx.send(cookie);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+            }
+        });
+        (url, rx)
+    }
+
+    fn config_with_cookies(url: &str, cookies: &str) -> HttpConfig {
+        HttpConfig {
+            cookies: HashMap::from([(url.to_string(), cookies.to_string())]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn configure_replaces_cookies() {
+        let (url, mut cookies) = cookie_server().await;
+        let http = HttpClient::default();
+
+        let cases = [
+            (config_with_cookies(&url, "a=1; b=2"), Some("a=1; b=2")),
+            // 新配置不包含的Cookie不再发送
+            (config_with_cookies(&url, "c=3"), Some("c=3")),
+            (HttpConfig::default(), None),
+        ];
+        for (config, expected) in cases {
+            http.configure(config).unwrap();
+            for client in [http.client(), http.api_client()] {
+                client.get(&url).send().await.unwrap();
+                let mut cookie = cookies.recv().await.unwrap();
+                // Cookie的顺序不固定
+                if let Some(value) = cookie.as_mut() {
+                    let mut pairs = value.split("; ").collect::<Vec<&str>>();
+                    pairs.sort_unstable();
+                    *value = pairs.join("; ");
+                }
+                assert_eq!(cookie.as_deref(), expected);
+            }
+        }
+
+        // 配置有误时保留原来的配置
+        assert!(http.configure(config_with_cookies("not a url", "d=4")).is_err());
+        assert_eq!(http.config().cookies.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn api_requests_time_out() {
+        // 接受连接后不返回任何数据
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let http = HttpClient::default();
+        http.configure(HttpConfig { read_timeout: 1, ..Default::default() }).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(10), http.api_client().get(&url).send()).await;
+        assert!(result.expect("request should time out by itself").unwrap_err().is_timeout());
+    }
+}
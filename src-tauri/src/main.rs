@@ -5,15 +5,47 @@
 
 pub mod downloader;
 mod douyin;
+mod manager;
+mod http;
+mod template;
+mod mux;
+
+use tauri::Manager;
 
 fn main() {
+  let http = http::HttpClient::default();
   tauri::Builder::default()
+    .manage(manager::DownloadManager::new(http.clone()))
+    .manage(http)
+    .setup(|app| {
+      let manager = app.state::<manager::DownloadManager>();
+      manager.set_app_handle(app.handle());
+      tauri::async_runtime::spawn(manager.inner().clone().run_bandwidth_schedule());
+      Ok(())
+    })
     .invoke_handler(tauri::generate_handler![
       douyin::douyin_single_search,
       douyin::douyin_single_download,
       douyin::douyin_muplit_search,
       douyin::douyin_muplit_download,
       douyin::douyin_get_all_video_info,
+      manager::download_pause,
+      manager::download_resume,
+      manager::download_cancel,
+      manager::download_set_priority,
+      manager::download_move,
+      manager::download_set_queue_limits,
+      manager::download_queue_state,
+      manager::download_set_bandwidth,
+      manager::download_get_bandwidth,
+      manager::download_set_rate_limit,
+      manager::download_set_storage,
+      manager::download_set_post_process,
+      manager::download_get_post_process,
+      manager::download_set_stream_options,
+      manager::download_get_stream_options,
+      http::http_set_config,
+      http::http_get_config,
     ])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
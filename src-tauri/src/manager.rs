@@ -0,0 +1,726 @@
+use std::{sync::{Arc, Mutex}, collections::HashMap, path::Path, time::Duration};
+use futures::{stream, StreamExt};
+use serde::{Serialize, Deserialize};
+use tauri::{AppHandle, Manager, State};
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use log::warn;
+use crate::downloader::{self, Downloader, DownloadError, DownloadOptions, DownloadStatus, RateLimiter, BandwidthSchedule, CollisionPolicy, StorageBackend, PostProcessConfig, PostProcessor, Probe, StreamOptions};
+use crate::http::HttpClient;
+
+// 单个下载最多使用的连接数
+const DEFAULT_CHUNK_COUNT: usize = 8;
+const DEFAULT_MAX_DOWNLOADS: usize = 3;
+const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+// 检查磁盘空间时同时探测文件大小的任务数
+const PREFLIGHT_CONCURRENCY: usize = 8;
+
+// 按时间段限速时检查当前时间的间隔
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+enum ManagerError {
+
+    #[error("未找到下载任务")]
+    DownloadNotFoundError,
+
+    #[error("下载任务已存在")]
+    DownloadExistsError,
+
+    #[error("下载任务已取消")]
+    DownloadCancelledError,
+
+    #[error("限速时间段格式错误, 应为HH:MM")]
+    InvalidScheduleError,
+}
+
+/// 等待调度的下载任务, priority越大越先开始
+#[derive(Debug, Clone)]
+pub struct DownloadJob {
+    pub download_id: String,
+    pub url: String,
+    pub save_path: String,
+    pub priority: i32,
+    pub rate_limit: u64,
+    pub collision: CollisionPolicy,
+    // 检查磁盘空间时探测到的文件大小
+    pub total_size: Option<u64>,
+    // 检查磁盘空间时的探测结果, 开始下载时直接使用
+    pub probe: Option<Arc<Probe>>,
+    // HLS与DASH的清晰度选择条件, 为空时使用下载管理器的设置
+    pub stream: Option<StreamOptions>,
+}
+
+impl DownloadJob {
+    pub fn new(download_id: String, url: String, save_path: String) -> Self {
+        Self { download_id, url, save_path, priority: 0, rate_limit: 0, collision: CollisionPolicy::default(), total_size: None, probe: None, stream: None }
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_collision(mut self, collision: CollisionPolicy) -> Self {
+        self.collision = collision;
+        self
+    }
+}
+
+/// enqueue返回的句柄, 可以等待任务开始与结束
+pub struct JobHandle {
+    started: oneshot::Receiver<Arc<Downloader>>,
+    finished: oneshot::Receiver<Result<String>>,
+}
+
+impl JobHandle {
+
+    /// 任务开始下载时返回对应的Downloader, 任务在开始前结束时返回None
+    pub async fn started(&mut self) -> Option<Arc<Downloader>> {
+        (&mut self.started).await.ok()
+    }
+
+    /// 等待任务结束, 成功时返回实际保存路径
+    pub async fn finished(self) -> Result<String> {
+        self.finished.await.unwrap_or_else(|_| Err(anyhow!(ManagerError::DownloadCancelledError)))
+    }
+}
+
+struct QueuedJob {
+    job: DownloadJob,
+    started: oneshot::Sender<Arc<Downloader>>,
+    finished: oneshot::Sender<Result<String>>,
+}
+
+struct ActiveJob {
+    job: DownloadJob,
+    chunk_count: usize,
+    downloader: Option<Arc<Downloader>>,
+    // 占用的连接数, 暂停时释放为0, 不再占用下载数
+    connections: usize,
+    // 暂停后请求继续, 等待空出下载数与连接数
+    resuming: bool,
+    // 开始下载前取消时触发
+    token: CancellationToken,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueItemStatus {
+    Queued,
+    Starting,
+    Running(DownloadStatus),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueItem {
+    pub download_id: String,
+    pub save_path: String,
+    pub priority: i32,
+    pub status: QueueItemStatus,
+    pub chunk_count: usize,
+    pub downloaded: u64,
+    pub total_size: Option<u64>,
+}
+
+/// 通过download_queue事件推送给前端的队列状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueState {
+    pub max_downloads: usize,
+    pub max_connections: usize,
+    pub used_connections: usize,
+    pub items: Vec<QueueItem>,
+}
+
+struct QueueInner {
+    max_downloads: usize,
+    max_connections: usize,
+    used_connections: usize,
+    queued: Vec<QueuedJob>,
+    active: HashMap<String, ActiveJob>,
+    app_handle: Option<AppHandle>,
+}
+
+/// 全局下载队列, 限制同时进行的下载数与总连接数, 所有下载共用一个全局限速器
+#[derive(Clone)]
+pub struct DownloadManager {
+    inner: Arc<Mutex<QueueInner>>,
+    limiter: RateLimiter,
+    bandwidth: Arc<Mutex<BandwidthSchedule>>,
+    http: HttpClient,
+    // 新开始的下载使用的存储后端
+    storage: Arc<Mutex<StorageBackend>>,
+    // 下载完成后执行的ffmpeg后处理
+    post_process: Arc<Mutex<PostProcessConfig>>,
+    // 新开始的HLS与DASH下载的清晰度选择条件
+    stream: Arc<Mutex<StreamOptions>>,
+}
+
+impl DownloadManager {
+
+    /// 下载与解析共用同一个网络客户端
+    pub fn new(http: HttpClient) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(QueueInner {
+                max_downloads: DEFAULT_MAX_DOWNLOADS,
+                max_connections: DEFAULT_MAX_CONNECTIONS,
+                used_connections: 0,
+                queued: vec![],
+                active: HashMap::new(),
+                app_handle: None,
+            })),
+            limiter: RateLimiter::default(),
+            bandwidth: Arc::new(Mutex::new(BandwidthSchedule::default())),
+            http,
+            storage: Arc::new(Mutex::new(StorageBackend::default())),
+            post_process: Arc::new(Mutex::new(PostProcessConfig::default())),
+            stream: Arc::new(Mutex::new(StreamOptions::default())),
+        }
+    }
+}
+
+impl QueueInner {
+
+    fn contains(&self, download_id: &str) -> bool {
+        self.active.contains_key(download_id)
+            || self.queued.iter().any(|q| q.job.download_id == download_id)
+    }
+
+    fn position(&self, download_id: &str) -> Option<usize> {
+        self.queued.iter().position(|q| q.job.download_id == download_id)
+    }
+
+    /// 占用下载数的任务, 暂停的任务不计入
+    fn running(&self) -> usize {
+        self.active.values().filter(|active| active.connections > 0).count()
+    }
+
+    /// 插入到同优先级任务的末尾
+    fn insert(&mut self, queued: QueuedJob) {
+        let index = self.queued.iter()
+            .position(|q| q.job.priority < queued.job.priority)
+            .unwrap_or(self.queued.len());
+        self.queued.insert(index, queued);
+    }
+
+    fn state(&self) -> QueueState {
+        let mut items: Vec<QueueItem> = self.active.values()
+            .map(|active| QueueItem {
+                download_id: active.job.download_id.clone(),
+                save_path: active.downloader.as_ref()
+                    .map_or(active.job.save_path.clone(), |d| d.get_save_path()),
+                priority: active.job.priority,
+                status: match active.downloader.as_ref() {
+                    _ if active.resuming => QueueItemStatus::Queued,
+                    Some(d) => QueueItemStatus::Running(d.status()),
+                    None => QueueItemStatus::Starting,
+                },
+                chunk_count: active.chunk_count,
+                downloaded: active.downloader.as_ref().map_or(0, |d| d.downloaded_size()),
+                total_size: active.downloader.as_ref().map_or(active.job.total_size, |d| d.total_size()),
+            })
+            .collect();
+        items.extend(self.queued.iter().map(|q| QueueItem {
+            download_id: q.job.download_id.clone(),
+            save_path: q.job.save_path.clone(),
+            priority: q.job.priority,
+            status: QueueItemStatus::Queued,
+            chunk_count: 0,
+            downloaded: 0,
+            total_size: q.job.total_size,
+        }));
+        QueueState {
+            max_downloads: self.max_downloads,
+            max_connections: self.max_connections,
+            used_connections: self.used_connections,
+            items,
+        }
+    }
+}
+
+impl DownloadManager {
+
+    pub fn set_app_handle(&self, app_handle: AppHandle) {
+        self.inner.lock().unwrap().app_handle = Some(app_handle);
+    }
+
+    fn emit_state(&self) {
+        let inner = self.inner.lock().unwrap();
+        if let Some(app_handle) = inner.app_handle.as_ref() {
+            let _ = app_handle.emit_all("download_queue", inner.state());
+        }
+    }
+
+    /// 尚未开始的任务还需要的空间, 已开始的下载预分配了临时文件, 占用的空间已从可用空间中扣除
+    fn reserved_space(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        let starting = inner.active.values()
+            .filter(|active| active.downloader.is_none())
+            .map(|active| active.job.total_size.unwrap_or(0));
+        let queued = inner.queued.iter().map(|q| q.job.total_size.unwrap_or(0));
+        starting.chain(queued).sum()
+    }
+
+    /// 开始下载前探测所有任务的文件大小, 与尚未开始的任务一起同目标磁盘的可用空间比较, 空间不足时拒绝下载
+    /// 不区分其他任务保存在哪个磁盘, 结果偏保守; 探测不到大小的任务不计入
+    pub async fn preflight(&self, path: &Path, jobs: &mut [DownloadJob]) -> Result<(), String> {
+        let client = self.http.client();
+        let probes = stream::iter(jobs.iter().map(|job| downloader::probe(&client, &job.url)))
+            .buffered(PREFLIGHT_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+        let mut unknown = 0;
+        for (job, probe) in jobs.iter_mut().zip(probes) {
+            if let Ok(probe) = probe {
+                job.total_size = probe.filesize;
+                job.probe = Some(probe);
+            }
+            if job.total_size.is_none() {
+                unknown += 1;
+            }
+        }
+        if unknown > 0 {
+            warn!("Failed to probe the size of {} downloads, skipping them in the disk space check", unknown);
+        }
+
+        let required = jobs.iter().filter_map(|job| job.total_size).sum::<u64>() + self.reserved_space();
+        let available = downloader::available_space(path).map_err(|e| e.to_string())?;
+        if required > available {
+            return Err(DownloadError::InsufficientSpace { required, available }.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn enqueue(&self, job: DownloadJob) -> Result<JobHandle, String> {
+        let (started_tx, started) = oneshot::channel();
+        let (finished_tx, finished) = oneshot::channel();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.contains(&job.download_id) {
+                return Err(ManagerError::DownloadExistsError.to_string());
+            }
+            inner.insert(QueuedJob { job, started: started_tx, finished: finished_tx });
+        }
+        self.schedule();
+        self.emit_state();
+        Ok(JobHandle { started, finished })
+    }
+
+    /// 在下载数与连接数的限制内先继续请求继续的暂停任务, 再启动排在最前面的任务
+    /// 继续的任务需要按开始时的连接数重新占用, 空间不够时等待, 之后的任务也不会先开始
+    fn schedule(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut resuming: Vec<String> = inner.active.iter()
+            .filter(|(_, active)| active.resuming)
+            .map(|(download_id, _)| download_id.clone())
+            .collect();
+        resuming.sort_by_key(|download_id| std::cmp::Reverse(inner.active[download_id].job.priority));
+        for download_id in resuming {
+            let available = inner.max_connections.saturating_sub(inner.used_connections);
+            let chunk_count = inner.active[&download_id].chunk_count;
+            if inner.running() >= inner.max_downloads || available < chunk_count {
+                return;
+            }
+            inner.used_connections += chunk_count;
+            let active = inner.active.get_mut(&download_id).expect("resuming job is active");
+            active.connections = chunk_count;
+            active.resuming = false;
+            if let Some(downloader) = active.downloader.as_ref() {
+                downloader.resume();
+            }
+        }
+        while !inner.queued.is_empty() && inner.running() < inner.max_downloads {
+            let available = inner.max_connections.saturating_sub(inner.used_connections);
+            if available == 0 {
+                break;
+            }
+            let chunk_count = std::cmp::min(DEFAULT_CHUNK_COUNT, available);
+            let queued = inner.queued.remove(0);
+            inner.used_connections += chunk_count;
+            let token = CancellationToken::new();
+            inner.active.insert(queued.job.download_id.clone(), ActiveJob {
+                job: queued.job.clone(),
+                chunk_count,
+                downloader: None,
+                connections: chunk_count,
+                resuming: false,
+                token: token.clone(),
+            });
+            tokio::spawn(self.clone().run_job(queued, chunk_count, token));
+        }
+    }
+
+    async fn run_job(self, queued: QueuedJob, chunk_count: usize, token: CancellationToken) {
+        let QueuedJob { job, started, finished } = queued;
+        let download_id = job.download_id.clone();
+
+        let result = async {
+            let options = DownloadOptions {
+                chunk_count: Some(chunk_count as u8),
+                rate_limit: job.rate_limit,
+                global_limiter: Some(self.limiter.clone()),
+                http: Some(self.http.clone()),
+                collision: job.collision,
+                download_id: Some(download_id.clone()),
+                storage: *self.storage.lock().unwrap(),
+                remux_ts: true,
+                post_process: self.post_processor(),
+                probe: job.probe,
+                stream: job.stream.unwrap_or_else(|| self.stream.lock().unwrap().clone()),
+                ..Default::default()
+            };
+            // 探测文件时也可能被取消
+            let downloader = tokio::select! {
+                _ = token.cancelled() => return Err(anyhow!(ManagerError::DownloadCancelledError)),
+                downloader = Downloader::with_options(job.url, job.save_path, options) => downloader?,
+            };
+            if let Some(active) = self.inner.lock().unwrap().active.get_mut(&download_id) {
+                // 在加锁前取消的任务在这里补上
+                if active.token.is_cancelled() {
+                    downloader.cancel();
+                }
+                active.downloader = Some(downloader.clone());
+            }
+            self.emit_state();
+            let _ = started.send(downloader.clone());
+            downloader.clone().download().await?;
+            Ok(downloader.get_save_path())
+        }.await;
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(active) = inner.active.remove(&download_id) {
+                inner.used_connections = inner.used_connections.saturating_sub(active.connections);
+            }
+        }
+        let _ = finished.send(result);
+        self.schedule();
+        self.emit_state();
+    }
+
+    /// 设置后ffmpeg被移除时只跳过后处理, 不影响下载
+    fn post_processor(&self) -> Option<PostProcessor> {
+        match PostProcessor::from_config(&self.post_process.lock().unwrap()) {
+            Ok(processor) => processor,
+            Err(e) => {
+                warn!("Skip post-processing, error: {:?}", e);
+                None
+            },
+        }
+    }
+
+    /// 暂停时释放占用的下载数与连接数, 让排队中的任务开始
+    fn pause(&self, download_id: &str) -> Result<(), String> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let active = inner.active
+                .get_mut(download_id)
+                .ok_or_else(|| ManagerError::DownloadNotFoundError.to_string())?;
+            let downloader = active.downloader.clone()
+                .ok_or_else(|| ManagerError::DownloadNotFoundError.to_string())?;
+            downloader.pause();
+            let connections = std::mem::take(&mut active.connections);
+            active.resuming = false;
+            inner.used_connections = inner.used_connections.saturating_sub(connections);
+        }
+        self.schedule();
+        self.emit_state();
+        Ok(())
+    }
+
+    /// 暂停的任务重新排队, 有空闲的下载数与连接数时继续
+    fn resume(&self, download_id: &str) -> Result<(), String> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let active = inner.active
+                .get_mut(download_id)
+                .ok_or_else(|| ManagerError::DownloadNotFoundError.to_string())?;
+            let downloader = active.downloader.clone()
+                .ok_or_else(|| ManagerError::DownloadNotFoundError.to_string())?;
+            match active.connections {
+                0 => active.resuming = true,
+                _ => downloader.resume(),
+            }
+        }
+        self.schedule();
+        self.emit_state();
+        Ok(())
+    }
+
+    /// 取消排队中的任务直接移出队列, 开始下载前的任务中止探测, 进行中的任务交给Downloader处理
+    fn cancel(&self, download_id: &str) -> Result<(), String> {
+        let queued = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.position(download_id) {
+                Some(index) => Some(inner.queued.remove(index)),
+                None => {
+                    let active = inner.active
+                        .get_mut(download_id)
+                        .ok_or_else(|| ManagerError::DownloadNotFoundError.to_string())?;
+                    active.token.cancel();
+                    active.resuming = false;
+                    if let Some(downloader) = active.downloader.as_ref() {
+                        downloader.cancel();
+                    }
+                    None
+                },
+            }
+        };
+        if let Some(queued) = queued {
+            let _ = queued.finished.send(Err(anyhow!(ManagerError::DownloadCancelledError)));
+        }
+        self.emit_state();
+        Ok(())
+    }
+
+    fn set_priority(&self, download_id: &str, priority: i32) -> Result<(), String> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let index = inner.position(download_id)
+                .ok_or_else(|| ManagerError::DownloadNotFoundError.to_string())?;
+            let mut queued = inner.queued.remove(index);
+            queued.job.priority = priority;
+            inner.insert(queued);
+        }
+        self.emit_state();
+        Ok(())
+    }
+
+    /// 将排队中的任务移动到指定位置, 不改变其优先级
+    fn move_to(&self, download_id: &str, position: usize) -> Result<(), String> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let index = inner.position(download_id)
+                .ok_or_else(|| ManagerError::DownloadNotFoundError.to_string())?;
+            let queued = inner.queued.remove(index);
+            let position = std::cmp::min(position, inner.queued.len());
+            inner.queued.insert(position, queued);
+        }
+        self.emit_state();
+        Ok(())
+    }
+
+    /// 按当前时间更新全局限速
+    fn apply_bandwidth(&self) {
+        let limit = self.bandwidth.lock().unwrap().current_limit(Local::now().time());
+        self.limiter.set_rate(limit);
+    }
+
+    /// 定期检查限速时间段, 在main的setup中启动
+    pub async fn run_bandwidth_schedule(self) {
+        loop {
+            self.apply_bandwidth();
+            tokio::time::sleep(SCHEDULE_CHECK_INTERVAL).await;
+        }
+    }
+
+    fn set_bandwidth(&self, schedule: BandwidthSchedule) -> Result<(), String> {
+        if !schedule.is_valid() {
+            return Err(ManagerError::InvalidScheduleError.to_string());
+        }
+        *self.bandwidth.lock().unwrap() = schedule;
+        self.apply_bandwidth();
+        Ok(())
+    }
+
+    /// 修改单个下载的限速, 排队中的任务在开始时生效
+    fn set_rate_limit(&self, download_id: &str, rate_limit: u64) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(index) = inner.position(download_id) {
+            inner.queued[index].job.rate_limit = rate_limit;
+            return Ok(());
+        }
+        let active = inner.active
+            .get_mut(download_id)
+            .ok_or_else(|| ManagerError::DownloadNotFoundError.to_string())?;
+        active.job.rate_limit = rate_limit;
+        if let Some(downloader) = active.downloader.as_ref() {
+            downloader.set_rate_limit(rate_limit);
+        }
+        Ok(())
+    }
+
+    fn set_limits(&self, max_downloads: usize, max_connections: usize) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.max_downloads = std::cmp::max(max_downloads, 1);
+            inner.max_connections = std::cmp::max(max_connections, 1);
+        }
+        self.schedule();
+        self.emit_state();
+    }
+}
+
+#[tauri::command]
+pub async fn download_pause(download_id: String, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    manager.pause(&download_id)
+}
+
+#[tauri::command]
+pub async fn download_resume(download_id: String, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    manager.resume(&download_id)
+}
+
+#[tauri::command]
+pub async fn download_cancel(download_id: String, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    manager.cancel(&download_id)
+}
+
+#[tauri::command]
+pub async fn download_set_priority(download_id: String, priority: i32, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    manager.set_priority(&download_id, priority)
+}
+
+#[tauri::command]
+pub async fn download_move(download_id: String, position: usize, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    manager.move_to(&download_id, position)
+}
+
+#[tauri::command]
+pub async fn download_set_queue_limits(max_downloads: usize, max_connections: usize, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    manager.set_limits(max_downloads, max_connections);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn download_queue_state(manager: State<'_, DownloadManager>) -> Result<QueueState, String> {
+    Ok(manager.inner.lock().unwrap().state())
+}
+
+#[tauri::command]
+pub async fn download_set_bandwidth(schedule: BandwidthSchedule, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    manager.set_bandwidth(schedule)
+}
+
+#[tauri::command]
+pub async fn download_get_bandwidth(manager: State<'_, DownloadManager>) -> Result<BandwidthSchedule, String> {
+    Ok(manager.bandwidth.lock().unwrap().clone())
+}
+
+#[tauri::command]
+pub async fn download_set_rate_limit(download_id: String, rate_limit: u64, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    manager.set_rate_limit(&download_id, rate_limit)
+}
+
+/// 修改存储后端, 只影响之后开始的下载
+#[tauri::command]
+pub async fn download_set_storage(storage: StorageBackend, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    *manager.storage.lock().unwrap() = storage;
+    Ok(())
+}
+
+/// 修改后处理步骤, 只影响之后开始的下载, 有步骤时必须能找到ffmpeg
+#[tauri::command]
+pub async fn download_set_post_process(config: PostProcessConfig, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    PostProcessor::from_config(&config).map_err(|e| e.to_string())?;
+    *manager.post_process.lock().unwrap() = config;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn download_get_post_process(manager: State<'_, DownloadManager>) -> Result<PostProcessConfig, String> {
+    Ok(manager.post_process.lock().unwrap().clone())
+}
+
+/// 修改HLS与DASH的清晰度选择条件, 只影响之后开始的下载
+#[tauri::command]
+pub async fn download_set_stream_options(options: StreamOptions, manager: State<'_, DownloadManager>) -> Result<(), String> {
+    *manager.stream.lock().unwrap() = options;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn download_get_stream_options(manager: State<'_, DownloadManager>) -> Result<StreamOptions, String> {
+    Ok(manager.stream.lock().unwrap().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::time::{sleep, timeout};
+    use crate::downloader::test_server::{TestServer, temp_dir};
+    use super::*;
+
+    fn job(download_id: &str, url: String, rate_limit: u64) -> DownloadJob {
+        let save_path = temp_dir(download_id).join("file.bin").to_string_lossy().to_string();
+        let mut job = DownloadJob::new(download_id.to_string(), url, save_path);
+        job.rate_limit = rate_limit;
+        job
+    }
+
+    fn status(manager: &DownloadManager, download_id: &str) -> Option<QueueItemStatus> {
+        let state = manager.inner.lock().unwrap().state();
+        state.items.into_iter()
+            .find(|item| item.download_id == download_id)
+            .map(|item| item.status)
+    }
+
+    fn used_connections(manager: &DownloadManager) -> usize {
+        manager.inner.lock().unwrap().used_connections
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        timeout(Duration::from_secs(10), async {
+            while !condition() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancels_starting_job() {
+        // 只接受连接不响应, 任务停在探测文件阶段
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file.bin", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let manager = DownloadManager::new(HttpClient::default());
+        let handle = manager.enqueue(job("starting", url, 0)).unwrap();
+        assert!(matches!(status(&manager, "starting"), Some(QueueItemStatus::Starting)));
+
+        manager.cancel("starting").unwrap();
+        let error = timeout(Duration::from_secs(5), handle.finished()).await.unwrap().unwrap_err();
+        assert_eq!(error.to_string(), ManagerError::DownloadCancelledError.to_string());
+        assert!(status(&manager, "starting").is_none());
+        assert_eq!(used_connections(&manager), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn paused_job_releases_slot() {
+        let server = TestServer::start(vec![0u8; 256 * 1024], true).await;
+        let manager = DownloadManager::new(HttpClient::default());
+        manager.set_limits(1, DEFAULT_MAX_CONNECTIONS);
+
+        // 限速使任务在测试期间一直在下载
+        let mut first = manager.enqueue(job("first", server.url("first.bin"), 4096)).unwrap();
+        first.started().await.unwrap();
+        let mut second = manager.enqueue(job("second", server.url("second.bin"), 4096)).unwrap();
+        assert!(matches!(status(&manager, "second"), Some(QueueItemStatus::Queued)));
+
+        // 暂停后释放下载数与连接数, 排队中的任务开始
+        manager.pause("first").unwrap();
+        second.started().await.unwrap();
+        assert_eq!(used_connections(&manager), DEFAULT_CHUNK_COUNT);
+
+        // 没有空闲的下载数时继续的任务等待
+        manager.resume("first").unwrap();
+        assert!(matches!(status(&manager, "first"), Some(QueueItemStatus::Queued)));
+
+        // 取消进行中的任务后暂停的任务继续
+        manager.set_rate_limit("first", 0).unwrap();
+        manager.cancel("second").unwrap();
+        assert!(second.finished().await.is_err());
+        let save_path = timeout(Duration::from_secs(10), first.finished()).await.unwrap().unwrap();
+        assert_eq!(std::fs::read(save_path).unwrap().len(), 256 * 1024);
+        wait_until(|| used_connections(&manager) == 0).await;
+    }
+}
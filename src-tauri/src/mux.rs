@@ -0,0 +1,705 @@
+use std::{collections::HashMap, fs::File, io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use anyhow::Result;
+use thiserror::Error;
+
+mod bits;
+mod codec;
+mod ts;
+mod mp4;
+mod reader;
+use codec::{AdtsHeader, AAC_FRAME_SAMPLES};
+use ts::{Demuxer, Pes, StreamKind, PACKET_SIZE};
+use mp4::Mp4Writer;
+use reader::InputTrack;
+
+// MPEG-TS的时间单位
+const TS_TIMESCALE: u32 = 90000;
+
+// 33位时间戳回绕的周期
+const TIMESTAMP_WRAP: i128 = 1 << 33;
+
+// 合并音视频时每次连续写入一个轨道的时长, 毫秒
+const INTERLEAVE_MS: u128 = 500;
+
+// 写入中的临时文件, 不能使用下载占用文件名时的.part后缀
+const TEMP_FILE_SUFFIX: &'static str = ".remux.tmp";
+
+#[derive(Error, Debug)]
+pub enum MuxError {
+
+    #[error("MPEG-TS格式错误: {0}")]
+    InvalidTsError(String),
+
+    #[error("缺少{0}")]
+    MissingConfigError(&'static str),
+
+    #[error("{0}格式错误")]
+    InvalidConfigError(&'static str),
+
+    #[error("没有可转封装的音视频流")]
+    NoStreamError,
+}
+
+/// MPEG-TS每个包188字节且以0x47开头, 只检查一个字节容易误判
+pub fn is_mpeg_ts(head: &[u8]) -> bool {
+    head.len() > PACKET_SIZE * 2 && (0..3).all(|i| head[i * PACKET_SIZE] == 0x47)
+}
+
+/// 按上一个时间戳展开33位的回绕
+fn unwrap_timestamp(last: Option<u64>, timestamp: u64) -> u64 {
+    let last = match last {
+        Some(last) => last as i128,
+        None => return timestamp,
+    };
+    let base = last - last.rem_euclid(TIMESTAMP_WRAP) + timestamp as i128;
+    [base - TIMESTAMP_WRAP, base, base + TIMESTAMP_WRAP].iter()
+        .copied()
+        .filter(|t| *t >= 0)
+        .min_by_key(|t| (t - last).abs())
+        .unwrap_or(base) as u64
+}
+
+/// 按00 00 01起始码切分Annex B格式的NAL单元
+fn split_nals(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = vec![];
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts.iter()
+        .enumerate()
+        .map(|(index, start)| {
+            let end = starts.get(index + 1).map_or(data.len(), |next| next - 3);
+            let mut nal = &data[*start..end];
+            // 4字节起始码的第一个0与结尾填充的0不属于NAL
+            while let Some((0, rest)) = nal.split_last() {
+                nal = rest;
+            }
+            nal
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+/// 视频流的参数集, 第一个关键帧之前收集齐全后写入avcC或hvcC
+#[derive(Debug, Default)]
+struct ParameterSets {
+    vps: Vec<Vec<u8>>,
+    sps: Vec<Vec<u8>>,
+    pps: Vec<Vec<u8>>,
+}
+
+impl ParameterSets {
+    fn add(list: &mut Vec<Vec<u8>>, nal: &[u8]) {
+        if !list.iter().any(|n| n == nal) {
+            list.push(nal.to_vec());
+        }
+    }
+}
+
+/// 一个PID转封装的状态
+#[derive(Debug, Default)]
+struct StreamState {
+    // 在第一个关键帧或第一个ADTS帧时创建
+    track: Option<usize>,
+    // 上一个解码时间, 90kHz, 用于展开回绕
+    last_dts: Option<u64>,
+    params: ParameterSets,
+    // 音频: 跨PES的不完整ADTS帧, 下一帧的解码时间与采样率
+    leftover: Vec<u8>,
+    next_dts: Option<u64>,
+    sample_rate: u32,
+}
+
+/// 将MPEG-TS中的H.264/H.265与AAC转封装为MP4, 不重新编码
+struct Remuxer<W: Write + Seek> {
+    writer: Mp4Writer<W>,
+    demuxer: Demuxer,
+    streams: HashMap<u16, StreamState>,
+}
+
+impl<W: Write + Seek> Remuxer<W> {
+
+    fn new(output: W) -> io::Result<Self> {
+        Ok(Self {
+            writer: Mp4Writer::new(output)?,
+            demuxer: Demuxer::default(),
+            streams: HashMap::new(),
+        })
+    }
+
+    fn push(&mut self, packet: &[u8]) -> Result<()> {
+        if let Some(pes) = self.demuxer.push(packet)? {
+            self.write_pes(pes)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        for pes in self.demuxer.flush()? {
+            self.write_pes(pes)?;
+        }
+        if self.demuxer.streams().is_empty() || self.streams.values().all(|s| s.track.is_none()) {
+            return Err(MuxError::NoStreamError.into());
+        }
+        Ok(self.writer.finish()?)
+    }
+
+    fn write_pes(&mut self, pes: Pes) -> Result<()> {
+        match pes.kind {
+            StreamKind::H264 | StreamKind::H265 => self.write_video(pes),
+            StreamKind::Aac => self.write_audio(pes),
+        }
+    }
+
+    /// 一个PES是一帧, NAL单元改为4字节长度前缀, 参数集与分隔符只保留在编码信息中
+    fn write_video(&mut self, pes: Pes) -> Result<()> {
+        let (pts, dts) = match (pes.pts, pes.dts) {
+            (Some(pts), Some(dts)) => (pts, dts),
+            _ => return Ok(()),
+        };
+        let hevc = pes.kind == StreamKind::H265;
+        let stream = self.streams.entry(pes.pid).or_default();
+        let dts = unwrap_timestamp(stream.last_dts, dts);
+        let pts = unwrap_timestamp(Some(dts), pts);
+        stream.last_dts = Some(dts);
+
+        let mut keyframe = false;
+        let mut sample = vec![];
+        for nal in split_nals(&pes.payload) {
+            let nal_type = match hevc {
+                true => (nal[0] >> 1) & 0x3f,
+                false => nal[0] & 0x1f,
+            };
+            match (hevc, nal_type) {
+                (true, 32) => ParameterSets::add(&mut stream.params.vps, nal),
+                (true, 33) | (false, 7) => ParameterSets::add(&mut stream.params.sps, nal),
+                (true, 34) | (false, 8) => ParameterSets::add(&mut stream.params.pps, nal),
+                (true, 35) | (false, 9) => {},
+                (true, 16..=23) | (false, 5) => keyframe = true,
+                _ => {},
+            }
+            if matches!((hevc, nal_type), (true, 32..=35) | (false, 7..=9)) {
+                continue;
+            }
+            sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            sample.extend_from_slice(nal);
+        }
+        if sample.is_empty() {
+            return Ok(());
+        }
+
+        // 第一个关键帧之前的帧无法解码, 直接丢弃
+        let track = match stream.track {
+            Some(track) => track,
+            None if keyframe => {
+                let params = &stream.params;
+                let entry = match hevc {
+                    true => codec::hevc_entry(&params.vps, &params.sps, &params.pps)?,
+                    false => codec::avc_entry(&params.sps, &params.pps)?,
+                };
+                let track = self.writer.add_track(TS_TIMESCALE, entry);
+                stream.track = Some(track);
+                stream.params = ParameterSets::default();
+                track
+            },
+            None => return Ok(()),
+        };
+        self.writer.write_sample(track, &sample, dts, pts as i64 - dts as i64, keyframe)?;
+        Ok(())
+    }
+
+    /// 一个PES中可能有多个ADTS帧, 每帧1024个采样, 时间戳按采样率换算
+    fn write_audio(&mut self, pes: Pes) -> Result<()> {
+        let stream = self.streams.entry(pes.pid).or_default();
+        let mut pts = pes.pts.map(|pts| {
+            let pts = unwrap_timestamp(stream.last_dts, pts);
+            stream.last_dts = Some(pts);
+            pts
+        });
+        let carried = stream.leftover.len();
+        let mut data = std::mem::take(&mut stream.leftover);
+        data.extend_from_slice(&pes.payload);
+
+        let mut offset = 0;
+        while offset < data.len() {
+            let header = match AdtsHeader::parse(&data[offset..]) {
+                Some(header) => header,
+                None if data.len() - offset < 7 => break,
+                // 不是帧头时逐字节查找下一个同步字
+                None => {
+                    offset += 1;
+                    continue;
+                },
+            };
+            if offset + header.frame_len > data.len() {
+                break;
+            }
+            let track = match stream.track {
+                Some(track) => track,
+                None => {
+                    let track = self.writer.add_track(header.sample_rate(), header.entry());
+                    stream.track = Some(track);
+                    stream.sample_rate = header.sample_rate();
+                    track
+                },
+            };
+            // PES的时间戳属于在这个PES中开始的第一帧, 偏差超过半帧时以它为准
+            if let Some(pts) = if offset >= carried { pts.take() } else { None } {
+                let expected = (pts as u128 * stream.sample_rate as u128 / TS_TIMESCALE as u128) as u64;
+                let drift = stream.next_dts.map_or(u64::MAX, |next| if next > expected { next - expected } else { expected - next });
+                if drift > AAC_FRAME_SAMPLES / 2 {
+                    stream.next_dts = Some(expected);
+                }
+            }
+            if let Some(dts) = stream.next_dts {
+                let frame = &data[offset + header.header_len..offset + header.frame_len];
+                self.writer.write_sample(track, frame, dts, 0, true)?;
+                stream.next_dts = Some(dts + AAC_FRAME_SAMPLES);
+            }
+            offset += header.frame_len;
+        }
+        stream.leftover = data[offset..].to_vec();
+        Ok(())
+    }
+}
+
+/// 读取MPEG-TS并写入MP4, 丢失同步时跳到下一个0x47
+pub fn remux_ts<R: Read, W: Write + Seek>(input: R, output: W) -> Result<W> {
+    let mut input = BufReader::new(input);
+    let mut remuxer = Remuxer::new(output)?;
+    let mut packet = [0u8; PACKET_SIZE];
+    let mut filled = 0;
+    loop {
+        let read = input.read(&mut packet[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+        if filled < PACKET_SIZE {
+            continue;
+        }
+        match packet.iter().position(|b| *b == 0x47) {
+            Some(0) => {
+                remuxer.push(&packet)?;
+                filled = 0;
+            },
+            Some(position) => {
+                packet.copy_within(position.., 0);
+                filled = PACKET_SIZE - position;
+            },
+            None => filled = 0,
+        }
+    }
+    remuxer.finish()
+}
+
+/// 样本的解码时间, 毫秒
+fn sample_time(track: &InputTrack, index: usize) -> u128 {
+    track.samples[index].dts as u128 * 1000 / std::cmp::max(track.timescale, 1) as u128
+}
+
+/// 将MP4/M4A或DASH分段文件中的视频轨道与音频轨道合并为一个MP4, 不重新编码
+/// 样本按解码时间交错写入, 保留原来的时间单位与编辑列表
+pub fn mux_tracks<R: Read + Seek, W: Write + Seek>(mut video: R, mut audio: R, output: W) -> Result<W> {
+    let inputs = [reader::read_track(&mut video, true)?, reader::read_track(&mut audio, false)?];
+    let mut sources = [video, audio];
+    let mut writer = Mp4Writer::new(output)?;
+    let tracks = inputs.iter()
+        .map(|input| {
+            let track = writer.add_track(input.timescale, input.entry.clone());
+            if let Some(media_time) = input.media_time {
+                writer.set_media_time(track, media_time);
+            }
+            track
+        })
+        .collect::<Vec<usize>>();
+
+    let mut next = [0usize; 2];
+    let mut data = vec![];
+    // 选择下一个样本最早的轨道, 连续写入INTERLEAVE_MS内的样本
+    while let Some(current) = (0..2)
+        .filter(|i| next[*i] < inputs[*i].samples.len())
+        .min_by_key(|i| sample_time(&inputs[*i], next[*i])) {
+        let input = &inputs[current];
+        let deadline = sample_time(input, next[current]) + INTERLEAVE_MS;
+        while next[current] < input.samples.len() && sample_time(input, next[current]) < deadline {
+            let sample = input.samples[next[current]];
+            data.resize(sample.size as usize, 0);
+            sources[current].seek(SeekFrom::Start(sample.offset))?;
+            sources[current].read_exact(&mut data)?;
+            writer.write_sample(tracks[current], &data, sample.dts, sample.cts_offset, sample.sync)?;
+            next[current] += 1;
+        }
+    }
+    Ok(writer.finish()?)
+}
+
+/// 在后台线程中写入临时文件, 成功后再改名为目标文件
+async fn write_file<F>(output: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(BufWriter<File>) -> Result<BufWriter<File>> + Send + 'static,
+{
+    let mut temp = output.as_os_str().to_owned();
+    temp.push(TEMP_FILE_SUFFIX);
+    let temp = PathBuf::from(temp);
+
+    let result = tokio::task::spawn_blocking({
+        let temp = temp.clone();
+        move || -> Result<()> {
+            let writer = write(BufWriter::new(File::create(&temp)?))?;
+            writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            Ok(())
+        }
+    }).await?;
+    match result {
+        Ok(_) => Ok(tokio::fs::rename(&temp, output).await?),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temp).await;
+            Err(e)
+        }
+    }
+}
+
+/// 将MPEG-TS文件转封装为MP4文件
+pub async fn remux_ts_file(input: &Path, output: &Path) -> Result<()> {
+    let input = input.to_path_buf();
+    write_file(output, move |writer| remux_ts(File::open(&input)?, writer)).await
+}
+
+/// 将单独的视频文件与音频文件合并为一个MP4文件
+pub async fn mux_files(video: &Path, audio: &Path, output: &Path) -> Result<()> {
+    let (video, audio) = (video.to_path_buf(), audio.to_path_buf());
+    write_file(output, move |writer| {
+        mux_tracks(BufReader::new(File::open(&video)?), BufReader::new(File::open(&audio)?), writer)
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use super::mp4::{write_box, write_full_box, write_sample_entry};
+    use super::reader::children;
+
+    // 64x64的H.264 Baseline参数集与两个很短的帧
+    const SPS: [u8; 7] = [0x67, 0x42, 0xc0, 0x0a, 0xda, 0x10, 0x99];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+    const IDR: [u8; 4] = [0x65, 0x88, 0x84, 0x21];
+    const SLICE: [u8; 3] = [0x41, 0x9a, 0x02];
+
+    const VIDEO_PID: u16 = 0x100;
+    const AUDIO_PID: u16 = 0x101;
+
+    /// 一个TS包, 不足184字节的负载用适配字段填充
+    fn packet(pid: u16, start: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x47, ((start as u8) << 6) | (pid >> 8) as u8, pid as u8];
+        if payload.len() < 184 {
+            let stuffing = 184 - payload.len() - 1;
+            packet.push(0x30);
+            packet.push(stuffing as u8);
+            if stuffing > 0 {
+                packet.push(0);
+                packet.extend(std::iter::repeat(0xff).take(stuffing - 1));
+            }
+        } else {
+            packet.push(0x10);
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn split_packets(pid: u16, data: &[u8]) -> Vec<u8> {
+        data.chunks(184)
+            .enumerate()
+            .flat_map(|(i, chunk)| packet(pid, i == 0, chunk))
+            .collect()
+    }
+
+    /// PAT或PMT, 末尾的CRC不会被校验
+    fn section(pid: u16, table_id: u8, body: &[u8]) -> Vec<u8> {
+        let len = 5 + body.len() + 4;
+        let mut data = vec![0, table_id, 0xb0 | (len >> 8) as u8, len as u8, 0, 1, 0xc1, 0, 0];
+        data.extend_from_slice(body);
+        data.extend_from_slice(&[0; 4]);
+        packet(pid, true, &data)
+    }
+
+    fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+        [
+            (prefix << 4) | (((ts >> 30) as u8 & 0x07) << 1) | 1,
+            (ts >> 22) as u8,
+            (((ts >> 15) as u8) << 1) | 1,
+            (ts >> 7) as u8,
+            ((ts as u8) << 1) | 1,
+        ]
+    }
+
+    fn pes(stream_id: u8, pts: u64, dts: Option<u64>, payload: &[u8]) -> Vec<u8> {
+        let mut header = timestamp(if dts.is_some() { 3 } else { 2 }, pts).to_vec();
+        if let Some(dts) = dts {
+            header.extend_from_slice(&timestamp(1, dts));
+        }
+        let mut data = vec![0, 0, 1, stream_id, 0, 0, 0x80, if dts.is_some() { 0xc0 } else { 0x80 }, header.len() as u8];
+        data.extend_from_slice(&header);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn annex_b(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter().flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat()).collect()
+    }
+
+    /// 44.1kHz双声道的AAC LC帧
+    fn adts(payload: &[u8]) -> Vec<u8> {
+        let len = 7 + payload.len();
+        let mut frame = vec![0xff, 0xf1, 0x50, 0x80 | (len >> 11) as u8, (len >> 3) as u8, ((len as u8 & 0x07) << 5) | 0x1f, 0xfc];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn fixture() -> Vec<u8> {
+        let mut ts = section(0, 0x00, &[0, 1, 0xe0 | 0x10, 0x00]);
+        ts.extend(section(0x1000, 0x02, &[
+            0xe1, 0x00, 0xf0, 0x00,
+            0x1b, 0xe1, 0x00, 0xf0, 0x00,
+            0x0f, 0xe1, 0x01, 0xf0, 0x00,
+        ]));
+        // 关键帧之前的帧会被丢弃
+        ts.extend(split_packets(VIDEO_PID, &pes(0xe0, 6000, Some(3000), &annex_b(&[&SLICE]))));
+        ts.extend(split_packets(AUDIO_PID, &pes(0xc0, 9000, None, &[adts(&[1; 10]), adts(&[2; 10])].concat())));
+        for i in 0..3u64 {
+            let frame = match i {
+                0 => annex_b(&[&SPS, &PPS, &IDR]),
+                _ => annex_b(&[&SLICE]),
+            };
+            ts.extend(split_packets(VIDEO_PID, &pes(0xe0, 12000 + i * 3000, Some(9000 + i * 3000), &frame)));
+        }
+        ts.extend(split_packets(AUDIO_PID, &pes(0xc0, 9000 + 2048 * 90000 / 44100, None, &[adts(&[3; 10]), adts(&[4; 10])].concat())));
+        ts
+    }
+
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Vec<&'a [u8]> {
+        let mut found = vec![data];
+        for kind in path {
+            found = found.iter()
+                .flat_map(|data| children(data))
+                .filter(|(name, _)| name == *kind)
+                .map(|(_, body)| body)
+                .collect();
+        }
+        found
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn detects_mpeg_ts() {
+        assert!(is_mpeg_ts(&fixture()));
+        assert!(!is_mpeg_ts(b"\0\0\0\x18ftypisom"));
+    }
+
+    #[test]
+    fn unwraps_timestamps() {
+        assert_eq!(unwrap_timestamp(None, 100), 100);
+        assert_eq!(unwrap_timestamp(Some((1 << 33) - 10), 5), (1 << 33) + 5);
+        assert_eq!(unwrap_timestamp(Some((1 << 33) + 5), (1 << 33) - 10), (1 << 33) - 10);
+    }
+
+    #[test]
+    fn rejects_truncated_pmt() {
+        let pat = section(0, 0x00, &[0, 1, 0xe0 | 0x10, 0x00]);
+        // 指针把表推到负载末尾, 只剩11个字节
+        let mut payload = vec![172];
+        payload.resize(173, 0xff);
+        payload.extend_from_slice(&[0x02, 0xb0, 9, 0, 1, 0xc1, 0, 0, 0xe1, 0x00, 0xf0]);
+        let truncated = packet(0x1000, true, &payload);
+        // 节目信息长度超出表的范围
+        let overflow = section(0x1000, 0x02, &[0xe1, 0x00, 0xf0, 0x20, 0x1b, 0xe1, 0x00, 0xf0, 0x00]);
+
+        for pmt in [truncated, overflow] {
+            let mut demuxer = Demuxer::default();
+            demuxer.push(&pat).unwrap();
+            assert!(matches!(demuxer.push(&pmt), Err(MuxError::InvalidTsError(_))));
+            assert!(demuxer.streams().is_empty());
+        }
+    }
+
+    #[test]
+    fn remuxes_h264_and_aac() {
+        let output = remux_ts(Cursor::new(fixture()), Cursor::new(vec![])).unwrap().into_inner();
+        let boxes = children(&output);
+        let names = boxes.iter().map(|(name, _)| name).collect::<Vec<&[u8; 4]>>();
+        assert_eq!(names, vec![b"ftyp", b"mdat", b"moov"]);
+
+        let traks = find(&output, &[b"moov", b"trak"]);
+        assert_eq!(traks.len(), 2);
+        let (video, audio) = (traks[0], traks[1]);
+
+        // 视频: 丢弃关键帧之前的帧, 参数集移到avcC中
+        let handler = find(video, &[b"mdia", b"hdlr"])[0];
+        assert_eq!(&handler[8..12], b"vide");
+        let tkhd = find(video, &[b"tkhd"])[0];
+        assert_eq!(u32_at(tkhd, tkhd.len() - 8) >> 16, 64);
+        assert_eq!(u32_at(tkhd, tkhd.len() - 4) >> 16, 64);
+        let stbl = find(video, &[b"mdia", b"minf", b"stbl"])[0];
+        let stsd = find(stbl, &[b"stsd"])[0];
+        assert_eq!(&stsd[12..16], b"avc1");
+        let stsz = find(stbl, &[b"stsz"])[0];
+        assert_eq!(u32_at(stsz, 8), 3);
+        assert_eq!(u32_at(stsz, 12), (4 + IDR.len()) as u32);
+        let stss = find(stbl, &[b"stss"])[0];
+        assert_eq!((u32_at(stss, 4), u32_at(stss, 8)), (1, 1));
+        let ctts = find(stbl, &[b"ctts"])[0];
+        assert_eq!((u32_at(ctts, 4), u32_at(ctts, 8), u32_at(ctts, 12)), (1, 3, 3000));
+        // 音频早开始33毫秒, 视频先空出这段时间, 再从第一帧的显示时间开始
+        let elst = find(video, &[b"edts", b"elst"])[0];
+        assert_eq!(u32_at(elst, 4), 2);
+        assert_eq!(u64::from_be_bytes(elst[8..16].try_into().unwrap()), 33);
+        assert_eq!(u64::from_be_bytes(elst[36..44].try_into().unwrap()), 3000);
+
+        // 音频: 每个ADTS帧是一个样本, 时间单位为采样率
+        let handler = find(audio, &[b"mdia", b"hdlr"])[0];
+        assert_eq!(&handler[8..12], b"soun");
+        let mdhd = find(audio, &[b"mdia", b"mdhd"])[0];
+        assert_eq!(u32_at(mdhd, 20), 44100);
+        let stbl = find(audio, &[b"mdia", b"minf", b"stbl"])[0];
+        let stsz = find(stbl, &[b"stsz"])[0];
+        assert_eq!(u32_at(stsz, 8), 4);
+        assert!((0..4).all(|i| u32_at(stsz, 12 + i * 4) == 10));
+        let stts = find(stbl, &[b"stts"])[0];
+        assert_eq!((u32_at(stts, 4), u32_at(stts, 8), u32_at(stts, 12)), (1, 4, 1024));
+        let stco = find(stbl, &[b"stco"])[0];
+        let first_chunk = u32_at(stco, 8) as usize;
+        assert_eq!(&output[first_chunk..first_chunk + 10], &[1; 10]);
+    }
+
+    fn put(buf: &mut Vec<u8>, values: &[u32]) {
+        values.iter().for_each(|v| buf.extend_from_slice(&v.to_be_bytes()));
+    }
+
+    /// 只有视频的普通MP4, 三帧, 第一帧是关键帧
+    fn video_mp4() -> Vec<u8> {
+        let mut writer = Mp4Writer::new(Cursor::new(vec![])).unwrap();
+        let entry = codec::avc_entry(&[SPS.to_vec()], &[PPS.to_vec()]).unwrap();
+        let track = writer.add_track(TS_TIMESCALE, entry);
+        for i in 0..3u64 {
+            writer.write_sample(track, &[i as u8 + 1; 20], i * 3000, 3000, i == 0).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// 只有音频的DASH分段文件: 带trex与编辑列表的初始化分段, 加一个moof与mdat
+    fn audio_m4s() -> Vec<u8> {
+        let entry = AdtsHeader::parse(&adts(&[])).unwrap().entry();
+        let mut file = vec![];
+        write_box(&mut file, b"ftyp", |buf| buf.extend_from_slice(b"iso6\0\0\0\0iso6"));
+        write_box(&mut file, b"moov", |buf| {
+            write_box(buf, b"trak", |buf| {
+                write_full_box(buf, b"tkhd", 0, 3, |buf| put(buf, &[0, 0, 2, 0, 0]));
+                // 跳过1024个采样的编码器延迟
+                write_box(buf, b"edts", |buf| write_full_box(buf, b"elst", 0, 0, |buf| put(buf, &[1, 0, 1024, 0x0001_0000])));
+                write_box(buf, b"mdia", |buf| {
+                    write_full_box(buf, b"mdhd", 0, 0, |buf| put(buf, &[0, 0, 44100, 0, 0x55c4_0000]));
+                    write_full_box(buf, b"hdlr", 0, 0, |buf| {
+                        put(buf, &[0]);
+                        buf.extend_from_slice(b"soun");
+                        buf.extend_from_slice(&[0; 13]);
+                    });
+                    write_box(buf, b"minf", |buf| write_box(buf, b"stbl", |buf| {
+                        write_full_box(buf, b"stsd", 0, 0, |buf| {
+                            put(buf, &[1]);
+                            write_sample_entry(buf, &entry);
+                        });
+                        for kind in [b"stts", b"stsc", b"stco"] {
+                            write_full_box(buf, kind, 0, 0, |buf| put(buf, &[0]));
+                        }
+                        write_full_box(buf, b"stsz", 0, 0, |buf| put(buf, &[0, 0]));
+                    }));
+                });
+            });
+            write_box(buf, b"mvex", |buf| write_full_box(buf, b"trex", 0, 0, |buf| put(buf, &[2, 1, 1024, 0, 0])));
+        });
+
+        // 数据位置相对moof的起始位置, 指向紧跟其后的mdat
+        let moof = |data_offset: u32| {
+            let mut moof = vec![];
+            write_box(&mut moof, b"moof", |buf| {
+                write_full_box(buf, b"mfhd", 0, 0, |buf| put(buf, &[1]));
+                write_box(buf, b"traf", |buf| {
+                    write_full_box(buf, b"tfhd", 0, 0x02_0000, |buf| put(buf, &[2]));
+                    write_full_box(buf, b"tfdt", 1, 0, |buf| put(buf, &[0, 2048]));
+                    write_full_box(buf, b"trun", 0, 0x0201, |buf| put(buf, &[4, data_offset, 10, 10, 10, 10]));
+                });
+            });
+            moof
+        };
+        let data_offset = moof(0).len() as u32 + 8;
+        file.extend(moof(data_offset));
+        write_box(&mut file, b"mdat", |buf| (1..=4).for_each(|i| buf.extend_from_slice(&[i; 10])));
+        file
+    }
+
+    /// 修改第一个指定类型box中的一个32位数值, offset从box内容开始计算
+    fn patch(data: &mut [u8], kind: &[u8; 4], offset: usize, value: u32) {
+        let position = data.windows(4).position(|w| w == kind).unwrap() + 4 + offset;
+        data[position..position + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    #[test]
+    fn rejects_oversized_sample_tables() {
+        // (box类型, 条目数的位置, 固定的样本大小)
+        let cases = [
+            (b"stsz", 8, None),
+            (b"stsz", 8, Some(20)),
+            (b"stco", 4, None),
+            (b"stts", 4, None),
+        ];
+        for (kind, offset, sample_size) in cases {
+            let mut data = video_mp4();
+            if let Some(sample_size) = sample_size {
+                patch(&mut data, kind, 4, sample_size);
+            }
+            patch(&mut data, kind, offset, u32::MAX);
+            let result = reader::read_track(&mut Cursor::new(data), true);
+            assert!(matches!(result, Err(MuxError::InvalidConfigError("trak"))), "{:?}", kind);
+        }
+        assert!(reader::read_track(&mut Cursor::new(video_mp4()), true).is_ok());
+    }
+
+    #[test]
+    fn muxes_video_and_fragmented_audio() {
+        let output = mux_tracks(Cursor::new(video_mp4()), Cursor::new(audio_m4s()), Cursor::new(vec![])).unwrap().into_inner();
+        let traks = find(&output, &[b"moov", b"trak"]);
+        assert_eq!(traks.len(), 2);
+        let (video, audio) = (traks[0], traks[1]);
+
+        let stbl = find(video, &[b"mdia", b"minf", b"stbl"])[0];
+        assert_eq!(&find(stbl, &[b"stsd"])[0][12..16], b"avc1");
+        assert_eq!(u32_at(find(stbl, &[b"stsz"])[0], 8), 3);
+        let stco = find(stbl, &[b"stco"])[0];
+        let first_chunk = u32_at(stco, 8) as usize;
+        assert_eq!(&output[first_chunk..first_chunk + 20], &[1; 20]);
+
+        // 保留音频的时间单位与编辑列表, 分段中的样本按trex的默认时长计算
+        let mdhd = find(audio, &[b"mdia", b"mdhd"])[0];
+        assert_eq!(u32_at(mdhd, 20), 44100);
+        let stbl = find(audio, &[b"mdia", b"minf", b"stbl"])[0];
+        assert_eq!(&find(stbl, &[b"stsd"])[0][12..16], b"mp4a");
+        assert_eq!(u32_at(find(stbl, &[b"stsz"])[0], 8), 4);
+        let stts = find(stbl, &[b"stts"])[0];
+        assert_eq!((u32_at(stts, 4), u32_at(stts, 8), u32_at(stts, 12)), (1, 4, 1024));
+        let elst = find(audio, &[b"edts", b"elst"])[0];
+        assert_eq!(u64::from_be_bytes(elst[elst.len() - 12..elst.len() - 4].try_into().unwrap()), 1024);
+        let stco = find(stbl, &[b"stco"])[0];
+        let first_chunk = u32_at(stco, 8) as usize;
+        assert_eq!(&output[first_chunk..first_chunk + 40], &[[1; 10], [2; 10], [3; 10], [4; 10]].concat()[..]);
+    }
+}
@@ -0,0 +1,74 @@
+/// 去掉NAL单元中的防竞争字节, 00 00 03 中的03不属于实际数据
+pub fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        result.push(byte);
+    }
+    result
+}
+
+/// 按位读取, 用于解析SPS与ADTS头, 数据不足时返回None
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    // 已读取的位数
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read_bit(&mut self) -> Option<u32> {
+        let byte = *self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit as u32)
+    }
+
+    /// 最多读取32位
+    pub fn read_bits(&mut self, count: usize) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()?;
+        }
+        Some(value)
+    }
+
+    pub fn read_flag(&mut self) -> Option<bool> {
+        Some(self.read_bit()? == 1)
+    }
+
+    pub fn skip(&mut self, count: usize) -> Option<()> {
+        if self.position + count > self.data.len() * 8 {
+            return None;
+        }
+        self.position += count;
+        Some(())
+    }
+
+    /// 无符号指数哥伦布编码
+    pub fn read_ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.read_bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some(((1u64 << zeros) - 1 + self.read_bits(zeros)? as u64) as u32)
+    }
+
+    /// 有符号指数哥伦布编码
+    pub fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()? as i64;
+        Some(if value % 2 == 1 { ((value + 1) / 2) as i32 } else { -(value / 2) as i32 })
+    }
+}
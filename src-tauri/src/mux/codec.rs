@@ -0,0 +1,311 @@
+use super::bits::{BitReader, unescape};
+use super::MuxError;
+
+// ADTS头中的采样率序号对应的采样率
+const SAMPLE_RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+
+// 每个AAC帧包含的采样数
+pub const AAC_FRAME_SAMPLES: u64 = 1024;
+
+/// MP4的stsd中一个轨道的编码信息
+#[derive(Debug, Clone)]
+pub enum SampleEntry {
+    Video {
+        // avc1或hvc1
+        format: [u8; 4],
+        width: u16,
+        height: u16,
+        // avcC或hvcC
+        config_type: [u8; 4],
+        config: Vec<u8>,
+    },
+    Audio {
+        sample_rate: u32,
+        channels: u16,
+        // AudioSpecificConfig
+        config: Vec<u8>,
+    },
+    // 从其他MP4的stsd中原样复制的条目
+    Copied {
+        video: bool,
+        width: u16,
+        height: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl SampleEntry {
+
+    pub fn is_video(&self) -> bool {
+        match self {
+            SampleEntry::Video { .. } => true,
+            SampleEntry::Audio { .. } => false,
+            SampleEntry::Copied { video, .. } => *video,
+        }
+    }
+
+    /// 视频的宽高, 音频为0
+    pub fn dimensions(&self) -> (u16, u16) {
+        match self {
+            SampleEntry::Video { width, height, .. } | SampleEntry::Copied { width, height, .. } => (*width, *height),
+            SampleEntry::Audio { .. } => (0, 0),
+        }
+    }
+}
+
+/// H.264 SPS中的宽高, 按裁剪区域修正
+fn avc_dimensions(sps: &[u8]) -> Option<(u16, u16)> {
+    let data = unescape(sps);
+    let mut reader = BitReader::new(data.get(1..)?);
+    let profile_idc = reader.read_bits(8)?;
+    reader.skip(16)?;
+    reader.read_ue()?;
+
+    let mut chroma_format_idc = 1;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc) {
+        chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc == 3 {
+            reader.skip(1)?;
+        }
+        reader.read_ue()?;
+        reader.read_ue()?;
+        reader.skip(1)?;
+        if reader.read_flag()? {
+            let count = if chroma_format_idc != 3 { 8 } else { 12 };
+            for i in 0..count {
+                if !reader.read_flag()? {
+                    continue;
+                }
+                // 跳过scaling_list, 只需要读出其中的指数哥伦布编码
+                let size = if i < 6 { 16 } else { 64 };
+                let (mut last, mut next) = (8i32, 8i32);
+                for _ in 0..size {
+                    if next != 0 {
+                        next = (last + reader.read_se()? + 256) % 256;
+                    }
+                    if next != 0 {
+                        last = next;
+                    }
+                }
+            }
+        }
+    }
+
+    reader.read_ue()?;
+    match reader.read_ue()? {
+        0 => {
+            reader.read_ue()?;
+        },
+        1 => {
+            reader.skip(1)?;
+            reader.read_se()?;
+            reader.read_se()?;
+            for _ in 0..reader.read_ue()? {
+                reader.read_se()?;
+            }
+        },
+        _ => {},
+    }
+    reader.read_ue()?;
+    reader.skip(1)?;
+
+    let width_mbs = reader.read_ue()? + 1;
+    let height_map_units = reader.read_ue()? + 1;
+    let frame_mbs_only = reader.read_bit()?;
+    if frame_mbs_only == 0 {
+        reader.skip(1)?;
+    }
+    reader.skip(1)?;
+
+    let mut width = width_mbs * 16;
+    let mut height = (2 - frame_mbs_only) * height_map_units * 16;
+    if reader.read_flag()? {
+        let (left, right, top, bottom) = (reader.read_ue()?, reader.read_ue()?, reader.read_ue()?, reader.read_ue()?);
+        let (crop_x, crop_y) = match chroma_format_idc {
+            0 => (1, 2 - frame_mbs_only),
+            1 => (2, 2 * (2 - frame_mbs_only)),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        width = width.checked_sub((left + right) * crop_x)?;
+        height = height.checked_sub((top + bottom) * crop_y)?;
+    }
+    Some((width as u16, height as u16))
+}
+
+/// 由SPS与PPS生成avc1的编码信息
+pub fn avc_entry(sps: &[Vec<u8>], pps: &[Vec<u8>]) -> Result<SampleEntry, MuxError> {
+    let first = sps.first().filter(|s| s.len() >= 4).ok_or(MuxError::MissingConfigError("SPS"))?;
+    if pps.is_empty() {
+        return Err(MuxError::MissingConfigError("PPS"));
+    }
+    let (width, height) = avc_dimensions(first).ok_or(MuxError::InvalidConfigError("SPS"))?;
+
+    // 长度字段使用4字节
+    let mut config = vec![1, first[1], first[2], first[3], 0xff, 0xe0 | sps.len() as u8];
+    for nal in sps {
+        config.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+        config.extend_from_slice(nal);
+    }
+    config.push(pps.len() as u8);
+    for nal in pps {
+        config.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+        config.extend_from_slice(nal);
+    }
+    Ok(SampleEntry::Video { format: *b"avc1", width, height, config_type: *b"avcC", config })
+}
+
+/// H.265 SPS中hvcC需要的字段
+struct HevcSps {
+    // general_profile_tier_level的12个字节
+    profile_tier_level: [u8; 12],
+    max_sub_layers: u8,
+    temporal_id_nested: bool,
+    chroma_format_idc: u32,
+    bit_depth_luma_minus8: u32,
+    bit_depth_chroma_minus8: u32,
+    width: u16,
+    height: u16,
+}
+
+fn parse_hevc_sps(sps: &[u8]) -> Option<HevcSps> {
+    let data = unescape(sps);
+    // 跳过2字节的NAL头
+    let mut reader = BitReader::new(data.get(2..)?);
+    reader.skip(4)?;
+    let max_sub_layers_minus1 = reader.read_bits(3)? as usize;
+    let temporal_id_nested = reader.read_flag()?;
+
+    let mut profile_tier_level = [0u8; 12];
+    for byte in profile_tier_level.iter_mut() {
+        *byte = reader.read_bits(8)? as u8;
+    }
+    let mut present = vec![];
+    for _ in 0..max_sub_layers_minus1 {
+        present.push((reader.read_flag()?, reader.read_flag()?));
+    }
+    if max_sub_layers_minus1 > 0 {
+        reader.skip((8 - max_sub_layers_minus1) * 2)?;
+    }
+    for (profile_present, level_present) in present {
+        if profile_present {
+            reader.skip(88)?;
+        }
+        if level_present {
+            reader.skip(8)?;
+        }
+    }
+
+    reader.read_ue()?;
+    let chroma_format_idc = reader.read_ue()?;
+    if chroma_format_idc == 3 {
+        reader.skip(1)?;
+    }
+    let mut width = reader.read_ue()?;
+    let mut height = reader.read_ue()?;
+    if reader.read_flag()? {
+        let (left, right, top, bottom) = (reader.read_ue()?, reader.read_ue()?, reader.read_ue()?, reader.read_ue()?);
+        let (sub_width, sub_height) = match chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        width = width.checked_sub((left + right) * sub_width)?;
+        height = height.checked_sub((top + bottom) * sub_height)?;
+    }
+    Some(HevcSps {
+        profile_tier_level,
+        max_sub_layers: max_sub_layers_minus1 as u8 + 1,
+        temporal_id_nested,
+        chroma_format_idc,
+        bit_depth_luma_minus8: reader.read_ue()?,
+        bit_depth_chroma_minus8: reader.read_ue()?,
+        width: width as u16,
+        height: height as u16,
+    })
+}
+
+/// 由VPS, SPS与PPS生成hvc1的编码信息, 参数集只放在hvcC中
+pub fn hevc_entry(vps: &[Vec<u8>], sps: &[Vec<u8>], pps: &[Vec<u8>]) -> Result<SampleEntry, MuxError> {
+    let first = sps.first().ok_or(MuxError::MissingConfigError("SPS"))?;
+    if vps.is_empty() {
+        return Err(MuxError::MissingConfigError("VPS"));
+    }
+    if pps.is_empty() {
+        return Err(MuxError::MissingConfigError("PPS"));
+    }
+    let info = parse_hevc_sps(first).ok_or(MuxError::InvalidConfigError("SPS"))?;
+
+    let mut config = vec![1];
+    config.extend_from_slice(&info.profile_tier_level);
+    config.extend_from_slice(&[
+        0xf0, 0x00,
+        0xfc,
+        0xfc | info.chroma_format_idc as u8,
+        0xf8 | info.bit_depth_luma_minus8 as u8,
+        0xf8 | info.bit_depth_chroma_minus8 as u8,
+        0x00, 0x00,
+        // 帧率不固定, 时间层数, 长度字段使用4字节
+        (info.max_sub_layers << 3) | ((info.temporal_id_nested as u8) << 2) | 0x03,
+        3,
+    ]);
+    for (nal_type, nals) in [(32u8, vps), (33, sps), (34, pps)] {
+        config.push(0x80 | nal_type);
+        config.extend_from_slice(&(nals.len() as u16).to_be_bytes());
+        for nal in nals {
+            config.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            config.extend_from_slice(nal);
+        }
+    }
+    Ok(SampleEntry::Video { format: *b"hvc1", width: info.width, height: info.height, config_type: *b"hvcC", config })
+}
+
+/// ADTS帧头
+#[derive(Debug, Clone, Copy)]
+pub struct AdtsHeader {
+    pub object_type: u8,
+    pub sample_rate_index: u8,
+    pub channels: u8,
+    // 帧头长度, 有CRC时为9字节
+    pub header_len: usize,
+    // 包含帧头的整个帧的长度
+    pub frame_len: usize,
+}
+
+impl AdtsHeader {
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut reader = BitReader::new(data.get(..7)?);
+        if reader.read_bits(12)? != 0xfff {
+            return None;
+        }
+        reader.skip(3)?;
+        let protection_absent = reader.read_flag()?;
+        let object_type = reader.read_bits(2)? as u8 + 1;
+        let sample_rate_index = reader.read_bits(4)? as u8;
+        reader.skip(1)?;
+        let channels = reader.read_bits(3)? as u8;
+        reader.skip(4)?;
+        let frame_len = reader.read_bits(13)? as usize;
+        let header_len = if protection_absent { 7 } else { 9 };
+        if sample_rate_index as usize >= SAMPLE_RATES.len() || frame_len < header_len {
+            return None;
+        }
+        Some(Self { object_type, sample_rate_index, channels, header_len, frame_len })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        SAMPLE_RATES[self.sample_rate_index as usize]
+    }
+
+    pub fn entry(&self) -> SampleEntry {
+        let config = ((self.object_type as u16) << 11)
+            | ((self.sample_rate_index as u16) << 7)
+            | ((self.channels as u16) << 3);
+        SampleEntry::Audio {
+            sample_rate: self.sample_rate(),
+            channels: self.channels as u16,
+            config: config.to_be_bytes().to_vec(),
+        }
+    }
+}
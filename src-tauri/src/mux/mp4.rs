@@ -0,0 +1,425 @@
+use std::io::{self, Seek, SeekFrom, Write};
+use super::codec::SampleEntry;
+
+// 整个文件的时间单位, 毫秒
+const MOVIE_TIMESCALE: u32 = 1000;
+
+// tkhd与mvhd中的单位矩阵
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    size: u32,
+    // 解码时间, 以轨道的时间单位计
+    dts: u64,
+    // 显示时间与解码时间的差
+    cts_offset: i64,
+    sync: bool,
+}
+
+/// 一个轨道的编码信息与所有样本的位置
+#[derive(Debug)]
+struct Track {
+    timescale: u32,
+    entry: SampleEntry,
+    // 编辑列表中媒体开始的位置, 为空时从最早的显示时间开始
+    media_time: Option<u64>,
+    samples: Vec<Sample>,
+    // 每个chunk的起始位置与样本数
+    chunks: Vec<(u64, u32)>,
+}
+
+impl Track {
+
+    /// 每个样本的时长, 最后一个样本沿用前一个的时长
+    fn durations(&self) -> Vec<u64> {
+        let mut durations = self.samples.windows(2)
+            .map(|pair| pair[1].dts.saturating_sub(pair[0].dts))
+            .collect::<Vec<u64>>();
+        durations.push(durations.last().copied().unwrap_or(0));
+        durations
+    }
+
+    /// 编辑列表跳过的媒体时长, 以轨道的时间单位计
+    fn media_time(&self) -> u64 {
+        self.media_time.unwrap_or_else(|| {
+            let first_pts = self.samples.iter()
+                .map(|s| s.dts as i64 + s.cts_offset)
+                .min()
+                .unwrap_or(0);
+            std::cmp::max(first_pts - self.samples[0].dts as i64, 0) as u64
+        })
+    }
+
+    /// 开始显示的时间, 以影片的时间单位计
+    fn start(&self) -> u64 {
+        rescale(self.samples[0].dts + self.media_time(), self.timescale, MOVIE_TIMESCALE)
+    }
+}
+
+pub(super) fn write_box(buf: &mut Vec<u8>, kind: &[u8; 4], content: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(kind);
+    content(buf);
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+pub(super) fn write_full_box(buf: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, content: impl FnOnce(&mut Vec<u8>)) {
+    write_box(buf, kind, |buf| {
+        buf.push(version);
+        buf.extend_from_slice(&flags.to_be_bytes()[1..]);
+        content(buf);
+    });
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+/// 时间单位换算, 向下取整
+fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    (value as u128 * to as u128 / std::cmp::max(from, 1) as u128) as u64
+}
+
+/// 写入ftyp与mdat后按顺序追加样本, 最后在文件末尾写入moov
+pub struct Mp4Writer<W: Write + Seek> {
+    out: W,
+    tracks: Vec<Track>,
+    // mdat头的位置, 结束时回填大小
+    mdat_start: u64,
+    position: u64,
+    last_track: Option<usize>,
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut header = vec![];
+        write_box(&mut header, b"ftyp", |buf| {
+            buf.extend_from_slice(b"isom");
+            put_u32(buf, 0x200);
+            for brand in [b"isom", b"iso2", b"avc1", b"mp41"] {
+                buf.extend_from_slice(brand);
+            }
+        });
+        let mdat_start = header.len() as u64;
+        // 大小未知, 先用64位的largesize占位
+        put_u32(&mut header, 1);
+        header.extend_from_slice(b"mdat");
+        put_u64(&mut header, 0);
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            tracks: vec![],
+            mdat_start,
+            position: header.len() as u64,
+            last_track: None,
+        })
+    }
+
+    pub fn add_track(&mut self, timescale: u32, entry: SampleEntry) -> usize {
+        self.tracks.push(Track { timescale, entry, media_time: None, samples: vec![], chunks: vec![] });
+        self.tracks.len() - 1
+    }
+
+    /// 沿用原文件编辑列表中的媒体开始位置, 例如AAC的编码器延迟
+    pub fn set_media_time(&mut self, track: usize, media_time: u64) {
+        self.tracks[track].media_time = Some(media_time);
+    }
+
+    /// 追加一个样本, 同一轨道连续的样本放在同一个chunk中
+    pub fn write_sample(&mut self, track: usize, data: &[u8], dts: u64, cts_offset: i64, sync: bool) -> io::Result<()> {
+        self.out.write_all(data)?;
+        let state = &mut self.tracks[track];
+        match state.chunks.last_mut() {
+            Some((_, count)) if self.last_track == Some(track) => *count += 1,
+            _ => state.chunks.push((self.position, 1)),
+        }
+        state.samples.push(Sample { size: data.len() as u32, dts, cts_offset, sync });
+        self.position += data.len() as u64;
+        self.last_track = Some(track);
+        Ok(())
+    }
+
+    /// 回填mdat大小并写入moov, 没有样本的轨道会被忽略, 视频轨道排在前面
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(self.mdat_start + 8))?;
+        self.out.write_all(&(self.position - self.mdat_start).to_be_bytes())?;
+        self.out.seek(SeekFrom::Start(self.position))?;
+
+        let mut tracks = self.tracks.iter()
+            .filter(|t| !t.samples.is_empty())
+            .collect::<Vec<&Track>>();
+        tracks.sort_by_key(|t| !t.entry.is_video());
+        if tracks.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no track to write"));
+        }
+        // 所有轨道中最早的显示时间对应影片的0时刻
+        let start = tracks.iter().map(|t| t.start()).min().unwrap_or(0);
+
+        let mut moov = vec![];
+        let mut duration = 0;
+        let mut traks = vec![];
+        for (index, track) in tracks.iter().enumerate() {
+            let (trak, track_duration) = write_trak(track, index as u32 + 1, start);
+            duration = std::cmp::max(duration, track_duration);
+            traks.extend(trak);
+        }
+        write_box(&mut moov, b"moov", |buf| {
+            write_full_box(buf, b"mvhd", 1, 0, |buf| {
+                put_u64(buf, 0);
+                put_u64(buf, 0);
+                put_u32(buf, MOVIE_TIMESCALE);
+                put_u64(buf, duration);
+                put_u32(buf, 0x0001_0000);
+                put_u16(buf, 0x0100);
+                buf.extend_from_slice(&[0; 10]);
+                MATRIX.iter().for_each(|v| put_u32(buf, *v));
+                buf.extend_from_slice(&[0; 24]);
+                put_u32(buf, tracks.len() as u32 + 1);
+            });
+            buf.extend_from_slice(&traks);
+        });
+        self.out.write_all(&moov)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// 生成trak, 返回内容与以影片时间单位计的时长
+fn write_trak(track: &Track, track_id: u32, start: u64) -> (Vec<u8>, u64) {
+    let entry = &track.entry;
+    let durations = track.durations();
+    let media_duration: u64 = durations.iter().sum();
+    // 跳过第一个样本显示之前的部分, 再用空的编辑补齐与其他轨道的起始时间差
+    let media_time = track.media_time();
+    let delay = track.start().saturating_sub(start);
+    let edit_duration = rescale(media_duration.saturating_sub(media_time), track.timescale, MOVIE_TIMESCALE);
+    let duration = delay + edit_duration;
+
+    let is_video = entry.is_video();
+    let (width, height) = entry.dimensions();
+
+    let mut trak = vec![];
+    write_box(&mut trak, b"trak", |buf| {
+        // 启用并用于播放
+        write_full_box(buf, b"tkhd", 1, 0x03, |buf| {
+            put_u64(buf, 0);
+            put_u64(buf, 0);
+            put_u32(buf, track_id);
+            put_u32(buf, 0);
+            put_u64(buf, duration);
+            buf.extend_from_slice(&[0; 8]);
+            put_u16(buf, 0);
+            put_u16(buf, 0);
+            put_u16(buf, if is_video { 0 } else { 0x0100 });
+            put_u16(buf, 0);
+            MATRIX.iter().for_each(|v| put_u32(buf, *v));
+            put_u32(buf, (width as u32) << 16);
+            put_u32(buf, (height as u32) << 16);
+        });
+        write_box(buf, b"edts", |buf| {
+            let count = if delay > 0 { 2 } else { 1 };
+            write_full_box(buf, b"elst", 1, 0, |buf| {
+                put_u32(buf, count);
+                if delay > 0 {
+                    put_u64(buf, delay);
+                    put_u64(buf, u64::MAX);
+                    put_u32(buf, 0x0001_0000);
+                }
+                put_u64(buf, edit_duration);
+                put_u64(buf, media_time);
+                put_u32(buf, 0x0001_0000);
+            });
+        });
+        write_box(buf, b"mdia", |buf| {
+            write_full_box(buf, b"mdhd", 1, 0, |buf| {
+                put_u64(buf, 0);
+                put_u64(buf, 0);
+                put_u32(buf, track.timescale);
+                put_u64(buf, media_duration);
+                // 语言und
+                put_u16(buf, 0x55c4);
+                put_u16(buf, 0);
+            });
+            write_full_box(buf, b"hdlr", 0, 0, |buf| {
+                put_u32(buf, 0);
+                buf.extend_from_slice(if is_video { b"vide" } else { b"soun" });
+                buf.extend_from_slice(&[0; 12]);
+                buf.extend_from_slice(if is_video { b"VideoHandler\0" as &[u8] } else { b"SoundHandler\0" });
+            });
+            write_box(buf, b"minf", |buf| {
+                if is_video {
+                    write_full_box(buf, b"vmhd", 0, 1, |buf| buf.extend_from_slice(&[0; 8]));
+                } else {
+                    write_full_box(buf, b"smhd", 0, 0, |buf| buf.extend_from_slice(&[0; 4]));
+                }
+                write_box(buf, b"dinf", |buf| {
+                    write_full_box(buf, b"dref", 0, 0, |buf| {
+                        put_u32(buf, 1);
+                        // 数据在同一个文件中
+                        write_full_box(buf, b"url ", 0, 1, |_| {});
+                    });
+                });
+                write_stbl(buf, track, entry, &durations);
+            });
+        });
+    });
+    (trak, duration)
+}
+
+pub(super) fn write_sample_entry(buf: &mut Vec<u8>, entry: &SampleEntry) {
+    match entry {
+        SampleEntry::Video { format, width, height, config_type, config } => write_box(buf, format, |buf| {
+            buf.extend_from_slice(&[0; 6]);
+            put_u16(buf, 1);
+            buf.extend_from_slice(&[0; 16]);
+            put_u16(buf, *width);
+            put_u16(buf, *height);
+            // 72dpi
+            put_u32(buf, 0x0048_0000);
+            put_u32(buf, 0x0048_0000);
+            put_u32(buf, 0);
+            put_u16(buf, 1);
+            buf.extend_from_slice(&[0; 32]);
+            put_u16(buf, 0x0018);
+            put_u16(buf, 0xffff);
+            write_box(buf, config_type, |buf| buf.extend_from_slice(config));
+        }),
+        SampleEntry::Audio { sample_rate, channels, config } => write_box(buf, b"mp4a", |buf| {
+            buf.extend_from_slice(&[0; 6]);
+            put_u16(buf, 1);
+            buf.extend_from_slice(&[0; 8]);
+            put_u16(buf, *channels);
+            put_u16(buf, 16);
+            put_u32(buf, 0);
+            // 16.16定点数, 超过65535的采样率只能写0
+            put_u32(buf, if *sample_rate <= 0xffff { sample_rate << 16 } else { 0 });
+            write_full_box(buf, b"esds", 0, 0, |buf| write_es_descriptor(buf, config));
+        }),
+        SampleEntry::Copied { data, .. } => buf.extend_from_slice(data),
+    }
+}
+
+/// esds中的ES_Descriptor, 描述符都不超过127字节, 长度用一个字节表示
+fn write_es_descriptor(buf: &mut Vec<u8>, config: &[u8]) {
+    let decoder_specific_len = config.len();
+    let decoder_config_len = 13 + 2 + decoder_specific_len;
+    let es_len = 3 + 2 + decoder_config_len + 3;
+
+    buf.extend_from_slice(&[0x03, es_len as u8]);
+    put_u16(buf, 0);
+    buf.push(0);
+    buf.extend_from_slice(&[0x04, decoder_config_len as u8]);
+    // MPEG-4 AAC, 音频流
+    buf.push(0x40);
+    buf.push(0x15);
+    buf.extend_from_slice(&[0; 3]);
+    put_u32(buf, 0);
+    put_u32(buf, 0);
+    buf.extend_from_slice(&[0x05, decoder_specific_len as u8]);
+    buf.extend_from_slice(config);
+    buf.extend_from_slice(&[0x06, 0x01, 0x02]);
+}
+
+fn write_stbl(buf: &mut Vec<u8>, track: &Track, entry: &SampleEntry, durations: &[u64]) {
+    let samples = &track.samples;
+    write_box(buf, b"stbl", |buf| {
+        write_full_box(buf, b"stsd", 0, 0, |buf| {
+            put_u32(buf, 1);
+            write_sample_entry(buf, entry);
+        });
+
+        // 连续相同的时长合并为一项
+        let mut runs: Vec<(u32, u64)> = vec![];
+        for duration in durations {
+            match runs.last_mut() {
+                Some((count, last)) if last == duration => *count += 1,
+                _ => runs.push((1, *duration)),
+            }
+        }
+        write_full_box(buf, b"stts", 0, 0, |buf| {
+            put_u32(buf, runs.len() as u32);
+            for (count, duration) in &runs {
+                put_u32(buf, *count);
+                put_u32(buf, *duration as u32);
+            }
+        });
+
+        if samples.iter().any(|s| s.cts_offset != 0) {
+            let mut runs: Vec<(u32, i64)> = vec![];
+            for sample in samples {
+                match runs.last_mut() {
+                    Some((count, last)) if *last == sample.cts_offset => *count += 1,
+                    _ => runs.push((1, sample.cts_offset)),
+                }
+            }
+            // 有负的偏移时使用有符号的版本1
+            let version = if samples.iter().any(|s| s.cts_offset < 0) { 1 } else { 0 };
+            write_full_box(buf, b"ctts", version, 0, |buf| {
+                put_u32(buf, runs.len() as u32);
+                for (count, offset) in &runs {
+                    put_u32(buf, *count);
+                    put_u32(buf, *offset as i32 as u32);
+                }
+            });
+        }
+
+        // 全部是关键帧时省略stss
+        if samples.iter().any(|s| !s.sync) {
+            let sync = samples.iter()
+                .enumerate()
+                .filter(|(_, s)| s.sync)
+                .map(|(i, _)| i as u32 + 1)
+                .collect::<Vec<u32>>();
+            write_full_box(buf, b"stss", 0, 0, |buf| {
+                put_u32(buf, sync.len() as u32);
+                sync.iter().for_each(|i| put_u32(buf, *i));
+            });
+        }
+
+        write_full_box(buf, b"stsc", 0, 0, |buf| {
+            let mut entries: Vec<(u32, u32)> = vec![];
+            for (index, (_, count)) in track.chunks.iter().enumerate() {
+                if entries.last().map_or(true, |(_, last)| last != count) {
+                    entries.push((index as u32 + 1, *count));
+                }
+            }
+            put_u32(buf, entries.len() as u32);
+            for (first_chunk, count) in entries {
+                put_u32(buf, first_chunk);
+                put_u32(buf, count);
+                put_u32(buf, 1);
+            }
+        });
+
+        write_full_box(buf, b"stsz", 0, 0, |buf| {
+            put_u32(buf, 0);
+            put_u32(buf, samples.len() as u32);
+            samples.iter().for_each(|s| put_u32(buf, s.size));
+        });
+
+        // 超过4GB时使用64位的chunk位置
+        if track.chunks.last().map_or(false, |(offset, _)| *offset > u32::MAX as u64) {
+            write_full_box(buf, b"co64", 0, 0, |buf| {
+                put_u32(buf, track.chunks.len() as u32);
+                track.chunks.iter().for_each(|(offset, _)| put_u64(buf, *offset));
+            });
+        } else {
+            write_full_box(buf, b"stco", 0, 0, |buf| {
+                put_u32(buf, track.chunks.len() as u32);
+                track.chunks.iter().for_each(|(offset, _)| put_u32(buf, *offset as u32));
+            });
+        }
+    });
+}
@@ -0,0 +1,428 @@
+use std::{collections::HashMap, io::{self, Read, Seek, SeekFrom}};
+use super::codec::SampleEntry;
+use super::MuxError;
+
+/// 输入文件中一个样本的位置与时间
+#[derive(Debug, Clone, Copy)]
+pub struct InputSample {
+    pub offset: u64,
+    pub size: u32,
+    pub dts: u64,
+    pub cts_offset: i64,
+    pub sync: bool,
+}
+
+/// 从MP4或DASH分段文件中读出的一个轨道
+#[derive(Debug)]
+pub struct InputTrack {
+    pub timescale: u32,
+    pub entry: SampleEntry,
+    // 编辑列表中第一个非空编辑的媒体开始位置
+    pub media_time: Option<u64>,
+    pub samples: Vec<InputSample>,
+}
+
+/// moov中的一个trak, 分段中的样本按track_id追加
+#[derive(Debug, Default)]
+struct TrakInfo {
+    track_id: u32,
+    handler: [u8; 4],
+    timescale: u32,
+    entry: Option<SampleEntry>,
+    media_time: Option<u64>,
+    samples: Vec<InputSample>,
+    // 下一个样本的解码时间, 分段没有tfdt时接着上一个分段
+    next_dts: u64,
+    // trex中的默认时长, 大小与标志
+    defaults: (u32, u32, u32),
+}
+
+/// 按大端序读取box内容, 数据不足时返回None
+struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position.checked_add(len)?)?;
+        self.position += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    /// 表的条目数, 超出剩余数据能容纳的条目数时返回None, 不按损坏的数量分配内存
+    fn entry_count(&mut self, entry_size: usize) -> Option<usize> {
+        let count = self.u32()? as usize;
+        match count <= (self.data.len() - self.position) / entry_size {
+            true => Some(count),
+            false => None,
+        }
+    }
+
+    /// full box的版本与24位标志
+    fn version_flags(&mut self) -> Option<(u8, u32)> {
+        let value = self.u32()?;
+        Some(((value >> 24) as u8, value & 0x00ff_ffff))
+    }
+}
+
+/// 拆分box内容中的子box, 大小为0表示一直到末尾, 为1时使用64位大小
+pub fn children(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut result = vec![];
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let mut reader = ByteReader::new(&data[offset..]);
+        let (size, kind) = match (reader.u32(), reader.bytes(4)) {
+            (Some(size), Some(kind)) => (size as u64, [kind[0], kind[1], kind[2], kind[3]]),
+            _ => break,
+        };
+        let (size, header) = match size {
+            0 => ((data.len() - offset) as u64, 8),
+            1 => match reader.u64() {
+                Some(size) => (size, 16),
+                None => break,
+            },
+            size => (size, 8),
+        };
+        let end = match usize::try_from(size).ok().and_then(|size| offset.checked_add(size)) {
+            Some(end) if end <= data.len() && size >= header => end,
+            _ => break,
+        };
+        result.push((kind, &data[offset + header as usize..end]));
+        offset = end;
+    }
+    result
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    children(data).into_iter().find(|(name, _)| name == kind).map(|(_, body)| body)
+}
+
+fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| child(data, kind))
+}
+
+fn invalid(name: &'static str) -> MuxError {
+    MuxError::InvalidConfigError(name)
+}
+
+/// stsd中的第一个条目, 视频条目的宽高在固定位置
+fn parse_stsd(data: &[u8], video: bool) -> Option<SampleEntry> {
+    let mut reader = ByteReader::new(data);
+    reader.version_flags()?;
+    reader.u32()?;
+    let size = reader.u32()? as usize;
+    let entry = data.get(8..8 + size)?;
+    let (width, height) = match video {
+        true => {
+            let mut reader = ByteReader::new(entry.get(32..36)?);
+            (reader.u16()?, reader.u16()?)
+        },
+        false => (0, 0),
+    };
+    Some(SampleEntry::Copied { video, width, height, data: entry.to_vec() })
+}
+
+/// 成对的数量与数值, 用于stts与ctts
+fn parse_runs(data: &[u8]) -> Option<Vec<(u32, u32)>> {
+    let mut reader = ByteReader::new(data);
+    reader.version_flags()?;
+    let count = reader.entry_count(8)?;
+    (0..count).map(|_| Some((reader.u32()?, reader.u32()?))).collect()
+}
+
+fn parse_elst(data: &[u8]) -> Option<Option<u64>> {
+    let mut reader = ByteReader::new(data);
+    let (version, _) = reader.version_flags()?;
+    for _ in 0..reader.u32()? {
+        let media_time = match version {
+            1 => {
+                reader.u64()?;
+                reader.u64()? as i64
+            },
+            _ => {
+                reader.u32()?;
+                reader.u32()? as i32 as i64
+            },
+        };
+        reader.u32()?;
+        // -1是空编辑
+        if media_time >= 0 {
+            return Some(Some(media_time as u64));
+        }
+    }
+    Some(None)
+}
+
+/// 由样本表计算每个样本的位置与时间, file_len用于检查固定大小的样本数
+fn parse_stbl(stbl: &[u8], file_len: u64) -> Option<(Vec<InputSample>, u64)> {
+    let mut reader = ByteReader::new(child(stbl, b"stsz")?);
+    reader.version_flags()?;
+    let sample_size = reader.u32()?;
+    let (count, sizes) = match sample_size {
+        0 => {
+            let count = reader.entry_count(4)?;
+            (count, (0..count).map(|_| reader.u32()).collect::<Option<Vec<u32>>>()?)
+        },
+        // 固定大小时没有条目, 样本都在文件中, 数量不会超过文件长度除以样本大小
+        size => match reader.u32()? as u64 {
+            count if count <= file_len / size as u64 => (count as usize, vec![]),
+            _ => return None,
+        },
+    };
+    let size_of = |index: usize| match sample_size {
+        0 => sizes.get(index).copied(),
+        size if index < count => Some(size),
+        _ => None,
+    };
+
+    let offsets = match (child(stbl, b"stco"), child(stbl, b"co64")) {
+        (Some(stco), _) => {
+            let mut reader = ByteReader::new(stco);
+            reader.version_flags()?;
+            (0..reader.entry_count(4)?).map(|_| reader.u32().map(u64::from)).collect::<Option<Vec<u64>>>()?
+        },
+        (None, Some(co64)) => {
+            let mut reader = ByteReader::new(co64);
+            reader.version_flags()?;
+            (0..reader.entry_count(8)?).map(|_| reader.u64()).collect::<Option<Vec<u64>>>()?
+        },
+        (None, None) => vec![],
+    };
+
+    let mut reader = ByteReader::new(child(stbl, b"stsc")?);
+    reader.version_flags()?;
+    let stsc = (0..reader.entry_count(12)?)
+        .map(|_| Some((reader.u32()?, reader.u32()?, reader.u32()?)))
+        .collect::<Option<Vec<(u32, u32, u32)>>>()?;
+
+    let mut samples = Vec::with_capacity(sizes.len());
+    let mut entry = 0;
+    for (index, chunk_offset) in offsets.iter().enumerate() {
+        while entry + 1 < stsc.len() && stsc[entry + 1].0 as usize <= index + 1 {
+            entry += 1;
+        }
+        let per_chunk = stsc.get(entry).map_or(0, |e| e.1);
+        let mut offset = *chunk_offset;
+        for _ in 0..per_chunk {
+            let size = match size_of(samples.len()) {
+                Some(size) => size,
+                None => break,
+            };
+            samples.push(InputSample { offset, size, dts: 0, cts_offset: 0, sync: true });
+            offset += size as u64;
+        }
+    }
+
+    let mut dts = 0;
+    let mut durations = parse_runs(child(stbl, b"stts")?)?
+        .into_iter()
+        .flat_map(|(count, delta)| std::iter::repeat(delta).take(count as usize));
+    for sample in samples.iter_mut() {
+        sample.dts = dts;
+        dts += durations.next().unwrap_or(0) as u64;
+    }
+    if let Some(ctts) = child(stbl, b"ctts") {
+        // 版本0本应是无符号数, 但常有编码器写入负数
+        let offsets = parse_runs(ctts)?
+            .into_iter()
+            .flat_map(|(count, offset)| std::iter::repeat(offset as i32 as i64).take(count as usize));
+        for (sample, offset) in samples.iter_mut().zip(offsets) {
+            sample.cts_offset = offset;
+        }
+    }
+    if let Some(stss) = child(stbl, b"stss") {
+        let mut reader = ByteReader::new(stss);
+        reader.version_flags()?;
+        samples.iter_mut().for_each(|s| s.sync = false);
+        for _ in 0..reader.entry_count(4)? {
+            if let Some(sample) = samples.get_mut((reader.u32()? as usize).wrapping_sub(1)) {
+                sample.sync = true;
+            }
+        }
+    }
+    Some((samples, dts))
+}
+
+fn parse_trak(trak: &[u8], file_len: u64) -> Option<TrakInfo> {
+    let mut reader = ByteReader::new(child(trak, b"tkhd")?);
+    let (version, _) = reader.version_flags()?;
+    reader.bytes(if version == 1 { 16 } else { 8 })?;
+    let track_id = reader.u32()?;
+
+    let mdia = child(trak, b"mdia")?;
+    let mut reader = ByteReader::new(child(mdia, b"mdhd")?);
+    let (version, _) = reader.version_flags()?;
+    reader.bytes(if version == 1 { 16 } else { 8 })?;
+    let timescale = reader.u32()?;
+    let handler = child(mdia, b"hdlr")?.get(8..12)?;
+    let handler = [handler[0], handler[1], handler[2], handler[3]];
+
+    let stbl = find(mdia, &[b"minf", b"stbl"])?;
+    let entry = parse_stsd(child(stbl, b"stsd")?, &handler == b"vide");
+    let (samples, next_dts) = parse_stbl(stbl, file_len)?;
+    let media_time = match find(trak, &[b"edts", b"elst"]) {
+        Some(elst) => parse_elst(elst)?,
+        None => None,
+    };
+    Some(TrakInfo { track_id, handler, timescale, entry, media_time, samples, next_dts, defaults: (0, 0, 0) })
+}
+
+/// 分段文件中每个轨道的默认样本时长, 大小与标志
+fn parse_trex(trex: &[u8]) -> Option<(u32, (u32, u32, u32))> {
+    let mut reader = ByteReader::new(trex);
+    reader.version_flags()?;
+    let track_id = reader.u32()?;
+    reader.u32()?;
+    Some((track_id, (reader.u32()?, reader.u32()?, reader.u32()?)))
+}
+
+/// 解析分段中的traf, 数据位置以moof的起始位置为基准
+fn parse_traf(traf: &[u8], moof_start: u64, traks: &mut HashMap<u32, TrakInfo>) -> Option<()> {
+    let mut reader = ByteReader::new(child(traf, b"tfhd")?);
+    let (_, flags) = reader.version_flags()?;
+    let track = match traks.get_mut(&reader.u32()?) {
+        Some(track) => track,
+        None => return Some(()),
+    };
+    let base = if flags & 0x01 != 0 { reader.u64()? } else { moof_start };
+    if flags & 0x02 != 0 {
+        reader.u32()?;
+    }
+    let (mut default_duration, mut default_size, mut default_flags) = track.defaults;
+    if flags & 0x08 != 0 {
+        default_duration = reader.u32()?;
+    }
+    if flags & 0x10 != 0 {
+        default_size = reader.u32()?;
+    }
+    if flags & 0x20 != 0 {
+        default_flags = reader.u32()?;
+    }
+
+    if let Some(tfdt) = child(traf, b"tfdt") {
+        let mut reader = ByteReader::new(tfdt);
+        let (version, _) = reader.version_flags()?;
+        track.next_dts = if version == 1 { reader.u64()? } else { reader.u32()? as u64 };
+    }
+
+    let mut offset = base;
+    for (kind, trun) in children(traf) {
+        if &kind != b"trun" {
+            continue;
+        }
+        let mut reader = ByteReader::new(trun);
+        let (_, flags) = reader.version_flags()?;
+        let count = reader.u32()?;
+        if flags & 0x01 != 0 {
+            offset = (base as i64 + reader.u32()? as i32 as i64) as u64;
+        }
+        let first_flags = if flags & 0x04 != 0 { Some(reader.u32()?) } else { None };
+        for index in 0..count {
+            let duration = if flags & 0x100 != 0 { reader.u32()? } else { default_duration };
+            let size = if flags & 0x200 != 0 { reader.u32()? } else { default_size };
+            let sample_flags = if flags & 0x400 != 0 { reader.u32()? } else { default_flags };
+            let sample_flags = first_flags.filter(|_| index == 0).unwrap_or(sample_flags);
+            let cts_offset = if flags & 0x800 != 0 { reader.u32()? as i32 as i64 } else { 0 };
+            track.samples.push(InputSample {
+                offset,
+                size,
+                dts: track.next_dts,
+                cts_offset,
+                // sample_is_non_sync_sample
+                sync: (sample_flags >> 16) & 0x01 == 0,
+            });
+            track.next_dts += duration as u64;
+            offset += size as u64;
+        }
+    }
+    Some(())
+}
+
+/// 读取第一个视频或音频轨道, 支持普通MP4与moov加若干moof/mdat的分段文件
+pub fn read_track<R: Read + Seek>(input: &mut R, video: bool) -> Result<InputTrack, MuxError> {
+    let io_error = |_: io::Error| invalid("MP4");
+    let len = input.seek(SeekFrom::End(0)).map_err(io_error)?;
+    let mut traks: HashMap<u32, TrakInfo> = HashMap::new();
+    let mut order = vec![];
+    let mut position = 0;
+
+    while position + 8 <= len {
+        input.seek(SeekFrom::Start(position)).map_err(io_error)?;
+        let mut header = [0u8; 16];
+        input.read_exact(&mut header[..8]).map_err(io_error)?;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (size, header_len) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            0 => (len - position, 8),
+            1 => {
+                input.read_exact(&mut header[8..]).map_err(io_error)?;
+                (u64::from_be_bytes([header[8], header[9], header[10], header[11], header[12], header[13], header[14], header[15]]), 16)
+            },
+            size => (size as u64, 8),
+        };
+        if size < header_len || position + size > len {
+            break;
+        }
+
+        // mdat只记录位置, 样本在合并时再按需读取
+        if &kind == b"moov" || &kind == b"moof" {
+            let mut body = vec![0u8; (size - header_len) as usize];
+            input.read_exact(&mut body).map_err(io_error)?;
+            if &kind == b"moov" {
+                for (name, trak) in children(&body) {
+                    if &name != b"trak" {
+                        continue;
+                    }
+                    let info = parse_trak(trak, len).ok_or_else(|| invalid("trak"))?;
+                    order.push(info.track_id);
+                    traks.insert(info.track_id, info);
+                }
+                for (name, trex) in find(&body, &[b"mvex"]).map(children).unwrap_or_default() {
+                    if &name != b"trex" {
+                        continue;
+                    }
+                    let (track_id, defaults) = parse_trex(trex).ok_or_else(|| invalid("trex"))?;
+                    if let Some(track) = traks.get_mut(&track_id) {
+                        track.defaults = defaults;
+                    }
+                }
+            } else {
+                for (name, traf) in children(&body) {
+                    if &name == b"traf" {
+                        parse_traf(traf, position, &mut traks).ok_or_else(|| invalid("traf"))?;
+                    }
+                }
+            }
+        }
+        position += size;
+    }
+
+    let handler = if video { b"vide" } else { b"soun" };
+    let trak = order.iter()
+        .filter_map(|id| traks.remove(id))
+        .find(|t| &t.handler == handler && t.entry.is_some() && !t.samples.is_empty())
+        .ok_or(MuxError::NoStreamError)?;
+    Ok(InputTrack {
+        timescale: trak.timescale,
+        entry: trak.entry.unwrap(),
+        media_time: trak.media_time,
+        samples: trak.samples,
+    })
+}
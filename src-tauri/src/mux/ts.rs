@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use super::MuxError;
+
+pub const PACKET_SIZE: usize = 188;
+
+const SYNC_BYTE: u8 = 0x47;
+
+// PAT固定使用的PID
+const PAT_PID: u16 = 0;
+
+/// PMT中支持转封装的流类型
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamKind {
+    H264,
+    H265,
+    Aac,
+}
+
+impl StreamKind {
+    fn from_stream_type(stream_type: u8) -> Option<Self> {
+        match stream_type {
+            0x1b => Some(StreamKind::H264),
+            0x24 => Some(StreamKind::H265),
+            0x0f => Some(StreamKind::Aac),
+            _ => None,
+        }
+    }
+}
+
+/// 一个完整的PES包, 时间戳为90kHz
+#[derive(Debug)]
+pub struct Pes {
+    pub pid: u16,
+    pub kind: StreamKind,
+    pub pts: Option<u64>,
+    pub dts: Option<u64>,
+    pub payload: Vec<u8>,
+}
+
+/// 按包解析MPEG-TS, 每个PID的负载拼接成PES后返回
+#[derive(Debug, Default)]
+pub struct Demuxer {
+    pmt_pid: Option<u16>,
+    streams: HashMap<u16, StreamKind>,
+    // 每个PID正在拼接的PES
+    pending: HashMap<u16, Vec<u8>>,
+}
+
+/// 33位时间戳, 分散在5个字节中
+fn parse_timestamp(data: &[u8]) -> u64 {
+    ((data[0] as u64 >> 1) & 0x07) << 30
+        | (data[1] as u64) << 22
+        | (data[2] as u64 >> 1) << 15
+        | (data[3] as u64) << 7
+        | data[4] as u64 >> 1
+}
+
+impl Demuxer {
+
+    /// 已在PMT中找到的流
+    pub fn streams(&self) -> &HashMap<u16, StreamKind> {
+        &self.streams
+    }
+
+    /// 解析一个TS包, 某个PID的上一个PES结束时返回它
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<Pes>, MuxError> {
+        if packet.len() != PACKET_SIZE || packet[0] != SYNC_BYTE {
+            return Err(MuxError::InvalidTsError("同步字节错误".to_string()));
+        }
+        let payload_start = packet[1] & 0x40 != 0;
+        let pid = ((packet[1] as u16 & 0x1f) << 8) | packet[2] as u16;
+        let adaptation = (packet[3] >> 4) & 0x03;
+
+        let mut offset = 4;
+        if adaptation & 0x02 != 0 {
+            offset += 1 + packet[4] as usize;
+        }
+        if adaptation & 0x01 == 0 || offset >= PACKET_SIZE {
+            return Ok(None);
+        }
+        let payload = &packet[offset..];
+
+        if pid == PAT_PID || Some(pid) == self.pmt_pid {
+            if payload_start {
+                self.parse_section(pid, payload)?;
+            }
+            return Ok(None);
+        }
+        if !self.streams.contains_key(&pid) {
+            return Ok(None);
+        }
+
+        let mut finished = None;
+        if payload_start {
+            if let Some(data) = self.pending.insert(pid, vec![]) {
+                finished = self.parse_pes(pid, data)?;
+            }
+        }
+        // 在第一个PES开始之前的数据不完整, 直接丢弃
+        if let Some(data) = self.pending.get_mut(&pid) {
+            data.extend_from_slice(payload);
+        }
+        Ok(finished)
+    }
+
+    /// 文件结束时取出所有未结束的PES
+    pub fn flush(&mut self) -> Result<Vec<Pes>, MuxError> {
+        let mut pids = self.pending.keys().copied().collect::<Vec<u16>>();
+        pids.sort_unstable();
+        let mut result = vec![];
+        for pid in pids {
+            if let Some(data) = self.pending.remove(&pid) {
+                result.extend(self.parse_pes(pid, data)?);
+            }
+        }
+        Ok(result)
+    }
+
+    /// 解析PAT与PMT, 假定表不会跨包
+    fn parse_section(&mut self, pid: u16, payload: &[u8]) -> Result<(), MuxError> {
+        let invalid = || MuxError::InvalidTsError(format!("PID {} 的表格式错误", pid));
+        let pointer = *payload.first().ok_or_else(invalid)? as usize;
+        let section = payload.get(1 + pointer..).ok_or_else(invalid)?;
+        if section.len() < 3 {
+            return Err(invalid());
+        }
+        let section_len = ((section[1] as usize & 0x0f) << 8) | section[2] as usize;
+        // 去掉表头与末尾的CRC32
+        let end = (3 + section_len).checked_sub(4).filter(|end| *end <= section.len()).ok_or_else(invalid)?;
+
+        if pid == PAT_PID {
+            let programs = section.get(8..end).ok_or_else(invalid)?;
+            // 只转封装第一个节目, 节目号0是网络信息表
+            self.pmt_pid = programs.chunks_exact(4)
+                .find(|program| program[0] != 0 || program[1] != 0)
+                .map(|program| ((program[2] as u16 & 0x1f) << 8) | program[3] as u16);
+            return Ok(());
+        }
+
+        let program_info = section.get(10..12).ok_or_else(invalid)?;
+        let program_info_len = ((program_info[0] as usize & 0x0f) << 8) | program_info[1] as usize;
+        let mut position = 12 + program_info_len;
+        if position > end {
+            return Err(invalid());
+        }
+        while position + 5 <= end {
+            let stream_type = section[position];
+            let stream_pid = ((section[position + 1] as u16 & 0x1f) << 8) | section[position + 2] as u16;
+            let info_len = ((section[position + 3] as usize & 0x0f) << 8) | section[position + 4] as usize;
+            if let Some(kind) = StreamKind::from_stream_type(stream_type) {
+                self.streams.insert(stream_pid, kind);
+            }
+            position += 5 + info_len;
+        }
+        Ok(())
+    }
+
+    fn parse_pes(&self, pid: u16, data: Vec<u8>) -> Result<Option<Pes>, MuxError> {
+        let kind = match self.streams.get(&pid) {
+            Some(kind) => *kind,
+            None => return Ok(None),
+        };
+        if data.len() < 9 || data[..3] != [0, 0, 1] {
+            return Err(MuxError::InvalidTsError(format!("PID {} 的PES头错误", pid)));
+        }
+        let flags = data[7] >> 6;
+        let header_end = 9 + data[8] as usize;
+        if header_end > data.len() {
+            return Err(MuxError::InvalidTsError(format!("PID {} 的PES头长度错误", pid)));
+        }
+        let pts = match flags & 0x02 != 0 && header_end >= 14 {
+            true => Some(parse_timestamp(&data[9..14])),
+            false => None,
+        };
+        let dts = match flags == 0x03 && header_end >= 19 {
+            true => Some(parse_timestamp(&data[14..19])),
+            false => pts,
+        };
+        // 视频的PES长度常为0, 表示一直到下一个PES开始
+        let pes_len = ((data[4] as usize) << 8) | data[5] as usize;
+        let end = match pes_len {
+            0 => data.len(),
+            len => std::cmp::min(6 + len, data.len()),
+        };
+        let payload = data.get(header_end..end).unwrap_or_default().to_vec();
+        Ok(Some(Pes { pid, kind, pts, dts, payload }))
+    }
+}
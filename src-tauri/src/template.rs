@@ -0,0 +1,199 @@
+use std::{collections::HashMap, fmt::Write, path::PathBuf};
+use chrono::{Local, TimeZone};
+use thiserror::Error;
+use crate::downloader::sanitize_filename;
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+
+    #[error("文件名模板格式错误: {0}")]
+    SyntaxError(String),
+
+    #[error("文件名模板中的字段不存在: {0}")]
+    UnknownFieldError(String),
+
+    #[error("文件名模板中的截断长度或日期格式错误: {0}")]
+    InvalidSpecError(String),
+}
+
+/// 模板字段的值, 日期字段保存秒级时间戳与默认的日期格式
+#[derive(Debug, Clone)]
+pub enum TemplateValue {
+    Text(String),
+    Date(Option<i64>, &'static str),
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    // {name}或{name:spec}, 文本字段的spec为最多保留的字符数, 日期字段的spec为chrono日期格式
+    Field { name: String, spec: Option<String> },
+}
+
+/// 文件名模板, 例如: {nickname}/{create_date}_{video_id}_{title:40}.{ext}
+/// "/"用于分隔子目录, {{与}}表示花括号本身
+#[derive(Debug, Clone)]
+pub struct FilenameTemplate {
+    components: Vec<Vec<Segment>>,
+}
+
+impl FilenameTemplate {
+
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut components = vec![];
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut field = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => return Err(TemplateError::SyntaxError(template.to_string())),
+                            Some(c) => field.push(c),
+                        }
+                    }
+                    let (name, spec) = match field.split_once(':') {
+                        Some((name, spec)) => (name, Some(spec.to_string())),
+                        None => (field.as_str(), None),
+                    };
+                    if name.trim().is_empty() {
+                        return Err(TemplateError::SyntaxError(template.to_string()));
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field { name: name.trim().to_string(), spec });
+                },
+                '}' => return Err(TemplateError::SyntaxError(template.to_string())),
+                '/' | '\\' => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    components.push(std::mem::take(&mut segments));
+                },
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        components.push(segments);
+        Ok(Self { components })
+    }
+
+    /// 生成相对于保存目录的路径, 每一级目录与文件名都单独清理, 字段值中的"/"不会产生子目录
+    pub fn render(&self, values: &HashMap<&str, TemplateValue>) -> Result<PathBuf, TemplateError> {
+        let mut path = PathBuf::new();
+        let last = self.components.len() - 1;
+        for (index, component) in self.components.iter().enumerate() {
+            let mut rendered = String::new();
+            for segment in component {
+                match segment {
+                    Segment::Literal(literal) => rendered.push_str(literal),
+                    Segment::Field { name, spec } => {
+                        let value = values.get(name.as_str())
+                            .ok_or_else(|| TemplateError::UnknownFieldError(name.clone()))?;
+                        render_value(&mut rendered, value, spec.as_deref())?;
+                    },
+                }
+            }
+            // 字段为空时不创建空的子目录
+            if index < last && rendered.trim().is_empty() {
+                continue;
+            }
+            path.push(sanitize_filename(&rendered));
+        }
+        Ok(path)
+    }
+}
+
+fn render_value(rendered: &mut String, value: &TemplateValue, spec: Option<&str>) -> Result<(), TemplateError> {
+    match value {
+        TemplateValue::Text(text) => match spec {
+            Some(width) => {
+                let width = width.trim().parse::<usize>()
+                    .map_err(|_| TemplateError::InvalidSpecError(width.to_string()))?;
+                rendered.extend(text.chars().take(width));
+            },
+            None => rendered.push_str(text),
+        },
+        TemplateValue::Date(Some(timestamp), default_format) => {
+            let format = spec.unwrap_or(default_format);
+            let date = Local.timestamp_opt(*timestamp, 0)
+                .single()
+                .ok_or_else(|| TemplateError::InvalidSpecError(timestamp.to_string()))?;
+            // 无效的日期格式在格式化时才会报错
+            write!(rendered, "{}", date.format(format))
+                .map_err(|_| TemplateError::InvalidSpecError(format.to_string()))?;
+        },
+        TemplateValue::Date(None, _) => {},
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> HashMap<&'static str, TemplateValue> {
+        HashMap::from([
+            ("title", TemplateValue::Text("周末去看海".to_string())),
+            ("nickname", TemplateValue::Text("阿杰".to_string())),
+            ("empty", TemplateValue::Text(String::new())),
+            ("slash", TemplateValue::Text("a/b".to_string())),
+            ("date", TemplateValue::Date(Some(1659960000), "%Y-%m-%d")),
+            ("no_date", TemplateValue::Date(None, "%Y-%m-%d")),
+            ("ext", TemplateValue::Text("mp4".to_string())),
+        ])
+    }
+
+    #[test]
+    fn renders_templates() {
+        let date = Local.timestamp_opt(1659960000, 0).unwrap();
+        let cases = [
+            ("{title}.{ext}", "周末去看海.mp4".to_string()),
+            ("{ title }.{ext}", "周末去看海.mp4".to_string()),
+            ("{title:2}.{ext}", "周末.mp4".to_string()),
+            ("{nickname}/{title}.{ext}", "阿杰/周末去看海.mp4".to_string()),
+            ("{nickname}\\{title}.{ext}", "阿杰/周末去看海.mp4".to_string()),
+            ("{{{title}}}.{ext}", "{周末去看海}.mp4".to_string()),
+            ("{date}_{title}.{ext}", format!("{}_周末去看海.mp4", date.format("%Y-%m-%d"))),
+            ("{date:%Y%m}/{title}.{ext}", format!("{}/周末去看海.mp4", date.format("%Y%m"))),
+            // 空字段不产生子目录, 字段中的"/"不产生子目录
+            ("{empty}/{no_date}/{title}.{ext}", "周末去看海.mp4".to_string()),
+            ("{slash}.{ext}", "a_b.mp4".to_string()),
+            ("", "download".to_string()),
+        ];
+        for (template, expected) in cases {
+            let path = FilenameTemplate::parse(template).unwrap().render(&values()).unwrap();
+            assert_eq!(path, PathBuf::from(expected), "{}", template);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let syntax_errors = ["{title", "title}", "{}", "{ :3}", "{ti{tle}}"];
+        for template in syntax_errors {
+            assert!(matches!(FilenameTemplate::parse(template), Err(TemplateError::SyntaxError(_))), "{}", template);
+        }
+
+        let render_errors = ["{unknown}", "{title:abc}", "{title:-1}"];
+        for template in render_errors {
+            let result = FilenameTemplate::parse(template).unwrap().render(&values());
+            assert!(result.is_err(), "{}", template);
+        }
+        let result = FilenameTemplate::parse("{unknown}").unwrap().render(&values());
+        assert!(matches!(result, Err(TemplateError::UnknownFieldError(name)) if name == "unknown"));
+    }
+}